@@ -15,9 +15,10 @@ if ! doctl account get &> /dev/null; then
 fi
 
 # Check if required environment variables are set
-if [ -z "$GOOGLE_CLIENT_ID" ] || [ -z "$GOOGLE_CLIENT_SECRET" ] || [ -z "$GOOGLE_PROJECT_ID" ]; then
+# (GOOGLE_PROJECT_ID is optional; without it each user enters their own)
+if [ -z "$GOOGLE_CLIENT_ID" ] || [ -z "$GOOGLE_CLIENT_SECRET" ]; then
     echo "Please set the required environment variables:"
-    echo "GOOGLE_CLIENT_ID, GOOGLE_CLIENT_SECRET, GOOGLE_PROJECT_ID"
+    echo "GOOGLE_CLIENT_ID, GOOGLE_CLIENT_SECRET"
     echo ""
     echo "Example: export GOOGLE_CLIENT_ID=your_client_id_here"
     exit 1
@@ -0,0 +1,40 @@
+CREATE TABLE users (
+    user_id TEXT PRIMARY KEY,
+    project_id TEXT NOT NULL,
+    pubsub_subscription TEXT,
+    notifications JSONB NOT NULL DEFAULT '{}',
+    webhooks JSONB NOT NULL DEFAULT '[]',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE tokens (
+    user_id TEXT PRIMARY KEY REFERENCES users (user_id) ON DELETE CASCADE,
+    access_token TEXT NOT NULL,
+    refresh_token TEXT NOT NULL,
+    token_type TEXT NOT NULL,
+    expires_in BIGINT NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE devices (
+    user_id TEXT NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+    device_id TEXT NOT NULL,
+    -- Order of the device in the user's device list
+    position INTEGER NOT NULL,
+    PRIMARY KEY (user_id, device_id)
+);
+
+CREATE INDEX devices_device_id_idx ON devices (device_id);
+
+-- Recently processed events. Not tied to users, since events can be
+-- recorded before a newly registered user has been saved.
+CREATE TABLE events (
+    id BIGSERIAL PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    event_id TEXT NOT NULL,
+    device_id TEXT NOT NULL,
+    timestamp TEXT NOT NULL,
+    processed_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    UNIQUE (user_id, event_id)
+);
@@ -0,0 +1,3 @@
+-- Absolute expiry of the access token. NULL for tokens stored before this
+-- column existed, which are refreshed on startup.
+ALTER TABLE tokens ADD COLUMN expires_at TIMESTAMPTZ;
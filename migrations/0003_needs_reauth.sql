@@ -0,0 +1,3 @@
+-- Set when Google rejects the user's refresh token; monitoring stays paused
+-- until the user signs in again
+ALTER TABLE users ADD COLUMN needs_reauth BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,2 @@
+-- Granted scopes and OpenID Connect ID token, kept across refreshes
+ALTER TABLE tokens ADD COLUMN scope TEXT, ADD COLUMN id_token TEXT;
@@ -0,0 +1,6 @@
+-- Email and password sign-in for users who register without Google, who
+-- have no token until they connect their Google account
+ALTER TABLE users
+    ADD COLUMN email TEXT UNIQUE,
+    ADD COLUMN password_hash TEXT,
+    ADD COLUMN email_verified BOOLEAN NOT NULL DEFAULT FALSE;
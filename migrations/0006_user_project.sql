@@ -0,0 +1,4 @@
+-- Users choose their own Device Access project, so it's unset until they do.
+-- Users given the old placeholder never had one.
+ALTER TABLE users ALTER COLUMN project_id DROP NOT NULL;
+UPDATE users SET project_id = NULL WHERE project_id = 'YOUR_PROJECT_ID';
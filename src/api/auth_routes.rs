@@ -17,8 +17,24 @@ pub struct UserRegisterRequest {
 #[cfg(feature = "web-api")]
 pub fn auth_routes() -> Router<AppState> {
     Router::new()
-        .route("/auth/login", get(auth_handlers::login_page))
+        .route(
+            "/auth/login",
+            get(auth_handlers::login_page).post(account_handlers::password_login),
+        )
         .route("/auth/authorize", get(auth_handlers::start_oauth))
         .route("/auth/callback", get(auth_handlers::oauth_callback))
-        .route("/auth/register", post(auth_handlers::register_user))
+        .route("/auth/logout", post(auth_handlers::logout))
+        .route(
+            "/auth/register",
+            get(account_handlers::register_page).post(account_handlers::register_account),
+        )
+        .route("/auth/verify", get(account_handlers::verify_email))
+        .route(
+            "/auth/forgot",
+            get(account_handlers::forgot_password_page).post(account_handlers::request_password_reset),
+        )
+        .route(
+            "/auth/reset",
+            get(account_handlers::reset_password_page).post(account_handlers::reset_password),
+        )
 }
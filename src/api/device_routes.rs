@@ -1,14 +1,16 @@
 #[cfg(feature = "web-api")]
 use axum::{
-    extract::{Path, State},
+    extract::State,
     http::StatusCode,
     response::Json,
-    routing::get,
+    routing::{get, post},
     Router,
 };
+use serde::Deserialize;
 
 use crate::api::handlers::auth_handlers::AppState;
-use crate::devices::discovery;
+use crate::api::session::AuthenticatedUser;
+use crate::devices::discovery::{self, ProjectError};
 
 #[derive(serde::Serialize)]
 pub struct DeviceListResponse {
@@ -18,14 +20,116 @@ pub struct DeviceListResponse {
 #[cfg(feature = "web-api")]
 pub fn device_routes() -> Router<AppState> {
     Router::new()
-        .route("/devices/:user_id", get(list_devices))
-        .route("/devices/:user_id/cameras", get(list_cameras))
+        .route("/devices", get(list_devices).post(register_user))
+        .route("/devices/cameras", get(list_cameras))
+        .route("/devices/project", post(choose_project))
 }
 
-// List all devices for a user
+pub(crate) fn project_error_status(error: &ProjectError) -> StatusCode {
+    match error {
+        ProjectError::InvalidId
+        | ProjectError::InvalidSubscription
+        | ProjectError::NoAccess
+        | ProjectError::UnknownDevice(_) => StatusCode::BAD_REQUEST,
+        ProjectError::NotConnected | ProjectError::Unauthorized | ProjectError::NoProject => {
+            StatusCode::CONFLICT
+        }
+        ProjectError::Sdm(_) => StatusCode::BAD_GATEWAY,
+    }
+}
+
+/// Make `project_id` the user's Device Access project once Google confirms
+/// they can read it, returning its devices. Cameras chosen from a previous
+/// project are dropped.
+pub(crate) async fn set_user_project(
+    app_state: &AppState,
+    user_id: &str,
+    project_id: &str,
+) -> Result<Vec<discovery::Device>, ProjectError> {
+    let token = {
+        let users_lock = app_state.users.lock().await;
+        users_lock.get(user_id).and_then(|config| config.token.clone())
+    }
+    .ok_or(ProjectError::NotConnected)?;
+
+    let project_id = discovery::parse_project_id(project_id)?;
+    let devices = discovery::validate_project(&app_state.sdm, &project_id, &token).await?;
+
+    let changed = {
+        let mut users_lock = app_state.users.lock().await;
+        let config = users_lock.get_mut(user_id).ok_or(ProjectError::NotConnected)?;
+        let changed = config.project_id.as_deref() != Some(project_id.as_str());
+        if changed {
+            log::info!("User {} chose Device Access project {}", user_id, project_id);
+            config.project_id = Some(project_id);
+            config.device_ids.clear();
+        }
+        changed
+    };
+
+    // Stop monitoring cameras from the old project
+    if changed {
+        app_state.supervisor.sync_monitor(user_id).await;
+    }
+
+    Ok(devices)
+}
+
+/// Set the Pub/Sub subscription the user's events are pulled from, where
+/// `None` means the deployment's default. Their monitor picks it up on its
+/// next poll.
+pub(crate) async fn set_user_subscription(
+    app_state: &AppState,
+    user_id: &str,
+    subscription: Option<String>,
+) -> Result<(), ProjectError> {
+    let mut users_lock = app_state.users.lock().await;
+    let config = users_lock.get_mut(user_id).ok_or(ProjectError::NotConnected)?;
+    if config.pubsub_subscription != subscription {
+        log::info!(
+            "User {} chose Pub/Sub subscription {}",
+            user_id,
+            subscription.as_deref().unwrap_or("(default)")
+        );
+        config.pubsub_subscription = subscription;
+    }
+    Ok(())
+}
+
+/// Check Google lists every one of `device_ids` in the user's Device Access
+/// project, so users can only monitor devices they can read
+pub(crate) async fn check_user_devices(
+    app_state: &AppState,
+    user_id: &str,
+    device_ids: &[String],
+) -> Result<(), ProjectError> {
+    if device_ids.is_empty() {
+        return Ok(());
+    }
+
+    let (token, project_id) = {
+        let users_lock = app_state.users.lock().await;
+        let config = users_lock.get(user_id).ok_or(ProjectError::NotConnected)?;
+        (
+            config.token.clone().ok_or(ProjectError::NotConnected)?,
+            config.project_id.clone().ok_or(ProjectError::NoProject)?,
+        )
+    };
+
+    let devices = discovery::discover_devices(&app_state.sdm, &project_id, &token).await?;
+    match device_ids
+        .iter()
+        .find(|device_id| !devices.iter().any(|device| &device.device_id == *device_id))
+    {
+        Some(unknown) => Err(ProjectError::UnknownDevice(unknown.clone())),
+        None => Ok(()),
+    }
+}
+
+// List all devices for the signed-in user
 async fn list_devices(
     State(app_state): State<AppState>,
-    Path(user_id): Path<String>,
+    AuthenticatedUser { user_id }: AuthenticatedUser,
 ) -> Result<Json<DeviceListResponse>, (StatusCode, String)> {
     // Get user config from the store
     let user_config = {
@@ -38,8 +142,17 @@ async fn list_devices(
         None => return Err((StatusCode::NOT_FOUND, "User not found".to_string())),
     };
 
+    let token = user_config.token.as_ref().ok_or((
+        StatusCode::CONFLICT,
+        "No Google account is connected".to_string(),
+    ))?;
+    let project_id = user_config.project_id.as_deref().ok_or((
+        StatusCode::CONFLICT,
+        "No Device Access project is chosen".to_string(),
+    ))?;
+
     // Fetch devices
-    match discovery::discover_devices(&user_config.project_id, &user_config.token).await {
+    match discovery::discover_devices(&app_state.sdm, project_id, token).await {
         Ok(devices) => Ok(Json(DeviceListResponse { devices })),
         Err(e) => Err((
             StatusCode::INTERNAL_SERVER_ERROR,
@@ -48,10 +161,10 @@ async fn list_devices(
     }
 }
 
-// List only cameras for a user
+// List only cameras for the signed-in user
 async fn list_cameras(
     State(app_state): State<AppState>,
-    Path(user_id): Path<String>,
+    AuthenticatedUser { user_id }: AuthenticatedUser,
 ) -> Result<Json<DeviceListResponse>, (StatusCode, String)> {
     // Get user config from the store
     let user_config = {
@@ -64,8 +177,17 @@ async fn list_cameras(
         None => return Err((StatusCode::NOT_FOUND, "User not found".to_string())),
     };
 
+    let token = user_config.token.as_ref().ok_or((
+        StatusCode::CONFLICT,
+        "No Google account is connected".to_string(),
+    ))?;
+    let project_id = user_config.project_id.as_deref().ok_or((
+        StatusCode::CONFLICT,
+        "No Device Access project is chosen".to_string(),
+    ))?;
+
     // Fetch devices and filter for cameras
-    match discovery::discover_devices(&user_config.project_id, &user_config.token).await {
+    match discovery::discover_devices(&app_state.sdm, project_id, token).await {
         Ok(devices) => {
             let cameras = discovery::filter_cameras(&devices);
             Ok(Json(DeviceListResponse { devices: cameras }))
@@ -75,4 +197,160 @@ async fn list_cameras(
             format!("Failed to discover devices: {}", e),
         )),
     }
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProjectRequest {
+    pub project_id: String,
+    /// Full name of the Pub/Sub subscription for the project's events. Empty
+    /// switches back to the deployment's default; leaving it out keeps the
+    /// current one.
+    #[serde(default)]
+    pub pubsub_subscription: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+pub struct ProjectResponse {
+    project_id: String,
+    pubsub_subscription: Option<String>,
+    devices: Vec<discovery::Device>,
+}
+
+// Choose the signed-in user's Device Access project, checking it with Google
+async fn choose_project(
+    State(app_state): State<AppState>,
+    AuthenticatedUser { user_id }: AuthenticatedUser,
+    Json(request): Json<ProjectRequest>,
+) -> Result<Json<ProjectResponse>, (StatusCode, String)> {
+    let rejected = |e: ProjectError| (project_error_status(&e), e.to_string());
+
+    let project_id = discovery::parse_project_id(&request.project_id).map_err(rejected)?;
+    let subscription = request
+        .pubsub_subscription
+        .as_deref()
+        .map(discovery::parse_subscription)
+        .transpose()
+        .map_err(rejected)?;
+    let devices = set_user_project(&app_state, &user_id, &project_id)
+        .await
+        .map_err(rejected)?;
+    if let Some(subscription) = subscription {
+        set_user_subscription(&app_state, &user_id, subscription)
+            .await
+            .map_err(rejected)?;
+    }
+
+    let pubsub_subscription = {
+        let users_lock = app_state.users.lock().await;
+        users_lock.get(&user_id).and_then(|config| config.pubsub_subscription.clone())
+    };
+    Ok(Json(ProjectResponse { project_id, pubsub_subscription, devices }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegisterUserRequest {
+    /// Switches the user's Device Access project, like `POST /devices/project`
+    #[serde(default)]
+    pub project_id: Option<String>,
+    pub device_ids: Vec<String>,
+    /// Sets the user's Pub/Sub subscription, like `POST /devices/project`
+    #[serde(default)]
+    pub pubsub_subscription: Option<String>,
+}
+
+// Register the signed-in user's devices
+async fn register_user(
+    State(app_state): State<AppState>,
+    AuthenticatedUser { user_id }: AuthenticatedUser,
+    Json(request): Json<RegisterUserRequest>,
+) -> Result<Json<String>, (StatusCode, String)> {
+    let project_id = request.project_id;
+    let device_ids = request.device_ids;
+    let pubsub_subscription = request
+        .pubsub_subscription
+        .as_deref()
+        .map(discovery::parse_subscription)
+        .transpose()
+        .map_err(|e| (project_error_status(&e), e.to_string()))?;
+    let device_count = device_ids.len(); // Store count before we move device_ids
+
+    // Get existing user config
+    let user_config = {
+        let users_lock = app_state.users.lock().await;
+        users_lock.get(&user_id).cloned()
+    };
+
+    // If user doesn't exist or token is missing, return error
+    if user_config.is_none() {
+        return Err((StatusCode::NOT_FOUND, "User not found. Please authenticate first.".to_string()));
+    }
+
+    if let Some(project_id) = &project_id {
+        set_user_project(&app_state, &user_id, project_id)
+            .await
+            .map_err(|e| (project_error_status(&e), e.to_string()))?;
+    }
+    check_user_devices(&app_state, &user_id, &device_ids)
+        .await
+        .map_err(|e| (project_error_status(&e), e.to_string()))?;
+
+    // Update user config with selected devices
+    {
+        let mut users_lock = app_state.users.lock().await;
+        if let Some(config) = users_lock.get_mut(&user_id) {
+            config.device_ids = device_ids.clone();
+        }
+    }
+    if let Some(subscription) = pubsub_subscription {
+        set_user_subscription(&app_state, &user_id, subscription)
+            .await
+            .map_err(|e| (project_error_status(&e), e.to_string()))?;
+    }
+
+    // Start monitoring, or keep the existing monitor for this user
+    app_state.supervisor.sync_monitor(&user_id).await;
+
+    Ok(Json(format!("User {} registered with {} devices. Monitoring started.", user_id, device_count)))
+}
+
+#[cfg(all(test, feature = "web-api"))]
+mod tests {
+    use super::*;
+    use crate::api::handlers::auth_handlers::tests::app_state;
+    use crate::auth::models::UserStore;
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn invalid_subscriptions_are_refused_before_anything_changes() {
+        let alice = serde_json::from_value(serde_json::json!({
+            "user_id": "alice",
+            "device_ids": ["camera-1"],
+            "pubsub_subscription": "projects/my-project/subscriptions/sdm-events",
+        }))
+        .unwrap();
+        let users = UserStore::new(HashMap::from([("alice".to_string(), alice)]));
+        let app_state = app_state(users.clone()).await;
+
+        let (status, _) = register_user(
+            State(app_state),
+            AuthenticatedUser { user_id: "alice".to_string() },
+            Json(RegisterUserRequest {
+                project_id: None,
+                device_ids: Vec::new(),
+                pubsub_subscription: Some("sdm-events".to_string()),
+            }),
+        )
+        .await
+        .err()
+        .unwrap();
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let users_lock = users.lock().await;
+        let alice = &users_lock["alice"];
+        assert_eq!(alice.device_ids, ["camera-1"]);
+        assert_eq!(
+            alice.pubsub_subscription.as_deref(),
+            Some("projects/my-project/subscriptions/sdm-events")
+        );
+    }
+}
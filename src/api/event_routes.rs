@@ -0,0 +1,301 @@
+#[cfg(feature = "web-api")]
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap, StatusCode},
+    routing::post,
+    Json, Router,
+};
+use serde::Deserialize;
+use std::env;
+
+use crate::api::handlers::auth_handlers::AppState;
+use crate::auth::jwks::{JwksVerifier, GOOGLE_ISSUERS, GOOGLE_JWKS_URI};
+use crate::events::pubsub::PubSubMessage;
+use crate::sdm::SdmClient;
+
+/// How Pub/Sub push deliveries are authenticated
+#[derive(Clone)]
+pub struct PushAuth {
+    /// Shared secret expected in the `token` query parameter of the push endpoint
+    pub token: Option<String>,
+    /// Audience of the OIDC token Pub/Sub attaches to each push request
+    pub audience: Option<String>,
+    /// Service account the OIDC token must be issued for, if restricted
+    pub service_account: Option<String>,
+    pub verifier: JwksVerifier,
+}
+
+impl PushAuth {
+    pub fn from_env(sdm: &SdmClient) -> Self {
+        // docker-compose passes unset variables through as empty strings
+        let non_empty = |name: &str| env::var(name).ok().filter(|v| !v.is_empty());
+
+        let auth = Self {
+            token: non_empty("PUBSUB_PUSH_TOKEN"),
+            audience: non_empty("PUBSUB_PUSH_AUDIENCE"),
+            service_account: non_empty("PUBSUB_PUSH_SERVICE_ACCOUNT"),
+            verifier: JwksVerifier::new(sdm, GOOGLE_JWKS_URI),
+        };
+
+        if auth.token.is_none() && auth.audience.is_none() {
+            log::warn!(
+                "Neither PUBSUB_PUSH_TOKEN nor PUBSUB_PUSH_AUDIENCE is set, Pub/Sub push deliveries will be rejected"
+            );
+        }
+
+        auth
+    }
+}
+
+#[cfg(feature = "web-api")]
+pub fn event_routes() -> Router<AppState> {
+    Router::new().route("/events/pubsub", post(receive_pubsub_push))
+}
+
+// Body of a Pub/Sub push delivery
+#[derive(Debug, Deserialize)]
+struct PushRequest {
+    message: PubSubMessage,
+    subscription: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct PushQuery {
+    token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PushClaims {
+    email: Option<String>,
+    #[serde(default)]
+    email_verified: bool,
+}
+
+// Compare secrets without leaking where they differ through timing
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a
+            .bytes()
+            .zip(b.bytes())
+            .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+async fn authorize_push(
+    auth: &PushAuth,
+    query: &PushQuery,
+    headers: &HeaderMap,
+) -> Result<(), (StatusCode, String)> {
+    if let (Some(expected), Some(provided)) = (&auth.token, &query.token) {
+        if constant_time_eq(provided, expected) {
+            return Ok(());
+        }
+    }
+
+    let audience = match &auth.audience {
+        Some(audience) => audience,
+        None => return Err((StatusCode::UNAUTHORIZED, "Invalid push token".to_string())),
+    };
+
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or((StatusCode::UNAUTHORIZED, "Missing bearer token".to_string()))?;
+
+    let claims: PushClaims = auth
+        .verifier
+        .verify(bearer, audience, &GOOGLE_ISSUERS)
+        .await
+        .map_err(|e| (StatusCode::UNAUTHORIZED, format!("Invalid push JWT: {}", e)))?;
+
+    if let Some(service_account) = &auth.service_account {
+        if !claims.email_verified || claims.email.as_deref() != Some(service_account.as_str()) {
+            return Err((
+                StatusCode::FORBIDDEN,
+                "Push JWT was not issued for the expected service account".to_string(),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+// Receive a Pub/Sub push delivery. Any 2xx response acknowledges the
+// message, anything else makes Pub/Sub retry it.
+async fn receive_pubsub_push(
+    State(app_state): State<AppState>,
+    Query(query): Query<PushQuery>,
+    headers: HeaderMap,
+    Json(push): Json<PushRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    authorize_push(&app_state.push_auth, &query, &headers).await?;
+
+    let envelope = match push.message.sdm_envelope() {
+        Ok(envelope) => envelope,
+        Err(e) => {
+            // Redelivering a malformed message will not fix it, so acknowledge it
+            log::error!(
+                "Dropping push message {} from {}: {}",
+                push.message.message_id,
+                push.subscription,
+                e
+            );
+            return Ok(StatusCode::NO_CONTENT);
+        }
+    };
+
+    let project_id = envelope.project_id().unwrap_or_default().to_string();
+    let events = envelope.into_camera_events();
+    if events.is_empty() {
+        return Ok(StatusCode::NO_CONTENT);
+    }
+
+    // Find the users monitoring devices in this project, skipping those who
+    // need to sign in again as the pull path does
+    let targets: Vec<(String, Vec<String>)> = {
+        let users_lock = app_state.users.lock().await;
+        users_lock
+            .values()
+            .filter(|config| !config.needs_reauth)
+            .filter(|config| config.project_id.as_deref() == Some(project_id.as_str()))
+            .map(|config| (config.user_id.clone(), config.device_ids.clone()))
+            .collect()
+    };
+
+    for event in &events {
+        for (user_id, device_ids) in &targets {
+            if device_ids.contains(&event.device_id) {
+                app_state.events.process(user_id, event).await;
+            }
+        }
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(all(test, feature = "web-api"))]
+mod tests {
+    use super::*;
+    use crate::api::handlers::auth_handlers::tests::app_state;
+    use crate::auth::jwks::tests::{sign, AUDIENCE};
+    use crate::auth::models::{UserConfig, UserStore};
+    use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+    use std::collections::HashMap;
+
+    fn user(user_id: &str, project_id: &str, needs_reauth: bool) -> (String, UserConfig) {
+        let config = serde_json::from_value(serde_json::json!({
+            "user_id": user_id,
+            "device_ids": ["camera-1"],
+            "project_id": project_id,
+            "needs_reauth": needs_reauth,
+        }))
+        .unwrap();
+        (user_id.to_string(), config)
+    }
+
+    // A person seen by camera-1 in project-1
+    fn push() -> Json<PushRequest> {
+        let envelope = serde_json::json!({
+            "eventId": "envelope-1",
+            "timestamp": "2025-01-01T12:00:00Z",
+            "resourceUpdate": {
+                "name": "enterprises/project-1/devices/camera-1",
+                "events": {"sdm.devices.events.CameraPerson.Person": {"eventId": "person-1"}},
+            },
+        });
+        Json(
+            serde_json::from_value(serde_json::json!({
+                "message": {"data": BASE64.encode(envelope.to_string()), "messageId": "message-1"},
+                "subscription": "projects/p/subscriptions/push",
+            }))
+            .unwrap(),
+        )
+    }
+
+    fn query(token: Option<&str>) -> Query<PushQuery> {
+        Query(PushQuery {
+            token: token.map(str::to_string),
+        })
+    }
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, format!("Bearer {}", token).parse().unwrap());
+        headers
+    }
+
+    fn push_jwt(aud: &str, email: &str) -> String {
+        sign(
+            "test-key",
+            &serde_json::json!({
+                "aud": aud,
+                "iss": GOOGLE_ISSUERS[0],
+                "exp": (chrono::Utc::now() + chrono::Duration::hours(1)).timestamp(),
+                "email": email,
+                "email_verified": true,
+            }),
+        )
+    }
+
+    #[tokio::test]
+    async fn pushes_need_the_shared_token() {
+        let mut state = app_state(UserStore::new(HashMap::new())).await;
+        state.push_auth.token = Some("push-secret".to_string());
+
+        for token in [None, Some("wrong-secret"), Some("")] {
+            let result = receive_pubsub_push(State(state.clone()), query(token), HeaderMap::new(), push()).await;
+            assert_eq!(result.unwrap_err().0, StatusCode::UNAUTHORIZED);
+        }
+
+        let result = receive_pubsub_push(State(state), query(Some("push-secret")), HeaderMap::new(), push()).await;
+        assert_eq!(result.unwrap(), StatusCode::NO_CONTENT);
+    }
+
+    #[tokio::test]
+    async fn pushes_need_a_valid_jwt_for_the_service_account() {
+        let mut state = app_state(UserStore::new(HashMap::new())).await;
+        state.push_auth.audience = Some(AUDIENCE.to_string());
+        state.push_auth.service_account = Some("push@project.iam.gserviceaccount.com".to_string());
+
+        let unauthorized = [
+            HeaderMap::new(),
+            bearer("not-a-jwt"),
+            bearer(&push_jwt("another-audience", "push@project.iam.gserviceaccount.com")),
+        ];
+        for headers in unauthorized {
+            let result = receive_pubsub_push(State(state.clone()), query(None), headers, push()).await;
+            assert_eq!(result.unwrap_err().0, StatusCode::UNAUTHORIZED);
+        }
+
+        let headers = bearer(&push_jwt(AUDIENCE, "someone@example.com"));
+        let result = receive_pubsub_push(State(state.clone()), query(None), headers, push()).await;
+        assert_eq!(result.unwrap_err().0, StatusCode::FORBIDDEN);
+
+        let headers = bearer(&push_jwt(AUDIENCE, "push@project.iam.gserviceaccount.com"));
+        let result = receive_pubsub_push(State(state), query(None), headers, push()).await;
+        assert_eq!(result.unwrap(), StatusCode::NO_CONTENT);
+    }
+
+    #[tokio::test]
+    async fn pushes_reach_monitored_users_in_the_event_project() {
+        let users = UserStore::new(HashMap::from([
+            user("alice", "project-1", false),
+            user("bob", "project-2", false),
+            user("carol", "project-1", true),
+        ]));
+        let mut state = app_state(users).await;
+        state.push_auth.token = Some("push-secret".to_string());
+        for user_id in ["alice", "bob", "carol"] {
+            state.supervisor.sync_monitor(user_id).await;
+        }
+
+        // Bob's camera-1 is a different camera in another project, and Carol
+        // needs to sign in again
+        let result = receive_pubsub_push(State(state.clone()), query(Some("push-secret")), HeaderMap::new(), push()).await;
+        assert_eq!(result.unwrap(), StatusCode::NO_CONTENT);
+        assert!(state.dishwashers.status("alice", "camera-1").await.is_some());
+        assert!(state.dishwashers.status("bob", "camera-1").await.is_none());
+        assert!(state.dishwashers.status("carol", "camera-1").await.is_none());
+    }
+}
@@ -0,0 +1,407 @@
+#[cfg(feature = "web-api")]
+use axum::{
+    extract::{Form, Query, State},
+    http::{header, StatusCode},
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::api::auth_routes::UserRegisterRequest;
+use crate::api::handlers::auth_handlers::AppState;
+use crate::auth::accounts::{
+    check_password, find_by_email, hash_password, normalize_email, verify_no_password, verify_password,
+    AccountError,
+};
+use crate::auth::models::{LocalAccount, UserConfig};
+use crate::views;
+
+type PageError = (StatusCode, Html<String>);
+
+#[derive(Debug, Deserialize)]
+pub struct LoginRequest {
+    pub email: String,
+    pub password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ForgotPasswordRequest {
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResetPasswordRequest {
+    pub token: String,
+    pub password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TokenQuery {
+    token: String,
+}
+
+// Email a link to `path` carrying `token`. Without SMTP the email is logged
+// instead, which is enough to try accounts out locally.
+async fn send_link(app_state: &AppState, to: &str, subject: &str, text: &str, path: &str, token: &str) {
+    let body = format!("{}\n\n{}{}?token={}\n", text, app_state.public_url, path, token);
+
+    match &app_state.mailer {
+        Some(mailer) => {
+            if let Err(e) = mailer.send(to, subject, &body).await {
+                log::error!("Failed to send \"{}\" to {}: {}", subject, to, e);
+            }
+        }
+        None => log::warn!(
+            "SMTP is not configured, so \"{}\" was not sent to {}:\n{}",
+            subject, to, body
+        ),
+    }
+}
+
+fn invalid_link() -> PageError {
+    (
+        StatusCode::BAD_REQUEST,
+        Html(views::error_page("Invalid Link", &AccountError::InvalidLink.to_string())),
+    )
+}
+
+// Sign in with an email and password
+pub async fn password_login(
+    State(app_state): State<AppState>,
+    Form(request): Form<LoginRequest>,
+) -> Result<impl IntoResponse, PageError> {
+    let rejected = |error: AccountError| {
+        (StatusCode::UNAUTHORIZED, Html(views::login_page(Some(&error.to_string()))))
+    };
+
+    let email = normalize_email(&request.email).map_err(|_| rejected(AccountError::InvalidCredentials))?;
+    let user = {
+        let users_lock = app_state.users.lock().await;
+        find_by_email(&users_lock, &email)
+            .and_then(|user| Some((user.user_id.clone(), user.account.clone()?)))
+    };
+    let Some((user_id, account)) = user else {
+        verify_no_password(request.password).await;
+        return Err(rejected(AccountError::InvalidCredentials));
+    };
+
+    if !verify_password(request.password, account.password_hash.clone()).await {
+        return Err(rejected(AccountError::InvalidCredentials));
+    }
+    if !account.email_verified {
+        return Err(rejected(AccountError::Unverified));
+    }
+
+    log::info!("User {} signed in with a password", user_id);
+    Ok((
+        [(header::SET_COOKIE, app_state.sessions.issue(&user_id, Some(&account.password_hash)))],
+        Redirect::to("/dashboard"),
+    ))
+}
+
+// Registration form
+pub async fn register_page() -> Html<String> {
+    Html(views::register_page(None))
+}
+
+// Create an account with an email and password and send a link confirming
+// the address. If the address already has an account its owner is sent a
+// password reset link instead, and the response is the same either way, so
+// registering can't be used to find out who is registered.
+pub async fn register_account(
+    State(app_state): State<AppState>,
+    Form(request): Form<UserRegisterRequest>,
+) -> Result<Html<String>, PageError> {
+    let rejected = |status: StatusCode, error: AccountError| {
+        (status, Html(views::register_page(Some(&error.to_string()))))
+    };
+
+    let email = normalize_email(&request.email).map_err(|e| rejected(StatusCode::BAD_REQUEST, e))?;
+    check_password(&request.password).map_err(|e| rejected(StatusCode::BAD_REQUEST, e))?;
+    let password_hash = hash_password(request.password)
+        .await
+        .map_err(|e| rejected(StatusCode::INTERNAL_SERVER_ERROR, e))?;
+
+    // The project and cameras are chosen once the user connects Google
+    let user_id = uuid::Uuid::new_v4().to_string();
+
+    let existing = {
+        let mut users_lock = app_state.users.lock().await;
+        let existing = find_by_email(&users_lock, &email)
+            .and_then(|user| Some((user.user_id.clone(), user.account.clone()?)));
+        if existing.is_none() {
+            let account = LocalAccount {
+                email: email.clone(),
+                password_hash: password_hash.clone(),
+                email_verified: false,
+            };
+            users_lock.insert(
+                user_id.clone(),
+                UserConfig::with_account(user_id.clone(), account, app_state.default_project_id.clone()),
+            );
+        }
+        existing
+    };
+
+    match existing {
+        Some((existing_id, account)) => {
+            send_link(
+                &app_state,
+                &email,
+                "Your Dishwasher Monitor account",
+                "Someone tried to register for Dishwasher Monitor with this email address, which already has an account. If it was you, follow this link within the hour to choose a new password. Otherwise, ignore this email.",
+                "/auth/reset",
+                &app_state.sessions.password_reset_token(&existing_id, &account.password_hash),
+            )
+            .await;
+        }
+        None => {
+            log::info!("Registered user {} with a password", user_id);
+            send_link(
+                &app_state,
+                &email,
+                "Confirm your email address",
+                "Follow this link to confirm your email address and sign in to Dishwasher Monitor:",
+                "/auth/verify",
+                &app_state.sessions.email_verification_token(&user_id, &password_hash),
+            )
+            .await;
+        }
+    }
+
+    Ok(Html(views::check_email_page(
+        "We sent you a link to confirm your email address. Follow it to sign in.",
+    )))
+}
+
+// Confirm an email address from the link sent on registration, then sign in.
+// The link only signs in once: after that the user is sent to the login form.
+pub async fn verify_email(
+    State(app_state): State<AppState>,
+    Query(query): Query<TokenQuery>,
+) -> Result<Response, PageError> {
+    let (user_id, password_hash) = {
+        let mut users_lock = app_state.users.lock().await;
+        let user_id = app_state
+            .sessions
+            .verify_email_token(&query.token, |user_id| {
+                users_lock
+                    .get(user_id)
+                    .and_then(|user| user.account.as_ref())
+                    .map(|account| account.password_hash.clone())
+            })
+            .ok_or_else(invalid_link)?;
+        let account = users_lock
+            .get_mut(&user_id)
+            .and_then(|user| user.account.as_mut())
+            .ok_or_else(invalid_link)?;
+        if account.email_verified {
+            return Ok(Redirect::to("/login").into_response());
+        }
+        account.email_verified = true;
+        (user_id, account.password_hash.clone())
+    };
+    log::info!("User {} confirmed their email address", user_id);
+
+    Ok((
+        [(header::SET_COOKIE, app_state.sessions.issue(&user_id, Some(&password_hash)))],
+        Redirect::to("/dashboard"),
+    )
+        .into_response())
+}
+
+// Form requesting a password reset link
+pub async fn forgot_password_page() -> Html<String> {
+    Html(views::forgot_password_page())
+}
+
+// Email a password reset link. The response is the same whether or not the
+// address has an account, so it can't be used to find out who is registered.
+pub async fn request_password_reset(
+    State(app_state): State<AppState>,
+    Form(request): Form<ForgotPasswordRequest>,
+) -> Html<String> {
+    let user = match normalize_email(&request.email) {
+        Ok(email) => {
+            let users_lock = app_state.users.lock().await;
+            find_by_email(&users_lock, &email)
+                .and_then(|user| Some((user.user_id.clone(), user.account.clone()?)))
+        }
+        Err(_) => None,
+    };
+
+    if let Some((user_id, account)) = user {
+        send_link(
+            &app_state,
+            &account.email,
+            "Reset your password",
+            "Follow this link within the hour to choose a new password for Dishwasher Monitor. If you didn't ask to reset your password, ignore this email.",
+            "/auth/reset",
+            &app_state.sessions.password_reset_token(&user_id, &account.password_hash),
+        )
+        .await;
+    }
+
+    Html(views::check_email_page(
+        "If an account exists for that address, we sent it a link to reset the password.",
+    ))
+}
+
+// User a password reset token was issued to, if it's still valid. Tokens
+// stop working once the password they were issued for has been changed.
+fn reset_token_user(app_state: &AppState, users: &HashMap<String, UserConfig>, token: &str) -> Option<String> {
+    app_state.sessions.verify_password_reset_token(token, |user_id| {
+        users
+            .get(user_id)
+            .and_then(|user| user.account.as_ref())
+            .map(|account| account.password_hash.clone())
+    })
+}
+
+// Form choosing a new password, reached from a reset link
+pub async fn reset_password_page(
+    State(app_state): State<AppState>,
+    Query(query): Query<TokenQuery>,
+) -> Result<Html<String>, PageError> {
+    {
+        let users_lock = app_state.users.lock().await;
+        reset_token_user(&app_state, &users_lock, &query.token).ok_or_else(invalid_link)?;
+    }
+
+    Ok(Html(views::reset_password_page(&query.token, None)))
+}
+
+// Set a new password from a reset link, then sign in. Following the link
+// also proves the user owns the email address.
+pub async fn reset_password(
+    State(app_state): State<AppState>,
+    Form(request): Form<ResetPasswordRequest>,
+) -> Result<impl IntoResponse, PageError> {
+    // Check the link before spending time hashing, and again below in case
+    // it was used in the meantime
+    {
+        let users_lock = app_state.users.lock().await;
+        reset_token_user(&app_state, &users_lock, &request.token).ok_or_else(invalid_link)?;
+    }
+
+    // Only a validated token is put back into the page
+    let rejected = |status: StatusCode, error: AccountError| {
+        (status, Html(views::reset_password_page(&request.token, Some(&error.to_string()))))
+    };
+    check_password(&request.password).map_err(|e| rejected(StatusCode::BAD_REQUEST, e))?;
+    let password_hash = hash_password(request.password.clone())
+        .await
+        .map_err(|e| rejected(StatusCode::INTERNAL_SERVER_ERROR, e))?;
+
+    let user_id = {
+        let mut users_lock = app_state.users.lock().await;
+        let user_id = reset_token_user(&app_state, &users_lock, &request.token).ok_or_else(invalid_link)?;
+        let account = users_lock
+            .get_mut(&user_id)
+            .and_then(|user| user.account.as_mut())
+            .ok_or_else(invalid_link)?;
+        account.password_hash = password_hash.clone();
+        account.email_verified = true;
+        user_id
+    };
+    log::info!("User {} reset their password", user_id);
+
+    // Sessions started with the old password, including any stolen ones, end
+    Ok((
+        [(header::SET_COOKIE, app_state.sessions.issue(&user_id, Some(&password_hash)))],
+        Redirect::to("/dashboard"),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::handlers::auth_handlers::tests::app_state;
+    use crate::auth::models::UserStore;
+
+    #[tokio::test]
+    async fn verification_links_only_sign_in_once() {
+        let users = UserStore::new(HashMap::new());
+        let app_state = app_state(users.clone()).await;
+
+        let registered = register_account(
+            State(app_state.clone()),
+            Form(UserRegisterRequest {
+                email: "alice@example.com".to_string(),
+                password: "correct horse".to_string(),
+            }),
+        )
+        .await;
+        assert!(registered.is_ok());
+
+        let (user_id, password_hash) = {
+            let users_lock = users.lock().await;
+            let user = find_by_email(&users_lock, "alice@example.com").unwrap();
+            (user.user_id.clone(), user.account.as_ref().unwrap().password_hash.clone())
+        };
+        let token = app_state.sessions.email_verification_token(&user_id, &password_hash);
+        let follow = || verify_email(State(app_state.clone()), Query(TokenQuery { token: token.clone() }));
+
+        let first = follow().await.unwrap();
+        assert!(first.headers().contains_key(header::SET_COOKIE));
+        assert_eq!(first.headers()[header::LOCATION], "/dashboard");
+
+        let second = follow().await.unwrap();
+        assert!(!second.headers().contains_key(header::SET_COOKIE));
+        assert_eq!(second.headers()[header::LOCATION], "/login");
+    }
+
+    #[tokio::test]
+    async fn registering_a_taken_address_looks_like_a_new_registration() {
+        let users = UserStore::new(HashMap::new());
+        let app_state = app_state(users.clone()).await;
+        let register = |password: &str| {
+            register_account(
+                State(app_state.clone()),
+                Form(UserRegisterRequest {
+                    email: "Alice@example.com".to_string(),
+                    password: password.to_string(),
+                }),
+            )
+        };
+
+        let Html(first) = register("correct horse").await.unwrap();
+        let Html(second) = register("other horse!").await.unwrap();
+        assert_eq!(first, second);
+
+        // The original account is untouched
+        let users_lock = users.lock().await;
+        assert_eq!(users_lock.len(), 1);
+        let account = users_lock.values().next().unwrap().account.clone().unwrap();
+        assert!(verify_password("correct horse".to_string(), account.password_hash).await);
+    }
+
+    #[tokio::test]
+    async fn unknown_addresses_are_rejected_like_wrong_passwords() {
+        let password_hash = hash_password("correct horse".to_string()).await.unwrap();
+        let alice = UserConfig::with_account(
+            "alice".to_string(),
+            LocalAccount {
+                email: "alice@example.com".to_string(),
+                password_hash,
+                email_verified: true,
+            },
+            None,
+        );
+        let app_state = app_state(UserStore::new(HashMap::from([("alice".to_string(), alice)]))).await;
+        let login = |email: &str| {
+            password_login(
+                State(app_state.clone()),
+                Form(LoginRequest {
+                    email: email.to_string(),
+                    password: "wrong horse".to_string(),
+                }),
+            )
+        };
+
+        let (wrong_password, _) = login("alice@example.com").await.err().unwrap();
+        let (unknown, _) = login("bob@example.com").await.err().unwrap();
+        assert_eq!(wrong_password, StatusCode::UNAUTHORIZED);
+        assert_eq!(unknown, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -1,69 +1,79 @@
 #[cfg(feature = "web-api")]
 use axum::{
     extract::{Query, State},
-    http::StatusCode,
-    response::{Html, IntoResponse, Redirect},
-    Json,
+    http::{header, HeaderMap, StatusCode},
+    response::{AppendHeaders, Html, IntoResponse, Redirect},
 };
 use serde::Deserialize;
-use std::collections::HashMap;
 use std::sync::Arc;
 
+use crate::api::event_routes::PushAuth;
+use crate::api::session::{login_state, AuthenticatedUser, Sessions};
 use crate::auth::{
-    models::{OAuthConfig, UserStore},
-    oauth::{exchange_code_for_token, generate_oauth_state, get_authorization_url},
+    jwks::JwksVerifier,
+    models::{AuthorizationResponse, OAuthConfig, UserConfig, UserStore},
+    oauth::{
+        exchange_code_for_token, generate_oauth_state, get_authorization_url, validate_oauth_response,
+        verify_id_token, AuthError, Pkce,
+    },
+    pending::{PendingLogin, PendingLogins, PENDING_LOGIN_TTL},
 };
+use crate::dishwasher::tracker::DishwasherTracker;
+use crate::events::processor::EventProcessor;
+use crate::notifications::email::SmtpNotifier;
+use crate::sdm::SdmClient;
+use crate::supervisor::Supervisor;
 
 #[derive(Clone)]
 pub struct AppState {
     pub users: UserStore,
     pub oauth_config: OAuthConfig,
-    pub auth_states: Arc<tokio::sync::Mutex<HashMap<String, String>>>, // user_id -> state
+    pub pending_logins: PendingLogins,
+    pub id_tokens: JwksVerifier,
+    pub dishwashers: DishwasherTracker,
+    pub events: EventProcessor,
+    pub push_auth: PushAuth,
+    pub sdm: SdmClient,
+    pub sessions: Sessions,
+    /// Sends account emails, if SMTP is configured
+    pub mailer: Option<Arc<SmtpNotifier>>,
+    /// Root URL of the app, for links in emails
+    pub public_url: String,
+    /// Device Access project for new users, from `GOOGLE_PROJECT_ID`
+    pub default_project_id: Option<String>,
+    pub supervisor: Supervisor,
 }
 
-// Simple HTML login page
-pub async fn login_page() -> Html<&'static str> {
-    Html(
-        r#"
-        <!DOCTYPE html>
-        <html>
-        <head>
-            <title>Login to Dishwasher Monitor</title>
-            <style>
-                body { font-family: Arial, sans-serif; max-width: 600px; margin: 0 auto; padding: 2rem; }
-                button { padding: 0.5rem 1rem; background: #4285f4; color: white; border: none; cursor: pointer; }
-            </style>
-        </head>
-        <body>
-            <h1>Dishwasher Monitor</h1>
-            <p>Login with your Google account to monitor your smart devices.</p>
-            <a href="/auth/authorize"><button>Sign in with Google</button></a>
-        </body>
-        </html>
-        "#,
-    )
+// Sign-in page
+pub async fn login_page() -> Html<String> {
+    Html(crate::views::login_page(None))
 }
 
 // Start OAuth flow
 pub async fn start_oauth(
     State(app_state): State<AppState>,
-) -> Result<Redirect, (StatusCode, String)> {
-    // For simplicity, we're using a random ID as user ID
-    // In a real app, you'd have a proper user management system
-    let user_id = uuid::Uuid::new_v4().to_string();
-    
-    // Generate and store state parameter for CSRF protection
+    session: Option<AuthenticatedUser>,
+) -> impl IntoResponse {
+    // Generate and store state parameter for CSRF protection, remembering
+    // who is signed in in case they are reconnecting their Google account
     let state = generate_oauth_state();
+    let pkce = Pkce::generate();
     
-    {
-        let mut states = app_state.auth_states.lock().await;
-        states.insert(user_id.clone(), state.clone());
-    }
+    app_state
+        .pending_logins
+        .insert(
+            state.clone(),
+            PendingLogin::new(pkce.verifier, session.map(|session| session.user_id)),
+        )
+        .await;
     
     // Generate authorization URL
-    let auth_url = get_authorization_url(&app_state.oauth_config, &state);
+    let auth_url = get_authorization_url(&app_state.oauth_config, &state, &pkce.challenge);
     
-    Ok(Redirect::to(&auth_url))
+    (
+        [(header::SET_COOKIE, app_state.sessions.bind_login(&state, PENDING_LOGIN_TTL))],
+        Redirect::to(&auth_url),
+    )
 }
 
 #[derive(Debug, Deserialize)]
@@ -77,128 +87,169 @@ pub struct CallbackQuery {
 pub async fn oauth_callback(
     State(app_state): State<AppState>,
     Query(params): Query<CallbackQuery>,
+    headers: HeaderMap,
 ) -> Result<impl IntoResponse, (StatusCode, String)> {
     // Check for error in the callback
     if let Some(error) = params.error {
         return Err((StatusCode::BAD_REQUEST, format!("OAuth error: {}", error)));
     }
     
-    // Get the code and state params
-    let code = params.code.ok_or((StatusCode::BAD_REQUEST, "Missing code parameter".to_string()))?;
-    let state = params.state.ok_or((StatusCode::BAD_REQUEST, "Missing state parameter".to_string()))?;
-    
-    // Find the user ID that matches this state
-    let user_id = {
-        let states = app_state.auth_states.lock().await;
-        let mut matching_user_id = None;
-        
-        for (uid, saved_state) in states.iter() {
-            if *saved_state == state {
-                matching_user_id = Some(uid.clone());
-                break;
-            }
-        }
-        
-        matching_user_id.ok_or((StatusCode::BAD_REQUEST, "Invalid state parameter".to_string()))?
+    // The state must match the one this browser was given when it started
+    // signing in
+    let expected_state = login_state(&headers)
+        .ok_or((StatusCode::BAD_REQUEST, AuthError::InvalidState.to_string()))?;
+    let response = AuthorizationResponse {
+        code: params.code.unwrap_or_default(),
+        state: params.state.unwrap_or_default(),
     };
+    let code = validate_oauth_response(&response, expected_state)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    
+    // Check the sign-in hasn't expired, and find who started it
+    let login = app_state
+        .pending_logins
+        .take(&response.state)
+        .await
+        .ok_or((StatusCode::BAD_REQUEST, AuthError::InvalidState.to_string()))?;
+    let signed_in_user = login.user_id;
     
     // Exchange code for token
-    let token = exchange_code_for_token(&app_state.oauth_config, &code)
+    let token = exchange_code_for_token(&app_state.sdm, &app_state.oauth_config, &code, &login.code_verifier)
         .await
         .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Token exchange error: {}", e)))?;
     
-    // Clean up the state
-    {
-        let mut states = app_state.auth_states.lock().await;
-        states.remove(&user_id);
-    }
-    
-    // Store the user's token temporarily (in a real app, you'd use a database)
-    let project_id = std::env::var("GOOGLE_PROJECT_ID")
-        .unwrap_or_else(|_| "YOUR_PROJECT_ID".to_string());
-    
-    // Initialize user but without camera selection
-    {
+    // Users are keyed by their Google account, so signing in again finds the
+    // same user
+    let claims = verify_id_token(&app_state.id_tokens, &app_state.oauth_config, &token)
+        .await
+        .map_err(|e| (StatusCode::UNAUTHORIZED, format!("Sign-in failed: {}", e)))?;
+    let user_id = claims.sub;
+    log::info!(
+        "User {} signed in{}",
+        user_id,
+        claims.email.map(|email| format!(" as {}", email)).unwrap_or_default()
+    );
+    
+    // Give a returning user their new token and keep their cameras. Local
+    // accounts, and accounts created before users were keyed by Google
+    // account, move to the new key when their owner connects Google while
+    // signed in.
+    let (previous_id, needs_project, password_hash) = {
         let mut users_lock = app_state.users.lock().await;
-        users_lock.insert(
-            user_id.clone(),
-            crate::auth::models::UserConfig {
-                user_id: user_id.clone(),
-                device_ids: Vec::new(), // No devices selected yet
-                token,
-                project_id: project_id.clone(),
-            },
-        );
-    }
+        
+        // Two accounts can't share one Google account
+        if let Some(signed_in) = &signed_in_user {
+            let has_local_account = users_lock
+                .get(signed_in)
+                .is_some_and(|config| config.account.is_some());
+            if signed_in != &user_id && has_local_account && users_lock.contains_key(&user_id) {
+                return Err((
+                    StatusCode::CONFLICT,
+                    "This Google account is already connected to another user".to_string(),
+                ));
+            }
+        }
+        
+        let previous = match signed_in_user {
+            Some(signed_in) if signed_in != user_id && !users_lock.contains_key(&user_id) => users_lock
+                .remove(&signed_in)
+                .map(|config| (signed_in, config)),
+            _ => None,
+        };
+        let previous_id = previous.as_ref().map(|(id, _)| id.clone());
+        
+        let config = match previous {
+            Some((previous_id, mut config)) => {
+                log::info!("Moving user {} to Google account {}", previous_id, user_id);
+                config.user_id = user_id.clone();
+                users_lock.insert(user_id.clone(), config);
+                users_lock.get_mut(&user_id)
+            }
+            None => users_lock.get_mut(&user_id),
+        };
+        
+        match config {
+            Some(config) => {
+                config.token = Some(token);
+                config.needs_reauth = false;
+            }
+            None => {
+                users_lock.insert(
+                    user_id.clone(),
+                    UserConfig::new(
+                        user_id.clone(),
+                        Vec::new(), // No devices selected yet
+                        token,
+                        app_state.default_project_id.clone(),
+                    ),
+                );
+            }
+        }
+        let needs_project = users_lock
+            .get(&user_id)
+            .is_some_and(|config| config.project_id.is_none());
+        let password_hash = users_lock
+            .get(&user_id)
+            .and_then(|config| config.account.as_ref())
+            .map(|account| account.password_hash.clone());
+        (previous_id, needs_project, password_hash)
+    };
     
-    // In a real app, you'd now:
-    // 1. Create a proper session for the user
-    // 2. Store the tokens more securely
-    // 3. Redirect to a device selection page
+    // Stop the monitor running under the old ID
+    if let Some(previous_id) = previous_id {
+        app_state.supervisor.sync_monitor(&previous_id).await;
+    }
     
-    // Return success page with link to camera selection
-    Ok(Html(crate::views::auth_success_page(&user_id)))
+    // Resume monitoring for a reconnected user
+    app_state.supervisor.sync_monitor(&user_id).await;
+    
+    // Sign the user in and link to the next onboarding step
+    Ok((
+        AppendHeaders([
+            (header::SET_COOKIE, app_state.sessions.issue(&user_id, password_hash.as_deref())),
+            (header::SET_COOKIE, app_state.sessions.clear_login()),
+        ]),
+        Html(crate::views::auth_success_page(needs_project)),
+    ))
 }
 
-#[derive(Debug, Deserialize)]
-pub struct RegisterUserRequest {
-    pub user_id: String,
-    pub project_id: String,
-    pub device_ids: Vec<String>,
+// End the session
+pub async fn logout(State(app_state): State<AppState>) -> impl IntoResponse {
+    (
+        [(header::SET_COOKIE, app_state.sessions.clear())],
+        Redirect::to("/"),
+    )
 }
 
-// Register a user with their devices
-pub async fn register_user(
-    State(app_state): State<AppState>,
-    Json(request): Json<RegisterUserRequest>,
-) -> Result<Json<String>, (StatusCode, String)> {
-    let user_id = request.user_id;
-    let project_id = request.project_id;
-    let device_ids = request.device_ids;
-    let device_count = device_ids.len(); // Store count before we move device_ids
-    
-    // Get existing user config
-    let user_config = {
-        let users_lock = app_state.users.lock().await;
-        users_lock.get(&user_id).cloned()
-    };
-    
-    // If user doesn't exist or token is missing, return error
-    if user_config.is_none() {
-        return Err((StatusCode::NOT_FOUND, "User not found. Please authenticate first.".to_string()));
-    }
-    
-    // Update user config with selected devices
-    {
-        let mut users_lock = app_state.users.lock().await;
-        if let Some(config) = users_lock.get_mut(&user_id) {
-            config.device_ids = device_ids.clone();
-            config.project_id = project_id.clone();
+#[cfg(all(test, feature = "web-api"))]
+pub(crate) mod tests {
+    use super::*;
+
+    // State for calling handlers directly, with monitors that never reach
+    // Google and push deliveries verified against the test signing key
+    pub(crate) async fn app_state(users: UserStore) -> AppState {
+        let context = crate::supervisor::tests::context(users.clone()).await;
+        let verifier = crate::auth::jwks::tests::verifier().await;
+
+        AppState {
+            users,
+            oauth_config: context.oauth_config.clone(),
+            pending_logins: PendingLogins::new(PENDING_LOGIN_TTL),
+            id_tokens: verifier.clone(),
+            dishwashers: context.dishwashers.clone(),
+            events: context.events.clone(),
+            push_auth: PushAuth {
+                token: None,
+                audience: None,
+                service_account: None,
+                verifier,
+            },
+            sdm: context.sdm.clone(),
+            sessions: crate::api::session::tests::sessions(b"0123456789abcdef0123456789abcdef"),
+            mailer: None,
+            public_url: "http://localhost:3000".to_string(),
+            default_project_id: None,
+            supervisor: Supervisor::new(context),
         }
     }
-    
-    // Start a monitoring task for this user
-    let token_clone = user_config.as_ref().unwrap().token.clone();
-    let oauth_config_clone = app_state.oauth_config.clone();
-    let users_clone = Arc::clone(&app_state.users);
-    
-    // Create user config to pass to monitoring task
-    let monitoring_config = crate::auth::models::UserConfig {
-        user_id: user_id.clone(),
-        device_ids,
-        token: token_clone,
-        project_id,
-    };
-    
-    // Spawn monitoring task
-    tokio::spawn(async move {
-        crate::monitor_user_cameras(
-            monitoring_config,
-            users_clone,
-            oauth_config_clone,
-        )
-        .await;
-    });
-    
-    Ok(Json(format!("User {} registered with {} devices. Monitoring started.", user_id, device_count)))
 }
@@ -1 +1,2 @@
+pub mod account_handlers;
 pub mod auth_handlers;
@@ -0,0 +1,500 @@
+#[cfg(feature = "web-api")]
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header, request::Parts, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::env;
+use std::error::Error;
+use std::sync::Arc;
+
+use crate::api::handlers::auth_handlers::AppState;
+use crate::auth::models::UserConfig;
+
+const COOKIE_NAME: &str = "dishwashmon_session";
+
+// Ties an OAuth state to the browser that started the sign-in
+const LOGIN_COOKIE_NAME: &str = "dishwashmon_login";
+
+// Secrets shorter than this are too easy to brute force
+const MIN_SECRET_LEN: usize = 32;
+
+// How long emailed links stay valid
+const VERIFY_EMAIL_TTL_HOURS: i64 = 48;
+const PASSWORD_RESET_TTL_HOURS: i64 = 1;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// What a signed value is for. Part of the signed data, so a value issued for
+// one purpose is rejected for any other.
+const SESSION: &str = "session";
+const VERIFY_EMAIL: &str = "verify_email";
+const PASSWORD_RESET: &str = "password_reset";
+
+// Contents of a session cookie
+#[derive(Debug, Serialize, Deserialize)]
+struct SessionClaims {
+    // User the session belongs to
+    sub: String,
+    // Expiry as a Unix timestamp
+    exp: i64,
+    // Fingerprint of a local account's password when the session started, so
+    // changing the password ends every existing session
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pwd: Option<String>,
+}
+
+impl SessionClaims {
+    // Whether the session's user still exists and has the password it
+    // started with
+    fn is_current(&self, user: Option<&UserConfig>) -> bool {
+        user.is_some_and(|user| {
+            let current = user.account.as_ref().map(|account| password_fingerprint(&account.password_hash));
+            current == self.pwd
+        })
+    }
+}
+
+// Contents of an emailed link
+#[derive(Debug, Serialize, Deserialize)]
+struct LinkClaims {
+    sub: String,
+    exp: i64,
+    // Fingerprint of the user's password when the link was sent, so the link
+    // stops working once a reset link has been used or the password changed
+    pwd: String,
+}
+
+/// Issues and verifies signed session cookies, and the signed links emailed
+/// to users with local accounts
+#[derive(Clone)]
+pub struct Sessions {
+    key: Arc<Vec<u8>>,
+    ttl: chrono::Duration,
+    // Only send the cookie over HTTPS
+    secure: bool,
+}
+
+impl Sessions {
+    /// Sign sessions with `SESSION_SECRET`, or with a random key if it isn't
+    /// set, in which case everyone is signed out when the service restarts
+    pub fn from_env(secure: bool) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let key = match env::var("SESSION_SECRET").ok().filter(|v| !v.is_empty()) {
+            Some(secret) if secret.len() < MIN_SECRET_LEN => {
+                return Err(format!("SESSION_SECRET must be at least {} characters", MIN_SECRET_LEN).into());
+            }
+            Some(secret) => secret.into_bytes(),
+            None => {
+                log::warn!("SESSION_SECRET is not set, sessions will not survive a restart");
+                rand::thread_rng().gen::<[u8; 32]>().to_vec()
+            }
+        };
+        let ttl_hours = env::var("SESSION_TTL_HOURS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(24 * 7);
+
+        Ok(Self {
+            key: Arc::new(key),
+            ttl: chrono::Duration::hours(ttl_hours),
+            secure,
+        })
+    }
+
+    fn mac(&self, purpose: &str, payload: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        mac.update(purpose.as_bytes());
+        mac.update(b":");
+        mac.update(payload.as_bytes());
+        mac
+    }
+
+    // `claims` as URL-safe `payload.signature`
+    fn encode<T: Serialize>(&self, purpose: &str, claims: &T) -> String {
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims).expect("claims serialize"));
+        let signature = URL_SAFE_NO_PAD.encode(self.mac(purpose, &payload).finalize().into_bytes());
+        format!("{}.{}", payload, signature)
+    }
+
+    // Claims of a value from `encode` with a valid signature for `purpose`
+    fn decode<T: DeserializeOwned>(&self, purpose: &str, value: &str) -> Option<T> {
+        let (payload, signature) = value.split_once('.')?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+
+        self.mac(purpose, payload).verify_slice(&signature).ok()?;
+
+        serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()
+    }
+
+    /// `Set-Cookie` header starting a session for `user_id`. Pass the hash
+    /// of their password if they have a local account; the session ends
+    /// when it changes.
+    pub fn issue(&self, user_id: &str, password_hash: Option<&str>) -> HeaderValue {
+        let claims = SessionClaims {
+            sub: user_id.to_string(),
+            exp: (chrono::Utc::now() + self.ttl).timestamp(),
+            pwd: password_hash.map(password_fingerprint),
+        };
+
+        self.cookie(COOKIE_NAME, "/", &self.encode(SESSION, &claims), self.ttl.num_seconds())
+    }
+
+    /// `Set-Cookie` header ending the session
+    pub fn clear(&self) -> HeaderValue {
+        self.cookie(COOKIE_NAME, "/", "", 0)
+    }
+
+    /// `Set-Cookie` header holding the state of a sign-in started by this
+    /// browser, so the callback only completes in the same browser
+    pub fn bind_login(&self, state: &str, ttl: std::time::Duration) -> HeaderValue {
+        self.cookie(LOGIN_COOKIE_NAME, "/auth", state, ttl.as_secs() as i64)
+    }
+
+    /// `Set-Cookie` header removing the sign-in state once it's been used
+    pub fn clear_login(&self) -> HeaderValue {
+        self.cookie(LOGIN_COOKIE_NAME, "/auth", "", 0)
+    }
+
+    fn cookie(&self, name: &str, path: &str, value: &str, max_age: i64) -> HeaderValue {
+        // Lax still sends the cookie on the top-level redirect back from Google
+        let mut cookie = format!(
+            "{}={}; Path={}; Max-Age={}; HttpOnly; SameSite=Lax",
+            name, value, path, max_age
+        );
+        if self.secure {
+            cookie.push_str("; Secure");
+        }
+        HeaderValue::from_str(&cookie).expect("cookie is valid header text")
+    }
+
+    // Claims of a cookie with a valid signature that hasn't expired
+    fn verify(&self, value: &str) -> Option<SessionClaims> {
+        let claims: SessionClaims = self.decode(SESSION, value)?;
+        if claims.exp <= chrono::Utc::now().timestamp() {
+            return None;
+        }
+        Some(claims)
+    }
+
+    // Token for an emailed link for the user with password hash
+    // `password_hash`, valid for `ttl_hours`
+    fn link_token(&self, purpose: &str, user_id: &str, password_hash: &str, ttl_hours: i64) -> String {
+        let claims = LinkClaims {
+            sub: user_id.to_string(),
+            exp: (chrono::Utc::now() + chrono::Duration::hours(ttl_hours)).timestamp(),
+            pwd: password_fingerprint(password_hash),
+        };
+        self.encode(purpose, &claims)
+    }
+
+    // User an emailed link was sent to, if it hasn't expired. `current_hash`
+    // looks up the user's password hash, and the link is only accepted if
+    // the password hasn't changed since it was sent.
+    fn verify_link_token(
+        &self,
+        purpose: &str,
+        token: &str,
+        current_hash: impl FnOnce(&str) -> Option<String>,
+    ) -> Option<String> {
+        let claims: LinkClaims = self.decode(purpose, token)?;
+        if claims.exp <= chrono::Utc::now().timestamp() {
+            return None;
+        }
+        let current = password_fingerprint(&current_hash(&claims.sub)?);
+        if current != claims.pwd {
+            return None;
+        }
+        Some(claims.sub)
+    }
+
+    /// Token for the link confirming the email address of `user_id`, whose
+    /// password has hash `password_hash`
+    pub fn email_verification_token(&self, user_id: &str, password_hash: &str) -> String {
+        self.link_token(VERIFY_EMAIL, user_id, password_hash, VERIFY_EMAIL_TTL_HOURS)
+    }
+
+    /// User whose email address a verification token confirms, checked like
+    /// `verify_password_reset_token`
+    pub fn verify_email_token(
+        &self,
+        token: &str,
+        current_hash: impl FnOnce(&str) -> Option<String>,
+    ) -> Option<String> {
+        self.verify_link_token(VERIFY_EMAIL, token, current_hash)
+    }
+
+    /// Token for the link resetting the password with hash `password_hash`
+    pub fn password_reset_token(&self, user_id: &str, password_hash: &str) -> String {
+        self.link_token(PASSWORD_RESET, user_id, password_hash, PASSWORD_RESET_TTL_HOURS)
+    }
+
+    /// User a password reset token was issued to, if it hasn't expired.
+    /// `current_hash` looks up the user's password hash, and the token is
+    /// only accepted if the password hasn't changed since it was issued.
+    pub fn verify_password_reset_token(
+        &self,
+        token: &str,
+        current_hash: impl FnOnce(&str) -> Option<String>,
+    ) -> Option<String> {
+        self.verify_link_token(PASSWORD_RESET, token, current_hash)
+    }
+}
+
+// Identifies a password hash without revealing it
+fn password_fingerprint(password_hash: &str) -> String {
+    URL_SAFE_NO_PAD.encode(&Sha256::digest(password_hash.as_bytes())[..16])
+}
+
+// Value of the named cookie in a request
+fn cookie_value<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// State of the sign-in this browser started, from its cookie
+pub fn login_state(headers: &HeaderMap) -> Option<&str> {
+    cookie_value(headers, LOGIN_COOKIE_NAME).filter(|state| !state.is_empty())
+}
+
+/// The signed-in user, taken from the session cookie
+pub struct AuthenticatedUser {
+    pub user_id: String,
+}
+
+/// Sent when a request has no valid session: browsers are sent to the login
+/// page, API clients get a 401
+pub enum SessionRejection {
+    Login,
+    Unauthorized,
+}
+
+impl IntoResponse for SessionRejection {
+    fn into_response(self) -> Response {
+        match self {
+            SessionRejection::Login => Redirect::to("/auth/login").into_response(),
+            SessionRejection::Unauthorized => {
+                (StatusCode::UNAUTHORIZED, "Not signed in").into_response()
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for AuthenticatedUser {
+    type Rejection = SessionRejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let claims = cookie_value(&parts.headers, COOKIE_NAME).and_then(|value| state.sessions.verify(value));
+
+        // The user may have been removed, or changed their password, since
+        // the session was issued
+        let user_id = match claims {
+            Some(claims) if claims.is_current(state.users.lock().await.get(&claims.sub)) => claims.sub,
+            _ => {
+                let wants_html = parts
+                    .headers
+                    .get(header::ACCEPT)
+                    .and_then(|accept| accept.to_str().ok())
+                    .is_some_and(|accept| accept.contains("text/html"));
+                return Err(if wants_html {
+                    SessionRejection::Login
+                } else {
+                    SessionRejection::Unauthorized
+                });
+            }
+        };
+
+        Ok(Self { user_id })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn sessions(key: &[u8]) -> Sessions {
+        Sessions {
+            key: Arc::new(key.to_vec()),
+            ttl: chrono::Duration::hours(1),
+            secure: true,
+        }
+    }
+
+    // Session value set by a `Set-Cookie` header
+    fn session_value(set_cookie: &HeaderValue) -> String {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::COOKIE, set_cookie.clone());
+        cookie_value(&headers, COOKIE_NAME).unwrap().to_string()
+    }
+
+    #[test]
+    fn issued_sessions_verify() {
+        let sessions = sessions(b"0123456789abcdef0123456789abcdef");
+
+        let set_cookie = sessions.issue("alice", None);
+        let cookie = set_cookie.to_str().unwrap();
+        assert!(cookie.contains("HttpOnly"));
+        assert!(cookie.contains("SameSite=Lax"));
+        assert!(cookie.ends_with("; Secure"));
+        let claims = sessions.verify(&session_value(&set_cookie)).unwrap();
+        assert_eq!(claims.sub, "alice");
+        assert_eq!(claims.pwd, None);
+    }
+
+    #[test]
+    fn forged_or_expired_sessions_are_rejected() {
+        let sessions = sessions(b"0123456789abcdef0123456789abcdef");
+        let value = session_value(&sessions.issue("alice", None));
+
+        // Signed with another key
+        let other = self::sessions(b"fedcba9876543210fedcba9876543210");
+        assert!(other.verify(&value).is_none());
+
+        // Claims changed without re-signing
+        let (_, signature) = value.split_once('.').unwrap();
+        let forged = URL_SAFE_NO_PAD.encode(
+            serde_json::to_vec(&SessionClaims {
+                sub: "bob".to_string(),
+                exp: (chrono::Utc::now() + chrono::Duration::hours(1)).timestamp(),
+                pwd: None,
+            })
+            .unwrap(),
+        );
+        assert!(sessions.verify(&format!("{}.{}", forged, signature)).is_none());
+        assert!(sessions.verify("not a session").is_none());
+
+        let expired = sessions.encode(
+            SESSION,
+            &SessionClaims {
+                sub: "alice".to_string(),
+                exp: chrono::Utc::now().timestamp() - 1,
+                pwd: None,
+            },
+        );
+        assert!(sessions.verify(&expired).is_none());
+
+        // Emailed links can't be used as sessions
+        assert!(sessions.verify(&sessions.email_verification_token("alice", "hash")).is_none());
+        assert!(sessions.verify(&sessions.password_reset_token("alice", "hash")).is_none());
+    }
+
+    #[test]
+    fn sessions_remember_the_password_they_started_with() {
+        let sessions = sessions(b"0123456789abcdef0123456789abcdef");
+
+        let claims = sessions
+            .verify(&session_value(&sessions.issue("alice", Some("old-hash"))))
+            .unwrap();
+        // The hash itself isn't revealed
+        assert!(!serde_json::to_string(&claims).unwrap().contains("old-hash"));
+
+        let user = |password_hash: Option<&str>| -> UserConfig {
+            serde_json::from_value(serde_json::json!({
+                "user_id": "alice",
+                "device_ids": [],
+                "account": password_hash.map(|password_hash| serde_json::json!({
+                    "email": "alice@example.com",
+                    "password_hash": password_hash,
+                })),
+            }))
+            .unwrap()
+        };
+        assert!(claims.is_current(Some(&user(Some("old-hash")))));
+        assert!(!claims.is_current(Some(&user(Some("new-hash")))));
+        assert!(!claims.is_current(Some(&user(None))));
+        assert!(!claims.is_current(None));
+
+        // Sessions of users without a password end when they get one
+        let google_only = sessions.verify(&session_value(&sessions.issue("alice", None))).unwrap();
+        assert!(google_only.is_current(Some(&user(None))));
+        assert!(!google_only.is_current(Some(&user(Some("old-hash")))));
+    }
+
+    #[test]
+    fn email_verification_tokens_identify_the_user() {
+        let sessions = sessions(b"0123456789abcdef0123456789abcdef");
+
+        let hash = |_: &str| Some("hash".to_string());
+
+        let token = sessions.email_verification_token("alice", "hash");
+        assert_eq!(sessions.verify_email_token(&token, hash), Some("alice".to_string()));
+        // The password was reset in the meantime
+        assert_eq!(sessions.verify_email_token(&token, |_| Some("new-hash".to_string())), None);
+
+        // Session cookies and reset links aren't verification links
+        let session = session_value(&sessions.issue("alice", Some("hash")));
+        assert_eq!(sessions.verify_email_token(&session, hash), None);
+        assert_eq!(sessions.verify_email_token(&sessions.password_reset_token("alice", "hash"), hash), None);
+
+        let expired = sessions.encode(
+            VERIFY_EMAIL,
+            &LinkClaims {
+                sub: "alice".to_string(),
+                exp: chrono::Utc::now().timestamp() - 1,
+                pwd: password_fingerprint("hash"),
+            },
+        );
+        assert_eq!(sessions.verify_email_token(&expired, hash), None);
+    }
+
+    #[test]
+    fn reset_tokens_stop_working_once_the_password_changes() {
+        let sessions = sessions(b"0123456789abcdef0123456789abcdef");
+        let token = sessions.password_reset_token("alice", "old-hash");
+
+        assert_eq!(
+            sessions.verify_password_reset_token(&token, |_| Some("old-hash".to_string())),
+            Some("alice".to_string())
+        );
+        assert_eq!(
+            sessions.verify_password_reset_token(&token, |_| Some("new-hash".to_string())),
+            None
+        );
+        // The user or their local account is gone
+        assert_eq!(sessions.verify_password_reset_token(&token, |_| None), None);
+        assert_eq!(
+            sessions.verify_password_reset_token(&sessions.email_verification_token("alice", "old-hash"), |_| {
+                Some("old-hash".to_string())
+            }),
+            None
+        );
+
+        let expired = sessions.encode(
+            PASSWORD_RESET,
+            &LinkClaims {
+                sub: "alice".to_string(),
+                exp: chrono::Utc::now().timestamp() - 1,
+                pwd: password_fingerprint("old-hash"),
+            },
+        );
+        assert_eq!(
+            sessions.verify_password_reset_token(&expired, |_| Some("old-hash".to_string())),
+            None
+        );
+    }
+
+    #[test]
+    fn cookies_are_found_among_others() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::COOKIE,
+            HeaderValue::from_static("theme=dark; dishwashmon_login=state-1; dishwashmon_session=abc.def"),
+        );
+
+        assert_eq!(cookie_value(&headers, COOKIE_NAME), Some("abc.def"));
+        assert_eq!(login_state(&headers), Some("state-1"));
+        assert_eq!(cookie_value(&headers, "missing"), None);
+    }
+}
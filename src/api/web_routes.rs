@@ -1,15 +1,20 @@
 #[cfg(feature = "web-api")]
 use axum::{
-    extract::{Query, State, Form},
+    extract::{State, Form},
     http::StatusCode,
     response::{Html, Redirect},
     routing::{get, post},
     Router,
 };
 use serde::Deserialize;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
+use crate::api::device_routes::{
+    check_user_devices, project_error_status, set_user_project, set_user_subscription,
+};
 use crate::api::handlers::auth_handlers::AppState;
+use crate::api::session::AuthenticatedUser;
+use crate::auth::models::WebhookConfig;
 use crate::devices::discovery;
 use crate::views;
 
@@ -18,9 +23,13 @@ pub fn web_routes() -> Router<AppState> {
     Router::new()
         .route("/", get(index_page))
         .route("/dashboard", get(dashboard_page))
+        .route("/project", get(project_page).post(choose_project))
         .route("/cameras/select", get(camera_selection))
         .route("/cameras/register", post(register_camera))
         .route("/cameras/unregister", post(unregister_camera))
+        .route("/notifications/settings", post(update_notification_settings))
+        .route("/webhooks/register", post(register_webhook))
+        .route("/webhooks/unregister", post(unregister_webhook))
 }
 
 // Index page handler
@@ -28,28 +37,11 @@ async fn index_page() -> Html<String> {
     Html(views::home_page())
 }
 
-// Query params for user ID
-#[derive(Debug, Deserialize)]
-struct UserIdQuery {
-    user_id: Option<String>,
-}
-
 // Camera selection page handler
 async fn camera_selection(
     State(app_state): State<AppState>,
-    Query(params): Query<UserIdQuery>,
+    AuthenticatedUser { user_id }: AuthenticatedUser,
 ) -> Result<Html<String>, (StatusCode, String)> {
-    // Get user ID from query params
-    let user_id = match params.user_id {
-        Some(id) => id,
-        None => {
-            return Err((
-                StatusCode::BAD_REQUEST,
-                "Missing user_id parameter".to_string(),
-            ))
-        }
-    };
-
     // Get user config
     let user_config = {
         let users_lock = app_state.users.lock().await;
@@ -61,16 +53,32 @@ async fn camera_selection(
         None => {
             return Ok(Html(views::error_page(
                 "User Not Found",
-                "The user ID provided is not valid. Please authenticate again.",
+                "Your account no longer exists. Please authenticate again.",
             )))
         }
     };
 
+    let token = match &user_config.token {
+        Some(token) => token,
+        None => {
+            return Ok(Html(views::error_page(
+                "Google Account Not Connected",
+                "Connect your Google account from the dashboard to choose cameras.",
+            )))
+        }
+    };
+
+    // Cameras can't be listed until the user says which project they're in
+    let project_id = match &user_config.project_id {
+        Some(project_id) => project_id,
+        None => return Ok(Html(views::project_page(None, None, None))),
+    };
+
     // Fetch camera list
-    match discovery::discover_devices(&user_config.project_id, &user_config.token).await {
+    match discovery::discover_devices(&app_state.sdm, project_id, token).await {
         Ok(all_devices) => {
             let cameras = discovery::filter_cameras(&all_devices);
-            Ok(Html(views::camera_selection_page(&user_id, &cameras)))
+            Ok(Html(views::camera_selection_page(&cameras)))
         }
         Err(e) => {
             let error_message = format!("Failed to fetch cameras: {}", e);
@@ -82,19 +90,8 @@ async fn camera_selection(
 // Dashboard page handler
 async fn dashboard_page(
     State(app_state): State<AppState>,
-    Query(params): Query<UserIdQuery>,
+    AuthenticatedUser { user_id }: AuthenticatedUser,
 ) -> Result<Html<String>, (StatusCode, String)> {
-    // Get user ID from query params
-    let user_id = match params.user_id {
-        Some(id) => id,
-        None => {
-            return Err((
-                StatusCode::BAD_REQUEST,
-                "Missing user_id parameter".to_string(),
-            ))
-        }
-    };
-
     // Get user config
     let user_config = {
         let users_lock = app_state.users.lock().await;
@@ -106,16 +103,28 @@ async fn dashboard_page(
         None => {
             return Ok(Html(views::error_page(
                 "User Not Found",
-                "The user ID provided is not valid. Please authenticate again.",
+                "Your account no longer exists. Please authenticate again.",
             )))
         }
     };
 
-    // Fetch all devices to get details for the registered ones
-    match discovery::discover_devices(&user_config.project_id, &user_config.token).await {
+    // Fetch all devices to get details for the registered ones. Google won't
+    // answer until the user reconnects, so just list the device IDs.
+    let all_devices = match (&user_config.token, &user_config.project_id) {
+        (Some(token), Some(project_id)) if !user_config.needs_reauth => {
+            discovery::discover_devices(&app_state.sdm, project_id, token).await
+        }
+        _ => Ok(user_config
+            .device_ids
+            .iter()
+            .map(|device_id| discovery::Device::placeholder(device_id))
+            .collect()),
+    };
+
+    match all_devices {
         Ok(all_devices) => {
             // Create a HashSet of registered device IDs for efficient lookup
-            let registered_ids: HashSet<String> = user_config.device_ids.into_iter().collect();
+            let registered_ids: HashSet<String> = user_config.device_ids.iter().cloned().collect();
             
             // Filter devices to only include registered ones
             let registered_cameras: Vec<_> = all_devices
@@ -123,7 +132,23 @@ async fn dashboard_page(
                 .filter(|device| registered_ids.contains(&device.device_id))
                 .collect();
             
-            Ok(Html(views::dashboard_page(&user_id, &registered_cameras)))
+            // Look up the dishwasher state inferred for each camera
+            let mut states = HashMap::new();
+            for camera in &registered_cameras {
+                if let Some(status) = app_state.dishwashers.status(&user_id, &camera.device_id).await {
+                    states.insert(camera.device_id.clone(), status.state);
+                }
+            }
+            
+            Ok(Html(views::dashboard_page(
+                &registered_cameras,
+                &states,
+                &user_config.notifications,
+                &user_config.webhooks,
+                user_config.needs_reauth,
+                user_config.token.is_some(),
+                user_config.project_id.as_deref(),
+            )))
         }
         Err(e) => {
             let error_message = format!("Failed to fetch cameras: {}", e);
@@ -132,20 +157,79 @@ async fn dashboard_page(
     }
 }
 
+// Onboarding step choosing the user's Device Access project
+async fn project_page(
+    State(app_state): State<AppState>,
+    AuthenticatedUser { user_id }: AuthenticatedUser,
+) -> Html<String> {
+    let (project_id, subscription) = {
+        let users_lock = app_state.users.lock().await;
+        users_lock
+            .get(&user_id)
+            .map(|config| (config.project_id.clone(), config.pubsub_subscription.clone()))
+            .unwrap_or_default()
+    };
+
+    // Only show values that are safe to put back into the page
+    let project_id = project_id.and_then(|id| discovery::parse_project_id(&id).ok());
+    let subscription = subscription.and_then(|name| discovery::parse_subscription(&name).ok().flatten());
+    Html(views::project_page(project_id.as_deref(), subscription.as_deref(), None))
+}
+
+// Form data for choosing a project
+#[derive(Debug, Deserialize)]
+struct ProjectForm {
+    project_id: String,
+    #[serde(default)]
+    pubsub_subscription: String,
+}
+
+// Check the project with Google and save it along with the subscription,
+// then move on to choosing cameras
+async fn choose_project(
+    State(app_state): State<AppState>,
+    AuthenticatedUser { user_id }: AuthenticatedUser,
+    Form(form): Form<ProjectForm>,
+) -> Result<Redirect, (StatusCode, Html<String>)> {
+    let saved = async {
+        let subscription = discovery::parse_subscription(&form.pubsub_subscription)?;
+        set_user_project(&app_state, &user_id, &form.project_id).await?;
+        set_user_subscription(&app_state, &user_id, subscription).await
+    }
+    .await;
+
+    if let Err(e) = saved {
+        let project_id = discovery::parse_project_id(&form.project_id).ok();
+        let subscription = discovery::parse_subscription(&form.pubsub_subscription).ok().flatten();
+        return Err((
+            project_error_status(&e),
+            Html(views::project_page(
+                project_id.as_deref(),
+                subscription.as_deref(),
+                Some(&e.to_string()),
+            )),
+        ));
+    }
+
+    Ok(Redirect::to("/cameras/select"))
+}
+
 // Form data for camera registration
 #[derive(Debug, Deserialize)]
 struct CameraForm {
-    user_id: String,
     device_id: String,
 }
 
 // Register a camera
 async fn register_camera(
     State(app_state): State<AppState>,
+    AuthenticatedUser { user_id }: AuthenticatedUser,
     Form(form): Form<CameraForm>,
 ) -> Result<Redirect, (StatusCode, String)> {
-    let user_id = form.user_id;
     let device_id = form.device_id;
+    check_user_devices(&app_state, &user_id, std::slice::from_ref(&device_id))
+        .await
+        .map_err(|e| (project_error_status(&e), e.to_string()))?;
     
     // Get user config
     let mut update_successful = false;
@@ -160,9 +244,14 @@ async fn register_camera(
         }
     }
     
+    // Start or stop monitoring if the user's cameras changed
+    if update_successful {
+        app_state.supervisor.sync_monitor(&user_id).await;
+    }
+    
     // Redirect to dashboard
     if update_successful {
-        Ok(Redirect::to(&format!("/dashboard?user_id={}", user_id)))
+        Ok(Redirect::to("/dashboard"))
     } else {
         Err((
             StatusCode::NOT_FOUND,
@@ -174,9 +263,9 @@ async fn register_camera(
 // Unregister a camera
 async fn unregister_camera(
     State(app_state): State<AppState>,
+    AuthenticatedUser { user_id }: AuthenticatedUser,
     Form(form): Form<CameraForm>,
 ) -> Result<Redirect, (StatusCode, String)> {
-    let user_id = form.user_id;
     let device_id = form.device_id;
     
     // Get user config
@@ -190,13 +279,146 @@ async fn unregister_camera(
         }
     }
     
+    // Start or stop monitoring if the user's cameras changed
+    if update_successful {
+        app_state.supervisor.sync_monitor(&user_id).await;
+    }
+    
     // Redirect to dashboard
     if update_successful {
-        Ok(Redirect::to(&format!("/dashboard?user_id={}", user_id)))
+        Ok(Redirect::to("/dashboard"))
     } else {
         Err((
             StatusCode::NOT_FOUND,
             "Failed to update user configuration".to_string(),
         ))
     }
-}
\ No newline at end of file
+}
+
+// Form data for notification settings; unchecked boxes are omitted
+#[derive(Debug, Deserialize)]
+struct NotificationSettingsForm {
+    email_recipients: String,
+    notify_on_finished: Option<String>,
+    notify_on_person: Option<String>,
+}
+
+// Update where and when a user is notified
+async fn update_notification_settings(
+    State(app_state): State<AppState>,
+    AuthenticatedUser { user_id }: AuthenticatedUser,
+    Form(form): Form<NotificationSettingsForm>,
+) -> Result<Redirect, (StatusCode, String)> {
+    let email_recipients: Vec<String> = form
+        .email_recipients
+        .split(',')
+        .map(|address| address.trim().to_string())
+        .filter(|address| !address.is_empty())
+        .collect();
+    if let Some(invalid) = email_recipients
+        .iter()
+        .find(|address| address.parse::<lettre::Address>().is_err())
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Invalid email address: {}", invalid),
+        ));
+    }
+
+    {
+        let mut users_lock = app_state.users.lock().await;
+        match users_lock.get_mut(&user_id) {
+            Some(config) => {
+                config.notifications.email_recipients = email_recipients;
+                config.notifications.notify_on_finished = form.notify_on_finished.is_some();
+                config.notifications.notify_on_person = form.notify_on_person.is_some();
+            }
+            None => {
+                return Err((
+                    StatusCode::NOT_FOUND,
+                    "Failed to update user configuration".to_string(),
+                ))
+            }
+        }
+    }
+    // Person alerts are registered for users who want them
+    app_state.supervisor.sync_monitor(&user_id).await;
+
+    Ok(Redirect::to("/dashboard"))
+}
+
+// Form data for adding a webhook
+#[derive(Debug, Deserialize)]
+struct WebhookForm {
+    url: String,
+}
+
+// Register an outbound webhook with a freshly generated signing secret
+async fn register_webhook(
+    State(app_state): State<AppState>,
+    AuthenticatedUser { user_id }: AuthenticatedUser,
+    Form(form): Form<WebhookForm>,
+) -> Result<Redirect, (StatusCode, String)> {
+    let url = reqwest::Url::parse(form.url.trim())
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid webhook URL: {}", e)))?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Webhook URL must use http or https".to_string(),
+        ));
+    }
+
+    let webhook = WebhookConfig {
+        id: uuid::Uuid::new_v4().to_string(),
+        url: url.to_string(),
+        secret: format!(
+            "{}{}",
+            uuid::Uuid::new_v4().simple(),
+            uuid::Uuid::new_v4().simple()
+        ),
+    };
+
+    {
+        let mut users_lock = app_state.users.lock().await;
+        match users_lock.get_mut(&user_id) {
+            Some(config) => config.webhooks.push(webhook),
+            None => {
+                return Err((
+                    StatusCode::NOT_FOUND,
+                    "Failed to update user configuration".to_string(),
+                ))
+            }
+        }
+    }
+
+    Ok(Redirect::to("/dashboard"))
+}
+
+// Form data for removing a webhook
+#[derive(Debug, Deserialize)]
+struct WebhookRemovalForm {
+    webhook_id: String,
+}
+
+// Remove an outbound webhook
+async fn unregister_webhook(
+    State(app_state): State<AppState>,
+    AuthenticatedUser { user_id }: AuthenticatedUser,
+    Form(form): Form<WebhookRemovalForm>,
+) -> Result<Redirect, (StatusCode, String)> {
+
+    {
+        let mut users_lock = app_state.users.lock().await;
+        match users_lock.get_mut(&user_id) {
+            Some(config) => config.webhooks.retain(|webhook| webhook.id != form.webhook_id),
+            None => {
+                return Err((
+                    StatusCode::NOT_FOUND,
+                    "Failed to update user configuration".to_string(),
+                ))
+            }
+        }
+    }
+
+    Ok(Redirect::to("/dashboard"))
+}
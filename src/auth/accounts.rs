@@ -0,0 +1,121 @@
+use argon2::password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use std::collections::HashMap;
+use std::sync::LazyLock;
+use thiserror::Error;
+
+use crate::auth::models::UserConfig;
+
+pub const MIN_PASSWORD_LEN: usize = 8;
+
+// Longer passwords only cost hashing time
+const MAX_PASSWORD_LEN: usize = 256;
+
+// Checked in place of a real hash when there's no account, so sign-ins take
+// as long whether or not the email address is registered
+static DUMMY_HASH: LazyLock<String> = LazyLock::new(|| {
+    Argon2::default()
+        .hash_password(b"no account", &SaltString::generate(&mut OsRng))
+        .map(|hash| hash.to_string())
+        .unwrap_or_default()
+});
+
+/// Why an email and password registration or sign-in was refused. The
+/// messages are shown to the user.
+#[derive(Error, Debug)]
+pub enum AccountError {
+    #[error("Enter a valid email address")]
+    InvalidEmail,
+
+    #[error("Passwords must be between {MIN_PASSWORD_LEN} and {MAX_PASSWORD_LEN} characters")]
+    InvalidPassword,
+
+    #[error("Incorrect email address or password")]
+    InvalidCredentials,
+
+    #[error("Confirm your email address using the link we sent before signing in")]
+    Unverified,
+
+    #[error("This link is invalid or has expired")]
+    InvalidLink,
+
+    #[error("Failed to hash password: {0}")]
+    Hash(String),
+}
+
+/// Trim and lowercase an email address, rejecting anything that clearly
+/// isn't one. Whether it exists is checked by sending it a verification link.
+pub fn normalize_email(email: &str) -> Result<String, AccountError> {
+    let email = email.trim().to_lowercase();
+    match email.split_once('@') {
+        Some((local, domain))
+            if !local.is_empty()
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+                && !email.chars().any(|c| c.is_whitespace() || c.is_control()) =>
+        {
+            Ok(email)
+        }
+        _ => Err(AccountError::InvalidEmail),
+    }
+}
+
+pub fn check_password(password: &str) -> Result<(), AccountError> {
+    let len = password.chars().count();
+    if !(MIN_PASSWORD_LEN..=MAX_PASSWORD_LEN).contains(&len) {
+        return Err(AccountError::InvalidPassword);
+    }
+    Ok(())
+}
+
+/// Hash a password with Argon2 and a random salt. Hashing is deliberately
+/// slow, so it runs on the blocking thread pool.
+pub async fn hash_password(password: String) -> Result<String, AccountError> {
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| AccountError::Hash(e.to_string()))
+    })
+    .await
+    .map_err(|e| AccountError::Hash(e.to_string()))?
+}
+
+/// Check a password against a hash from `hash_password`
+pub async fn verify_password(password: String, password_hash: String) -> bool {
+    tokio::task::spawn_blocking(move || password_matches(&password, &password_hash))
+        .await
+        .unwrap_or(false)
+}
+
+/// Spend as long as `verify_password` does, for sign-ins to addresses
+/// without an account
+pub async fn verify_no_password(password: String) {
+    let _ = tokio::task::spawn_blocking(move || password_matches(&password, &DUMMY_HASH)).await;
+}
+
+fn password_matches(password: &str, password_hash: &str) -> bool {
+    PasswordHash::new(password_hash)
+        .map(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+        .unwrap_or(false)
+}
+
+/// The user with a local account for `email`, which must be normalized
+pub fn find_by_email<'a>(users: &'a HashMap<String, UserConfig>, email: &str) -> Option<&'a UserConfig> {
+    users
+        .values()
+        .find(|user| user.account.as_ref().is_some_and(|account| account.email == email))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_ins_without_an_account_check_a_real_hash() {
+        assert!(PasswordHash::new(&DUMMY_HASH).is_ok());
+        assert!(!password_matches("no account", "not a hash"));
+    }
+}
@@ -1,2 +1,5 @@
+pub mod accounts;
+pub mod jwks;
 pub mod models;
 pub mod oauth;
+pub mod pending;
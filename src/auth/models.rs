@@ -1,8 +1,5 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::sync::Mutex;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct NestToken {
@@ -10,28 +7,195 @@ pub struct NestToken {
     pub expires_in: u64,
     pub token_type: String,
     pub refresh_token: String,
-    #[serde(skip, default = "Utc::now")]
-    pub created_at: DateTime<Utc>,
+    /// Space-separated scopes the user granted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>,
+    /// When the access token expires. Missing from token endpoint responses
+    /// and from files written before it was stored, in which case the token
+    /// is treated as already expired.
+    #[serde(default = "unknown_expiry")]
+    pub expires_at: DateTime<Utc>,
+}
+
+fn unknown_expiry() -> DateTime<Utc> {
+    DateTime::UNIX_EPOCH
 }
 
 impl NestToken {
+    /// Start the expiry clock for a token issued at `issued_at`
+    pub fn set_issued_at(&mut self, issued_at: DateTime<Utc>) {
+        self.expires_at = issued_at + chrono::Duration::seconds(self.expires_in as i64);
+    }
+
+    /// Apply a refresh response issued at `issued_at`. Google usually leaves
+    /// out the refresh token, scope and ID token, so those are only replaced
+    /// when present.
+    pub fn apply_refresh(&mut self, refresh: RefreshedToken, issued_at: DateTime<Utc>) {
+        self.access_token = refresh.access_token;
+        self.expires_in = refresh.expires_in;
+        if let Some(token_type) = refresh.token_type {
+            self.token_type = token_type;
+        }
+        if let Some(refresh_token) = refresh.refresh_token {
+            self.refresh_token = refresh_token;
+        }
+        if refresh.scope.is_some() {
+            self.scope = refresh.scope;
+        }
+        if refresh.id_token.is_some() {
+            self.id_token = refresh.id_token;
+        }
+        self.set_issued_at(issued_at);
+    }
+
     pub fn is_expired(&self) -> bool {
-        let expiry = self.created_at + chrono::Duration::seconds(self.expires_in as i64);
         // Consider token expired if it has less than 5 minutes left
-        expiry <= Utc::now() + chrono::Duration::minutes(5)
+        self.expires_at <= Utc::now() + chrono::Duration::minutes(5)
     }
 }
 
+/// Token endpoint response to a refresh request
+#[derive(Debug, Deserialize)]
+pub struct RefreshedToken {
+    pub access_token: String,
+    pub expires_in: u64,
+    #[serde(default)]
+    pub token_type: Option<String>,
+    #[serde(default)]
+    pub refresh_token: Option<String>,
+    #[serde(default)]
+    pub scope: Option<String>,
+    #[serde(default)]
+    pub id_token: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UserConfig {
     pub user_id: String,
     pub device_ids: Vec<String>,
-    pub token: NestToken,
-    pub project_id: String,
+    /// Google grant for the user's cameras. Missing for local accounts that
+    /// haven't connected Google yet.
+    #[serde(default)]
+    pub token: Option<NestToken>,
+    /// Device Access project the user's cameras belong to, chosen during
+    /// onboarding
+    #[serde(default, deserialize_with = "deserialize_project_id")]
+    pub project_id: Option<String>,
+    /// Full Pub/Sub subscription name, e.g. "projects/my-gcp-project/subscriptions/sdm-events"
+    #[serde(default)]
+    pub pubsub_subscription: Option<String>,
+    #[serde(default)]
+    pub notifications: NotificationSettings,
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+    /// Google rejected the refresh token, so monitoring is paused until the
+    /// user signs in again
+    #[serde(default)]
+    pub needs_reauth: bool,
+    /// Email and password sign-in, for users who registered without Google
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub account: Option<LocalAccount>,
+}
+
+impl UserConfig {
+    pub fn new(user_id: String, device_ids: Vec<String>, token: NestToken, project_id: Option<String>) -> Self {
+        Self {
+            user_id,
+            device_ids,
+            token: Some(token),
+            project_id,
+            pubsub_subscription: None,
+            notifications: NotificationSettings::default(),
+            webhooks: Vec::new(),
+            needs_reauth: false,
+            account: None,
+        }
+    }
+
+    /// A user who registered with an email and password and has yet to
+    /// connect Google
+    pub fn with_account(user_id: String, account: LocalAccount, project_id: Option<String>) -> Self {
+        Self {
+            user_id,
+            device_ids: Vec::new(),
+            token: None,
+            project_id,
+            pubsub_subscription: None,
+            notifications: NotificationSettings::default(),
+            webhooks: Vec::new(),
+            needs_reauth: false,
+            account: Some(account),
+        }
+    }
+
+    /// Created before users were keyed by Google account, under a random ID
+    /// that signing in with Google can't find
+    pub fn is_legacy(&self) -> bool {
+        self.account.is_none() && uuid::Uuid::parse_str(&self.user_id).is_ok()
+    }
+}
+
+// Users used to be given this placeholder when no project was configured
+const PLACEHOLDER_PROJECT_ID: &str = "YOUR_PROJECT_ID";
+
+pub(crate) fn deserialize_project_id<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let project_id = Option::<String>::deserialize(deserializer)?;
+    Ok(project_id.filter(|id| !id.is_empty() && id != PLACEHOLDER_PROJECT_ID))
+}
+
+/// Credentials for signing in without Google
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LocalAccount {
+    /// Lowercased email address, unique across users
+    pub email: String,
+    /// Argon2 hash in PHC string format
+    pub password_hash: String,
+    /// Set once the user follows the link in the verification email
+    #[serde(default)]
+    pub email_verified: bool,
+}
+
+/// Which notifications a user receives and where they are sent
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NotificationSettings {
+    #[serde(default)]
+    pub email_recipients: Vec<String>,
+    #[serde(default = "default_true")]
+    pub notify_on_finished: bool,
+    #[serde(default)]
+    pub notify_on_person: bool,
+}
+
+/// An outbound webhook that receives signed JSON notifications
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookConfig {
+    pub id: String,
+    pub url: String,
+    /// Shared secret used to sign each delivery
+    pub secret: String,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Default for NotificationSettings {
+    fn default() -> Self {
+        Self {
+            email_recipients: Vec::new(),
+            notify_on_finished: true,
+            notify_on_person: false,
+        }
+    }
 }
 
 // Store user configurations and their tokens
-pub type UserStore = Arc<Mutex<HashMap<String, UserConfig>>>;
+pub use crate::storage::UserStore;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AuthorizationResponse {
@@ -47,6 +211,8 @@ pub struct OAuthConfig {
     pub scope: String,
     pub auth_uri: String,
     pub token_uri: String,
+    /// Keys that sign ID tokens
+    pub jwks_uri: String,
 }
 
 impl Default for OAuthConfig {
@@ -55,9 +221,10 @@ impl Default for OAuthConfig {
             client_id: String::new(),
             client_secret: String::new(),
             redirect_uri: String::new(),
-            scope: "https://www.googleapis.com/auth/sdm.service".to_string(),
+            scope: "openid email profile https://www.googleapis.com/auth/sdm.service https://www.googleapis.com/auth/pubsub".to_string(),
             auth_uri: "https://accounts.google.com/o/oauth2/auth".to_string(),
             token_uri: "https://oauth2.googleapis.com/token".to_string(),
+            jwks_uri: crate::auth::jwks::GOOGLE_JWKS_URI.to_string(),
         }
     }
 }
@@ -1,7 +1,10 @@
-use crate::auth::models::{AuthorizationResponse, NestToken, OAuthConfig};
-use reqwest::Client;
-use serde::Serialize;
-use std::error::Error;
+use crate::auth::jwks::{JwksVerifier, JwtError, GOOGLE_ISSUERS};
+use crate::auth::models::{AuthorizationResponse, NestToken, OAuthConfig, RefreshedToken};
+use crate::sdm::SdmClient;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use thiserror::Error;
 use uuid::Uuid;
 
@@ -17,41 +20,105 @@ pub enum AuthError {
     MissingCode,
     
     #[error("OAuth error: {0}")]
-    OAuthError(String),
+    OAuth(String),
     
+    #[error("Invalid ID token: {0}")]
+    IdToken(#[from] JwtError),
+    
+    /// The refresh token or authorization code was revoked, expired or
+    /// already used; the user has to sign in again
+    #[error("Authorization is no longer valid: {0}")]
+    InvalidGrant(String),
+    
+    #[allow(dead_code)]
     #[error("Other error: {0}")]
     Other(String),
 }
 
+/// Identity claims from a Google ID token
+#[derive(Debug, Deserialize)]
+pub struct IdTokenClaims {
+    /// Stable, unique ID of the Google account
+    pub sub: String,
+    #[serde(default)]
+    pub email: Option<String>,
+}
+
+// Error body returned by the token endpoint
+#[derive(Deserialize)]
+struct TokenErrorResponse {
+    error: String,
+    #[serde(default)]
+    error_description: Option<String>,
+}
+
+// Classify an unsuccessful token endpoint response
+async fn token_error(res: reqwest::Response) -> AuthError {
+    let error_text = match res.text().await {
+        Ok(text) => text,
+        Err(e) => return e.into(),
+    };
+    
+    match serde_json::from_str::<TokenErrorResponse>(&error_text) {
+        Ok(error) if error.error == "invalid_grant" => {
+            AuthError::InvalidGrant(error.error_description.unwrap_or(error.error))
+        }
+        _ => AuthError::OAuth(error_text),
+    }
+}
+
 /// Generate a state parameter for OAuth flow and store it
 pub fn generate_oauth_state() -> String {
     Uuid::new_v4().to_string()
 }
 
+/// PKCE (RFC 7636) secret for one authorization flow. The challenge goes in
+/// the authorization URL and the verifier with the code, so an intercepted
+/// code is useless on its own.
+pub struct Pkce {
+    pub verifier: String,
+    pub challenge: String,
+}
+
+impl Pkce {
+    pub fn generate() -> Self {
+        // 32 random bytes encode to a 43 character verifier, the minimum length
+        Self::from_verifier(URL_SAFE_NO_PAD.encode(rand::thread_rng().gen::<[u8; 32]>()))
+    }
+
+    // The S256 challenge for a verifier
+    fn from_verifier(verifier: String) -> Self {
+        let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+        Self { verifier, challenge }
+    }
+}
+
 /// Generate the authorization URL
-pub fn get_authorization_url(config: &OAuthConfig, state: &str) -> String {
+pub fn get_authorization_url(config: &OAuthConfig, state: &str, code_challenge: &str) -> String {
     format!(
-        "{}?client_id={}&redirect_uri={}&response_type=code&scope={}&state={}&access_type=offline&prompt=consent",
+        "{}?client_id={}&redirect_uri={}&response_type=code&scope={}&state={}&code_challenge={}&code_challenge_method=S256&access_type=offline&prompt=consent",
         config.auth_uri,
         urlencoding::encode(&config.client_id),
         urlencoding::encode(&config.redirect_uri),
         urlencoding::encode(&config.scope),
-        urlencoding::encode(state)
+        urlencoding::encode(state),
+        urlencoding::encode(code_challenge)
     )
 }
 
 /// Exchange authorization code for tokens
 pub async fn exchange_code_for_token(
+    sdm: &SdmClient,
     config: &OAuthConfig,
     code: &str,
+    code_verifier: &str,
 ) -> Result<NestToken, AuthError> {
-    let client = Client::new();
-    
     #[derive(Serialize)]
     struct TokenRequest<'a> {
         client_id: &'a str,
         client_secret: &'a str,
         code: &'a str,
+        code_verifier: &'a str,
         grant_type: &'a str,
         redirect_uri: &'a str,
     }
@@ -60,34 +127,50 @@ pub async fn exchange_code_for_token(
         client_id: &config.client_id,
         client_secret: &config.client_secret,
         code,
+        code_verifier,
         grant_type: "authorization_code",
         redirect_uri: &config.redirect_uri,
     };
     
-    let res = client
+    // Codes can only be redeemed once, so a retry could only be rejected
+    let res = sdm
+        .http()
         .post(&config.token_uri)
         .form(&token_request)
         .send()
         .await?;
     
     if !res.status().is_success() {
-        let error_text = res.text().await?;
-        return Err(AuthError::OAuthError(error_text));
+        return Err(token_error(res).await);
     }
     
     let mut token = res.json::<NestToken>().await?;
-    token.created_at = chrono::Utc::now();
+    token.set_issued_at(chrono::Utc::now());
     
     Ok(token)
 }
 
-/// Refresh an expired token
-pub async fn refresh_token(
+/// Check the ID token returned with `token` was issued by Google for this
+/// client and return who it identifies
+pub async fn verify_id_token(
+    verifier: &JwksVerifier,
     config: &OAuthConfig,
-    refresh_token: &str,
-) -> Result<NestToken, Box<dyn Error + Send + Sync>> {
-    let client = Client::new();
+    token: &NestToken,
+) -> Result<IdTokenClaims, AuthError> {
+    let id_token = token
+        .id_token
+        .as_deref()
+        .ok_or_else(|| AuthError::OAuth("Token response has no ID token".to_string()))?;
     
+    Ok(verifier.verify(id_token, &config.client_id, &GOOGLE_ISSUERS).await?)
+}
+
+/// Refresh an expired token, returning `token` updated with the response
+pub async fn refresh_token(
+    sdm: &SdmClient,
+    config: &OAuthConfig,
+    token: &NestToken,
+) -> Result<NestToken, AuthError> {
     #[derive(Serialize)]
     struct RefreshRequest<'a> {
         client_id: &'a str,
@@ -99,18 +182,21 @@ pub async fn refresh_token(
     let refresh_request = RefreshRequest {
         client_id: &config.client_id,
         client_secret: &config.client_secret,
-        refresh_token,
+        refresh_token: &token.refresh_token,
         grant_type: "refresh_token",
     };
     
-    let res = client
-        .post(&config.token_uri)
-        .form(&refresh_request)
-        .send()
+    let res = sdm
+        .send(sdm.http().post(&config.token_uri).form(&refresh_request))
         .await?;
     
-    let mut token = res.json::<NestToken>().await?;
-    token.created_at = chrono::Utc::now();
+    if !res.status().is_success() {
+        return Err(token_error(res).await);
+    }
+    
+    let refreshed = res.json::<RefreshedToken>().await?;
+    let mut token = token.clone();
+    token.apply_refresh(refreshed, chrono::Utc::now());
     
     Ok(token)
 }
@@ -132,3 +218,163 @@ pub fn validate_oauth_response(
     
     Ok(response.code.clone())
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    #[test]
+    fn pkce_challenges_are_s256_of_the_verifier() {
+        // Example from RFC 7636 appendix B
+        let pkce = Pkce::from_verifier("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk".to_string());
+        assert_eq!(pkce.challenge, "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM");
+
+        let pkce = Pkce::generate();
+        assert_eq!(pkce.verifier.len(), 43);
+        assert!(pkce
+            .verifier
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+        assert_ne!(Pkce::generate().verifier, pkce.verifier);
+    }
+
+    #[test]
+    fn authorization_urls_carry_the_challenge() {
+        let config = OAuthConfig {
+            client_id: "client id".to_string(),
+            redirect_uri: "http://localhost:8080/auth/callback".to_string(),
+            ..OAuthConfig::default()
+        };
+
+        let url = get_authorization_url(&config, "state-1", "challenge-1");
+        assert!(url.starts_with("https://accounts.google.com/o/oauth2/auth?"));
+        assert!(url.contains("client_id=client%20id"));
+        assert!(url.contains("redirect_uri=http%3A%2F%2Flocalhost%3A8080%2Fauth%2Fcallback"));
+        assert!(url.contains("&state=state-1&code_challenge=challenge-1&code_challenge_method=S256"));
+    }
+
+    #[test]
+    fn callbacks_need_the_expected_state_and_a_code() {
+        let response = |code: &str, state: &str| AuthorizationResponse {
+            code: code.to_string(),
+            state: state.to_string(),
+        };
+
+        assert_eq!(validate_oauth_response(&response("code-1", "state-1"), "state-1").unwrap(), "code-1");
+        assert!(matches!(
+            validate_oauth_response(&response("code-1", "state-2"), "state-1"),
+            Err(AuthError::InvalidState)
+        ));
+        assert!(matches!(
+            validate_oauth_response(&response("", "state-1"), "state-1"),
+            Err(AuthError::MissingCode)
+        ));
+    }
+
+    #[cfg(feature = "web-api")]
+    fn stored_token() -> NestToken {
+        NestToken {
+            access_token: "old-access".to_string(),
+            expires_in: 3600,
+            token_type: "Bearer".to_string(),
+            refresh_token: "stored-refresh".to_string(),
+            scope: Some("openid https://www.googleapis.com/auth/sdm.service".to_string()),
+            id_token: Some("stored-id-token".to_string()),
+            expires_at: chrono::Utc::now() - chrono::Duration::minutes(1),
+        }
+    }
+
+    // Start a token endpoint that refreshes "stored-refresh" and rejects
+    // every other refresh token as revoked
+    #[cfg(feature = "web-api")]
+    pub(crate) async fn token_endpoint() -> OAuthConfig {
+        use axum::{http::StatusCode, routing::post, Form, Json, Router};
+        use std::collections::HashMap;
+        use std::net::SocketAddr;
+
+        async fn token(
+            Form(form): Form<HashMap<String, String>>,
+        ) -> (StatusCode, Json<serde_json::Value>) {
+            if form.get("grant_type").map(String::as_str) != Some("refresh_token") {
+                return (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "error": "unsupported_grant_type" })));
+            }
+            match form.get("refresh_token").map(String::as_str) {
+                // Google leaves out everything that didn't change
+                Some("stored-refresh") => (
+                    StatusCode::OK,
+                    Json(serde_json::json!({ "access_token": "new-access", "expires_in": 3599 })),
+                ),
+                _ => (
+                    StatusCode::BAD_REQUEST,
+                    Json(serde_json::json!({
+                        "error": "invalid_grant",
+                        "error_description": "Token has been expired or revoked.",
+                    })),
+                ),
+            }
+        }
+
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .serve(Router::new().route("/token", post(token)).into_make_service());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        OAuthConfig {
+            token_uri: format!("http://{}/token", addr),
+            ..OAuthConfig::default()
+        }
+    }
+
+    #[cfg(feature = "web-api")]
+    #[tokio::test]
+    async fn refreshing_keeps_what_the_response_leaves_out() {
+        let config = token_endpoint().await;
+        let sdm = SdmClient::new(crate::sdm::SdmClientConfig::default());
+        let stored = stored_token();
+
+        let refreshed = refresh_token(&sdm, &config, &stored).await.unwrap();
+        assert_eq!(refreshed.access_token, "new-access");
+        assert_eq!(refreshed.expires_in, 3599);
+        assert_eq!(refreshed.token_type, stored.token_type);
+        assert_eq!(refreshed.refresh_token, stored.refresh_token);
+        assert_eq!(refreshed.scope, stored.scope);
+        assert_eq!(refreshed.id_token, stored.id_token);
+        assert!(refreshed.expires_at > chrono::Utc::now() + chrono::Duration::minutes(55));
+        assert!(!refreshed.is_expired());
+    }
+
+    #[cfg(feature = "web-api")]
+    #[tokio::test]
+    async fn revoked_refresh_tokens_are_invalid_grants() {
+        let config = token_endpoint().await;
+        let sdm = SdmClient::new(crate::sdm::SdmClientConfig::default());
+        let revoked = NestToken {
+            refresh_token: "revoked-refresh".to_string(),
+            ..stored_token()
+        };
+
+        match refresh_token(&sdm, &config, &revoked).await {
+            Err(AuthError::InvalidGrant(description)) => {
+                assert_eq!(description, "Token has been expired or revoked.")
+            }
+            other => panic!("expected an invalid grant, got {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "web-api")]
+    #[tokio::test]
+    async fn authorization_codes_are_only_sent_once() {
+        use axum::response::IntoResponse;
+
+        let unavailable = axum::http::StatusCode::SERVICE_UNAVAILABLE.into_response();
+        let (base_url, requests) = crate::sdm::tests::scripted_server(vec![unavailable]).await;
+        let config = OAuthConfig {
+            token_uri: format!("{}/token", base_url),
+            ..OAuthConfig::default()
+        };
+        let sdm = SdmClient::new(crate::sdm::SdmClientConfig::default());
+
+        assert!(exchange_code_for_token(&sdm, &config, "code-1", "verifier").await.is_err());
+        assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+// Abandoned sign-ins expire after this long
+pub const PENDING_LOGIN_TTL: Duration = Duration::from_secs(10 * 60);
+
+// Bound on sign-ins in progress at once; past this the oldest are dropped
+const MAX_PENDING_LOGINS: usize = 10_000;
+
+/// A sign-in waiting for Google to redirect back to the callback
+pub struct PendingLogin {
+    /// PKCE secret sent with the authorization code
+    pub code_verifier: String,
+    /// User who was signed in when the flow started, if any
+    pub user_id: Option<String>,
+    started: Instant,
+}
+
+impl PendingLogin {
+    pub fn new(code_verifier: String, user_id: Option<String>) -> Self {
+        Self {
+            code_verifier,
+            user_id,
+            started: Instant::now(),
+        }
+    }
+
+    fn is_expired(&self, ttl: Duration) -> bool {
+        self.started.elapsed() >= ttl
+    }
+}
+
+/// Sign-ins in progress, keyed by their OAuth state parameter. Expired
+/// entries are swept whenever a sign-in starts, so abandoned flows don't
+/// accumulate.
+#[derive(Clone)]
+pub struct PendingLogins {
+    logins: Arc<Mutex<HashMap<String, PendingLogin>>>,
+    ttl: Duration,
+}
+
+impl PendingLogins {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            logins: Arc::new(Mutex::new(HashMap::new())),
+            ttl,
+        }
+    }
+
+    pub async fn insert(&self, state: String, login: PendingLogin) {
+        let mut logins = self.logins.lock().await;
+
+        let before = logins.len();
+        logins.retain(|_, login| !login.is_expired(self.ttl));
+        if logins.len() < before {
+            log::debug!("Swept {} expired sign-ins", before - logins.len());
+        }
+
+        if logins.len() >= MAX_PENDING_LOGINS {
+            let oldest = logins
+                .iter()
+                .min_by_key(|(_, login)| login.started)
+                .map(|(state, _)| state.clone());
+            if let Some(oldest) = oldest {
+                log::warn!("Too many sign-ins in progress, dropping the oldest");
+                logins.remove(&oldest);
+            }
+        }
+
+        logins.insert(state, login);
+    }
+
+    /// Remove and return the sign-in for `state`, unless it has expired.
+    /// Each state can only be used once.
+    pub async fn take(&self, state: &str) -> Option<PendingLogin> {
+        let login = self.logins.lock().await.remove(state)?;
+        if login.is_expired(self.ttl) {
+            return None;
+        }
+        Some(login)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn sign_ins_can_be_completed_once() {
+        let logins = PendingLogins::new(PENDING_LOGIN_TTL);
+        logins
+            .insert("state-1".to_string(), PendingLogin::new("verifier-1".to_string(), Some("alice".to_string())))
+            .await;
+
+        let login = logins.take("state-1").await.unwrap();
+        assert_eq!(login.code_verifier, "verifier-1");
+        assert_eq!(login.user_id.as_deref(), Some("alice"));
+        assert!(logins.take("state-1").await.is_none());
+        assert!(logins.take("state-2").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn expired_sign_ins_are_rejected_and_swept() {
+        let logins = PendingLogins::new(Duration::ZERO);
+        logins
+            .insert("state-1".to_string(), PendingLogin::new("verifier-1".to_string(), None))
+            .await;
+        assert!(logins.take("state-1").await.is_none());
+
+        logins
+            .insert("state-2".to_string(), PendingLogin::new("verifier-2".to_string(), None))
+            .await;
+        logins
+            .insert("state-3".to_string(), PendingLogin::new("verifier-3".to_string(), None))
+            .await;
+        assert_eq!(logins.logins.lock().await.len(), 1);
+    }
+}
@@ -1,8 +1,47 @@
-use reqwest::{Client, header};
 use serde::{Deserialize, Serialize};
-use std::error::Error;
+use thiserror::Error;
 
 use crate::auth::models::NestToken;
+use crate::sdm::{SdmClient, SdmError};
+
+/// Why a Device Access project ID was refused. The messages are shown to the
+/// user.
+#[derive(Error, Debug)]
+pub enum ProjectError {
+    #[error("Enter the project ID shown in the Device Access Console")]
+    InvalidId,
+
+    #[error("Enter the subscription's full name, like projects/my-project/subscriptions/sdm-events")]
+    InvalidSubscription,
+
+    #[error("Connect your Google account before choosing a project")]
+    NotConnected,
+
+    #[error("Google doesn't know this project, or you didn't give it access to your home when connecting Google")]
+    NoAccess,
+
+    #[error("Google no longer accepts your sign-in. Reconnect your Google account and try again")]
+    Unauthorized,
+
+    #[error("Choose your Device Access project before choosing cameras")]
+    NoProject,
+
+    #[error("Device {0} isn't in your Device Access project")]
+    UnknownDevice(String),
+
+    #[error("Couldn't check the project with Google: {0}")]
+    Sdm(SdmError),
+}
+
+impl From<SdmError> for ProjectError {
+    fn from(e: SdmError) -> Self {
+        match e {
+            SdmError::NotFound(_) | SdmError::PermissionDenied(_) => ProjectError::NoAccess,
+            SdmError::Unauthorized(_) => ProjectError::Unauthorized,
+            e => ProjectError::Sdm(e),
+        }
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Device {
@@ -16,11 +55,11 @@ pub struct Device {
 
 impl Device {
     // Extract the device ID from the full name path
-    pub fn from_nest_device(device: NestDevice) -> Self {
+    fn from_nest_device(device: NestDevice) -> Self {
         // Extract device ID from name (format: "enterprises/project-id/devices/device-id")
         let device_id = device.name
             .split('/')
-            .last()
+            .next_back()
             .unwrap_or(&device.name)
             .to_string();
         
@@ -49,6 +88,20 @@ impl Device {
     }
 }
 
+impl Device {
+    /// Stand-in for a registered device whose details can't be fetched
+    pub fn placeholder(device_id: &str) -> Self {
+        Self {
+            name: String::new(),
+            device_id: device_id.to_string(),
+            type_name: String::new(),
+            traits: Vec::new(),
+            room_name: None,
+            display_name: device_id.to_string(),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 struct NestDevice {
     name: String,
@@ -74,31 +127,15 @@ struct DevicesResponse {
 
 /// Discover cameras and other devices for a user's project
 pub async fn discover_devices(
+    sdm: &SdmClient,
     project_id: &str,
     token: &NestToken,
-) -> Result<Vec<Device>, Box<dyn Error + Send + Sync>> {
-    let client = Client::new();
-    let mut headers = header::HeaderMap::new();
-    headers.insert(
-        header::AUTHORIZATION,
-        header::HeaderValue::from_str(&format!("Bearer {}", token.access_token))?,
-    );
-
-    let url = format!(
-        "https://smartdevicemanagement.googleapis.com/v1/enterprises/{}/devices",
-        project_id
-    );
-
-    let response = client
-        .get(&url)
-        .headers(headers)
-        .send()
-        .await?;
+) -> Result<Vec<Device>, SdmError> {
+    let url = sdm.url(&format!("enterprises/{}/devices", project_id));
 
-    if !response.status().is_success() {
-        let error_text = response.text().await?;
-        return Err(format!("API error: {}", error_text).into());
-    }
+    let response = sdm
+        .execute(sdm.http().get(&url).bearer_auth(&token.access_token))
+        .await?;
 
     let devices_response: DevicesResponse = response.json().await?;
     
@@ -111,6 +148,57 @@ pub async fn discover_devices(
     Ok(devices)
 }
 
+/// Trim a Device Access project ID, rejecting anything that isn't one. The
+/// ID becomes part of API URLs, so only letters, digits and dashes pass.
+pub fn parse_project_id(project_id: &str) -> Result<String, ProjectError> {
+    let project_id = project_id.trim();
+    if project_id.is_empty()
+        || project_id.len() > 64
+        || !project_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    {
+        return Err(ProjectError::InvalidId);
+    }
+    Ok(project_id.to_string())
+}
+
+/// Trim a Pub/Sub subscription name, rejecting anything that isn't a full
+/// `projects/<gcp-project>/subscriptions/<name>` name. The name becomes part
+/// of API URLs, so only the characters Google allows in names pass. An empty
+/// name means the deployment's default subscription.
+pub fn parse_subscription(subscription: &str) -> Result<Option<String>, ProjectError> {
+    let subscription = subscription.trim();
+    if subscription.is_empty() {
+        return Ok(None);
+    }
+
+    let valid = match subscription.split('/').collect::<Vec<_>>()[..] {
+        ["projects", project, "subscriptions", name] => {
+            project.len() <= 64
+                && project.starts_with(|c: char| c.is_ascii_alphabetic())
+                && project.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | ':'))
+                && (3..=255).contains(&name.len())
+                && name.starts_with(|c: char| c.is_ascii_alphabetic())
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '~' | '+'))
+        }
+        _ => false,
+    };
+    if !valid {
+        return Err(ProjectError::InvalidSubscription);
+    }
+    Ok(Some(subscription.to_string()))
+}
+
+/// Check the user can read `project_id` by listing its devices, which are
+/// returned
+pub async fn validate_project(
+    sdm: &SdmClient,
+    project_id: &str,
+    token: &NestToken,
+) -> Result<Vec<Device>, ProjectError> {
+    let project_id = parse_project_id(project_id)?;
+    Ok(discover_devices(sdm, &project_id, token).await?)
+}
+
 /// Filter devices to only include cameras
 pub fn filter_cameras(devices: &[Device]) -> Vec<Device> {
     devices
@@ -121,4 +209,48 @@ pub fn filter_cameras(devices: &[Device]) -> Vec<Device> {
         })
         .cloned()
         .collect()
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn project_ids_are_trimmed_and_must_be_safe_in_urls() {
+        assert_eq!(parse_project_id("  0a1b2c3d-4e5f \n").unwrap(), "0a1b2c3d-4e5f");
+
+        let too_long = "a".repeat(65);
+        for invalid in [
+            "",
+            "   ",
+            "my project",
+            "project/../devices",
+            "project?x=1",
+            "proj\u{e9}ct",
+            too_long.as_str(),
+        ] {
+            assert!(matches!(parse_project_id(invalid), Err(ProjectError::InvalidId)), "{}", invalid);
+        }
+        assert!(parse_project_id(&"a".repeat(64)).is_ok());
+    }
+
+    #[test]
+    fn subscriptions_must_be_full_names() {
+        assert_eq!(
+            parse_subscription(" projects/my-project/subscriptions/sdm-events ").unwrap(),
+            Some("projects/my-project/subscriptions/sdm-events".to_string())
+        );
+        assert_eq!(parse_subscription("  ").unwrap(), None);
+
+        for invalid in [
+            "sdm-events",
+            "projects/my-project/topics/sdm-events",
+            "projects//subscriptions/sdm-events",
+            "projects/my-project/subscriptions/1-events",
+            "projects/my-project/subscriptions/sdm-events/extra",
+            "projects/my-project/subscriptions/sdm?events",
+            "projects/../subscriptions/sdm-events",
+        ] {
+            assert!(parse_subscription(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
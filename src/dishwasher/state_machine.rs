@@ -0,0 +1,226 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Lifecycle of a dishwasher as inferred from camera events
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DishwasherState {
+    Idle,
+    Loaded,
+    Running,
+    Finished,
+    Unloaded,
+}
+
+impl DishwasherState {
+    /// Machine-readable name, the same as the serialized form
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DishwasherState::Idle => "idle",
+            DishwasherState::Loaded => "loaded",
+            DishwasherState::Running => "running",
+            DishwasherState::Finished => "finished",
+            DishwasherState::Unloaded => "unloaded",
+        }
+    }
+}
+
+impl fmt::Display for DishwasherState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let label = match self {
+            DishwasherState::Idle => "Idle",
+            DishwasherState::Loaded => "Loaded",
+            DishwasherState::Running => "Running",
+            DishwasherState::Finished => "Finished",
+            DishwasherState::Unloaded => "Unloaded",
+        };
+        write!(f, "{}", label)
+    }
+}
+
+/// Move to `to` when an event of `event_type` arrives while in `from`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EventRule {
+    pub from: DishwasherState,
+    pub event_type: String,
+    pub to: DishwasherState,
+}
+
+/// Which timestamp a timeout is measured from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeoutBase {
+    /// Time since the state was entered
+    #[default]
+    Entered,
+    /// Time since the last camera event for the device
+    LastEvent,
+}
+
+/// Move to `to` once `after_secs` have elapsed in `state`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimeoutRule {
+    pub state: DishwasherState,
+    pub after_secs: u64,
+    pub to: DishwasherState,
+    #[serde(default)]
+    pub measured_from: TimeoutBase,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StateMachineConfig {
+    pub event_rules: Vec<EventRule>,
+    pub timeouts: Vec<TimeoutRule>,
+}
+
+impl Default for StateMachineConfig {
+    fn default() -> Self {
+        Self {
+            event_rules: vec![
+                // Someone at the dishwasher while it is empty is loading it
+                EventRule {
+                    from: DishwasherState::Idle,
+                    event_type: "person".to_string(),
+                    to: DishwasherState::Loaded,
+                },
+                // Someone at the dishwasher after a cycle is emptying it
+                EventRule {
+                    from: DishwasherState::Finished,
+                    event_type: "person".to_string(),
+                    to: DishwasherState::Unloaded,
+                },
+            ],
+            timeouts: vec![
+                // Once loading activity stops the cycle is assumed to start
+                TimeoutRule {
+                    state: DishwasherState::Loaded,
+                    after_secs: 10 * 60,
+                    to: DishwasherState::Running,
+                    measured_from: TimeoutBase::LastEvent,
+                },
+                TimeoutRule {
+                    state: DishwasherState::Running,
+                    after_secs: 90 * 60,
+                    to: DishwasherState::Finished,
+                    measured_from: TimeoutBase::Entered,
+                },
+                TimeoutRule {
+                    state: DishwasherState::Unloaded,
+                    after_secs: 5 * 60,
+                    to: DishwasherState::Idle,
+                    measured_from: TimeoutBase::LastEvent,
+                },
+            ],
+        }
+    }
+}
+
+impl StateMachineConfig {
+    /// Load rules from a JSON file
+    pub fn from_file(file_path: &str) -> io::Result<Self> {
+        let contents = fs::read_to_string(Path::new(file_path))?;
+        let config = serde_json::from_str(&contents)?;
+        Ok(config)
+    }
+
+    /// Load rules from `DISHWASHER_RULES_FILE` if set, falling back to the defaults
+    pub fn from_env() -> Self {
+        match std::env::var("DISHWASHER_RULES_FILE") {
+            Ok(file_path) => Self::from_file(&file_path).unwrap_or_else(|e| {
+                log::error!(
+                    "Failed to load dishwasher rules from {}: {}, using defaults",
+                    file_path,
+                    e
+                );
+                Self::default()
+            }),
+            Err(_) => Self::default(),
+        }
+    }
+
+    /// Find the state an event moves a device into, if any rule matches
+    pub fn next_state_for_event(
+        &self,
+        current: DishwasherState,
+        event_type: &str,
+    ) -> Option<DishwasherState> {
+        self.event_rules
+            .iter()
+            .find(|rule| rule.from == current && rule.event_type == event_type)
+            .map(|rule| rule.to)
+    }
+
+    /// Find the timeout rule that applies to a state, if any
+    pub fn timeout_for(&self, state: DishwasherState) -> Option<&TimeoutRule> {
+        self.timeouts.iter().find(|rule| rule.state == state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_names_match_their_serialized_form() {
+        for state in [
+            DishwasherState::Idle,
+            DishwasherState::Loaded,
+            DishwasherState::Running,
+            DishwasherState::Finished,
+            DishwasherState::Unloaded,
+        ] {
+            assert_eq!(serde_json::to_value(state).unwrap(), state.as_str());
+        }
+    }
+
+    #[test]
+    fn default_rules_follow_a_wash_cycle() {
+        let config = StateMachineConfig::default();
+
+        assert_eq!(
+            config.next_state_for_event(DishwasherState::Idle, "person"),
+            Some(DishwasherState::Loaded)
+        );
+        assert_eq!(
+            config.next_state_for_event(DishwasherState::Finished, "person"),
+            Some(DishwasherState::Unloaded)
+        );
+        assert_eq!(config.next_state_for_event(DishwasherState::Idle, "motion"), None);
+        assert_eq!(config.next_state_for_event(DishwasherState::Running, "person"), None);
+
+        let running = config.timeout_for(DishwasherState::Running).unwrap();
+        assert_eq!(running.to, DishwasherState::Finished);
+        assert_eq!(running.measured_from, TimeoutBase::Entered);
+        assert!(config.timeout_for(DishwasherState::Idle).is_none());
+    }
+
+    #[test]
+    fn timeouts_are_measured_from_entry_unless_configured() {
+        let config: StateMachineConfig = serde_json::from_str(
+            r#"{
+                "event_rules": [{"from": "idle", "event_type": "motion", "to": "running"}],
+                "timeouts": [
+                    {"state": "running", "after_secs": 60, "to": "finished"},
+                    {"state": "finished", "after_secs": 30, "to": "idle", "measured_from": "last_event"}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.next_state_for_event(DishwasherState::Idle, "motion"),
+            Some(DishwasherState::Running)
+        );
+        assert_eq!(
+            config.timeout_for(DishwasherState::Running).unwrap().measured_from,
+            TimeoutBase::Entered
+        );
+        assert_eq!(
+            config.timeout_for(DishwasherState::Finished).unwrap().measured_from,
+            TimeoutBase::LastEvent
+        );
+    }
+}
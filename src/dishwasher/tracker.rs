@@ -0,0 +1,288 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
+use tokio::sync::{broadcast, Mutex};
+
+use crate::dishwasher::state_machine::{DishwasherState, StateMachineConfig, TimeoutBase};
+use crate::events::{
+    handlers::{EventHandler, HandlerFlow},
+    CameraEvent,
+};
+
+/// Current state of a single monitored dishwasher
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceStatus {
+    pub state: DishwasherState,
+    pub entered_at: DateTime<Utc>,
+    pub last_event_at: DateTime<Utc>,
+}
+
+impl DeviceStatus {
+    fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            state: DishwasherState::Idle,
+            entered_at: now,
+            last_event_at: now,
+        }
+    }
+}
+
+/// A state change for one device, published to subscribers
+#[derive(Debug, Clone, Serialize)]
+pub struct Transition {
+    pub user_id: String,
+    pub device_id: String,
+    pub from: DishwasherState,
+    pub to: DishwasherState,
+    pub at: DateTime<Utc>,
+    pub cause: String,
+}
+
+// (user_id, device_id)
+type DeviceKey = (String, String);
+
+/// Tracks the dishwasher state of every monitored device
+#[derive(Clone)]
+pub struct DishwasherTracker {
+    config: Arc<StateMachineConfig>,
+    devices: Arc<Mutex<HashMap<DeviceKey, DeviceStatus>>>,
+    transitions: broadcast::Sender<Transition>,
+}
+
+impl DishwasherTracker {
+    pub fn new(config: StateMachineConfig) -> Self {
+        let (transitions, _) = broadcast::channel(64);
+        Self {
+            config: Arc::new(config),
+            devices: Arc::new(Mutex::new(HashMap::new())),
+            transitions,
+        }
+    }
+
+    /// Receive every transition made from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<Transition> {
+        self.transitions.subscribe()
+    }
+
+    /// Current status of a device, if it has seen any events
+    pub async fn status(&self, user_id: &str, device_id: &str) -> Option<DeviceStatus> {
+        let devices = self.devices.lock().await;
+        devices
+            .get(&(user_id.to_string(), device_id.to_string()))
+            .cloned()
+    }
+
+    /// Forget a user's devices other than `device_ids`, once they stop
+    /// being monitored
+    pub async fn retain_devices(&self, user_id: &str, device_ids: &[String]) {
+        let mut devices = self.devices.lock().await;
+        devices.retain(|(owner, device_id), _| owner != user_id || device_ids.contains(device_id));
+    }
+
+    /// Feed a camera event into the device's state machine
+    pub async fn handle_event(&self, user_id: &str, event: &CameraEvent) -> Option<Transition> {
+        let at = DateTime::parse_from_rfc3339(&event.timestamp)
+            .map(|t| t.with_timezone(&Utc))
+            .unwrap_or_else(|_| Utc::now());
+
+        let transition = {
+            let mut devices = self.devices.lock().await;
+            let status = devices
+                .entry((user_id.to_string(), event.device_id.clone()))
+                .or_insert_with(|| DeviceStatus::new(at));
+            status.last_event_at = at;
+
+            let next = self
+                .config
+                .next_state_for_event(status.state, &event.event_type)?;
+            let from = status.state;
+            status.state = next;
+            status.entered_at = at;
+
+            Transition {
+                user_id: user_id.to_string(),
+                device_id: event.device_id.clone(),
+                from,
+                to: next,
+                at,
+                cause: format!("{} event {}", event.event_type, event.event_id),
+            }
+        };
+
+        self.publish(&transition);
+        Some(transition)
+    }
+
+    /// Apply timeout rules to every tracked device
+    pub async fn check_timeouts(&self, now: DateTime<Utc>) -> Vec<Transition> {
+        let mut transitions = Vec::new();
+        {
+            let mut devices = self.devices.lock().await;
+            for ((user_id, device_id), status) in devices.iter_mut() {
+                let rule = match self.config.timeout_for(status.state) {
+                    Some(rule) => rule,
+                    None => continue,
+                };
+
+                let since = match rule.measured_from {
+                    TimeoutBase::Entered => status.entered_at,
+                    TimeoutBase::LastEvent => status.last_event_at,
+                };
+                if now - since < chrono::Duration::seconds(rule.after_secs as i64) {
+                    continue;
+                }
+
+                transitions.push(Transition {
+                    user_id: user_id.clone(),
+                    device_id: device_id.clone(),
+                    from: status.state,
+                    to: rule.to,
+                    at: now,
+                    cause: format!("timeout after {}s", rule.after_secs),
+                });
+                status.state = rule.to;
+                status.entered_at = now;
+            }
+        }
+
+        for transition in &transitions {
+            self.publish(transition);
+        }
+        transitions
+    }
+
+    fn publish(&self, transition: &Transition) {
+        log::info!(
+            "Dishwasher {} for user {}: {} -> {} ({})",
+            transition.device_id,
+            transition.user_id,
+            transition.from,
+            transition.to,
+            transition.cause
+        );
+        // Sending only fails when nobody is subscribed
+        let _ = self.transitions.send(transition.clone());
+    }
+}
+
+#[async_trait]
+impl EventHandler for DishwasherTracker {
+    fn name(&self) -> &str {
+        "dishwasher"
+    }
+
+    async fn handle(
+        &self,
+        user_id: &str,
+        event: &CameraEvent,
+    ) -> Result<HandlerFlow, Box<dyn Error + Send + Sync>> {
+        self.handle_event(user_id, event).await;
+        Ok(HandlerFlow::Continue)
+    }
+}
+
+/// Periodically apply timeout rules
+pub async fn start_timeout_checks(tracker: DishwasherTracker, interval: std::time::Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            tracker.check_timeouts(Utc::now()).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn event(event_type: &str, at: DateTime<Utc>) -> CameraEvent {
+        CameraEvent {
+            event_id: format!("{}-{}", event_type, at.timestamp()),
+            event_type: event_type.to_string(),
+            timestamp: at.to_rfc3339(),
+            device_id: "camera-1".to_string(),
+            project_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn events_and_timeouts_drive_a_full_cycle() {
+        let tracker = DishwasherTracker::new(StateMachineConfig::default());
+        let mut transitions = tracker.subscribe();
+        let start = Utc::now();
+
+        let loaded = tracker.handle_event("user", &event("person", start)).await.unwrap();
+        assert_eq!((loaded.from, loaded.to), (DishwasherState::Idle, DishwasherState::Loaded));
+        assert_eq!(transitions.recv().await.unwrap().to, DishwasherState::Loaded);
+
+        // Events without a matching rule don't change the state
+        assert!(tracker.handle_event("user", &event("motion", start)).await.is_none());
+
+        assert!(tracker.check_timeouts(start + Duration::minutes(9)).await.is_empty());
+        let running = tracker.check_timeouts(start + Duration::minutes(10)).await;
+        assert_eq!(running.len(), 1);
+        assert_eq!(running[0].to, DishwasherState::Running);
+
+        // Running is measured from when the cycle started
+        let started = start + Duration::minutes(10);
+        assert!(tracker.check_timeouts(started + Duration::minutes(89)).await.is_empty());
+        let finished = tracker.check_timeouts(started + Duration::minutes(90)).await;
+        assert_eq!(finished[0].to, DishwasherState::Finished);
+
+        let emptied = start + Duration::minutes(120);
+        let unloaded = tracker.handle_event("user", &event("person", emptied)).await.unwrap();
+        assert_eq!(unloaded.to, DishwasherState::Unloaded);
+        let idle = tracker.check_timeouts(emptied + Duration::minutes(5)).await;
+        assert_eq!(idle[0].to, DishwasherState::Idle);
+
+        let status = tracker.status("user", "camera-1").await.unwrap();
+        assert_eq!(status.state, DishwasherState::Idle);
+    }
+
+    #[tokio::test]
+    async fn activity_postpones_last_event_timeouts() {
+        let tracker = DishwasherTracker::new(StateMachineConfig::default());
+        let start = Utc::now();
+
+        tracker.handle_event("user", &event("person", start)).await;
+        tracker
+            .handle_event("user", &event("motion", start + Duration::minutes(8)))
+            .await;
+
+        assert!(tracker.check_timeouts(start + Duration::minutes(12)).await.is_empty());
+        let running = tracker.check_timeouts(start + Duration::minutes(18)).await;
+        assert_eq!(running[0].to, DishwasherState::Running);
+    }
+
+    #[tokio::test]
+    async fn devices_are_tracked_per_user() {
+        let tracker = DishwasherTracker::new(StateMachineConfig::default());
+        let start = Utc::now();
+
+        tracker.handle_event("alice", &event("person", start)).await;
+
+        assert_eq!(
+            tracker.status("alice", "camera-1").await.unwrap().state,
+            DishwasherState::Loaded
+        );
+        assert!(tracker.status("bob", "camera-1").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn unmonitored_devices_are_forgotten() {
+        let tracker = DishwasherTracker::new(StateMachineConfig::default());
+        let start = Utc::now();
+        tracker.handle_event("alice", &event("person", start)).await;
+        tracker.handle_event("bob", &event("person", start)).await;
+
+        tracker.retain_devices("alice", &["camera-2".to_string()]).await;
+
+        assert!(tracker.status("alice", "camera-1").await.is_none());
+        assert!(tracker.status("bob", "camera-1").await.is_some());
+    }
+}
@@ -0,0 +1,218 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::events::CameraEvent;
+use crate::storage::UserRepository;
+
+/// Record of an event that has already been handled
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProcessedEvent {
+    pub event_id: String,
+    pub device_id: String,
+    pub timestamp: String,
+}
+
+// Most recent events for one user, oldest first
+#[derive(Default)]
+struct RecentEvents {
+    order: VecDeque<ProcessedEvent>,
+    ids: HashSet<String>,
+    // Held while a claim is saved, so the user's saves never overlap
+    saving: Arc<Mutex<()>>,
+}
+
+impl RecentEvents {
+    // Add an event, returning the oldest events evicted to make room for it,
+    // or None if it is already present
+    fn insert(&mut self, event: ProcessedEvent, capacity: usize) -> Option<Vec<ProcessedEvent>> {
+        if !self.ids.insert(event.event_id.clone()) {
+            return None;
+        }
+
+        self.order.push_back(event);
+        let mut evicted = Vec::new();
+        while self.order.len() > capacity {
+            if let Some(event) = self.order.pop_front() {
+                self.ids.remove(&event.event_id);
+                evicted.push(event);
+            }
+        }
+        Some(evicted)
+    }
+}
+
+/// Bounded per-user cache of processed event IDs, persisted so events
+/// are not handled again after a restart
+#[derive(Clone)]
+pub struct EventDeduplicator {
+    capacity: usize,
+    repository: Arc<dyn UserRepository>,
+    users: Arc<Mutex<HashMap<String, RecentEvents>>>,
+}
+
+impl EventDeduplicator {
+    /// Load previously processed events, keeping at most `capacity` per user
+    pub async fn load(repository: Arc<dyn UserRepository>, capacity: usize) -> Self {
+        let stored = repository
+            .load_processed_events()
+            .await
+            .unwrap_or_else(|e| {
+                log::error!("Failed to load processed events: {}", e);
+                HashMap::new()
+            });
+
+        let mut users = HashMap::new();
+        for (user_id, events) in stored {
+            let mut recent = RecentEvents::default();
+            for event in events {
+                recent.insert(event, capacity);
+            }
+            users.insert(user_id, recent);
+        }
+
+        Self {
+            capacity,
+            repository,
+            users: Arc::new(Mutex::new(users)),
+        }
+    }
+
+    /// Record an event for a user and save the claim, along with the
+    /// eviction of the user's oldest event once the cache is full. Returns
+    /// false if the event was already processed.
+    pub async fn claim(&self, user_id: &str, event: &CameraEvent) -> bool {
+        let processed = ProcessedEvent {
+            event_id: event.event_id.clone(),
+            device_id: event.device_id.clone(),
+            timestamp: event.timestamp.clone(),
+        };
+
+        let (evicted, saving) = {
+            let mut users = self.users.lock().await;
+            let recent = users.entry(user_id.to_string()).or_default();
+            let evicted = match recent.insert(processed.clone(), self.capacity) {
+                Some(evicted) => evicted,
+                None => return false,
+            };
+            (evicted, Arc::clone(&recent.saving))
+        };
+        // Wait for the user's earlier saves without holding up other users
+        let _saving = saving.lock_owned().await;
+
+        if let Err(e) = self.repository.save_processed_event(user_id, &processed, &evicted).await {
+            log::error!(
+                "Failed to save processed event {} for user {}: {}",
+                processed.event_id, user_id, e
+            );
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::json::JsonRepository;
+
+    fn processed(event_id: &str) -> ProcessedEvent {
+        ProcessedEvent {
+            event_id: event_id.to_string(),
+            device_id: "camera-1".to_string(),
+            timestamp: "2025-01-01T12:00:00Z".to_string(),
+        }
+    }
+
+    fn event(event_id: &str) -> CameraEvent {
+        CameraEvent {
+            event_id: event_id.to_string(),
+            event_type: "person".to_string(),
+            timestamp: "2025-01-01T12:00:00Z".to_string(),
+            device_id: "camera-1".to_string(),
+            project_id: None,
+        }
+    }
+
+    fn ids(events: Option<Vec<ProcessedEvent>>) -> Option<Vec<String>> {
+        events.map(|events| events.into_iter().map(|event| event.event_id).collect())
+    }
+
+    #[test]
+    fn the_oldest_events_are_evicted() {
+        let mut recent = RecentEvents::default();
+
+        assert_eq!(ids(recent.insert(processed("a"), 2)), Some(vec![]));
+        assert_eq!(ids(recent.insert(processed("b"), 2)), Some(vec![]));
+        assert_eq!(ids(recent.insert(processed("a"), 2)), None);
+        assert_eq!(ids(recent.insert(processed("c"), 2)), Some(vec!["a".to_string()]));
+
+        // Evicted events are forgotten
+        assert_eq!(ids(recent.insert(processed("a"), 2)), Some(vec!["b".to_string()]));
+        assert_eq!(recent.order.len(), 2);
+        assert_eq!(recent.ids.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn claims_survive_a_restart() {
+        let dir = std::env::temp_dir().join(format!("dishwashmon-dedup-{}", uuid::Uuid::new_v4()));
+        let events_file = dir.join("events.json").to_string_lossy().into_owned();
+        let repository = || -> Arc<dyn UserRepository> {
+            Arc::new(JsonRepository::new(
+                dir.join("users.json").to_string_lossy().into_owned(),
+                events_file.clone(),
+                0,
+                None,
+            ))
+        };
+
+        let dedup = EventDeduplicator::load(repository(), 2).await;
+        for event_id in ["e1", "e2", "e3"] {
+            assert!(dedup.claim("alice", &event(event_id)).await);
+        }
+        assert!(dedup.claim("bob", &event("e1")).await);
+        assert!(!dedup.claim("alice", &event("e3")).await);
+
+        let restarted = EventDeduplicator::load(repository(), 2).await;
+        assert!(!restarted.claim("alice", &event("e2")).await);
+        assert!(!restarted.claim("alice", &event("e3")).await);
+        assert!(!restarted.claim("bob", &event("e1")).await);
+        // Evicted before the restart
+        assert!(restarted.claim("alice", &event("e1")).await);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_slow_save_does_not_hold_up_other_users() {
+        let dir = std::env::temp_dir().join(format!("dishwashmon-dedup-{}", uuid::Uuid::new_v4()));
+        let repository: Arc<dyn UserRepository> = Arc::new(JsonRepository::new(
+            dir.join("users.json").to_string_lossy().into_owned(),
+            dir.join("events.json").to_string_lossy().into_owned(),
+            0,
+            None,
+        ));
+        let dedup = EventDeduplicator::load(repository, 2).await;
+        assert!(dedup.claim("alice", &event("e1")).await);
+
+        // Stand in for a save of Alice's that is still running
+        let saving = {
+            let users = dedup.users.lock().await;
+            Arc::clone(&users["alice"].saving)
+        };
+        let _saving = saving.lock_owned().await;
+
+        // Alice's next claim waits for it, Bob's shouldn't
+        let waiting = {
+            let dedup = dedup.clone();
+            tokio::spawn(async move { dedup.claim("alice", &event("e2")).await })
+        };
+        tokio::task::yield_now().await;
+        let bob = event("e1");
+        let claim = tokio::time::timeout(std::time::Duration::from_secs(1), dedup.claim("bob", &bob));
+        assert!(claim.await.unwrap());
+        assert!(!waiting.is_finished());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,269 @@
+use async_trait::async_trait;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+use crate::events::CameraEvent;
+
+/// Whether later handlers in the chain should see the event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandlerFlow {
+    Continue,
+    Stop,
+}
+
+/// Reacts to camera events for a user
+#[async_trait]
+pub trait EventHandler: Send + Sync {
+    /// Name used in logs
+    fn name(&self) -> &str;
+
+    async fn handle(
+        &self,
+        user_id: &str,
+        event: &CameraEvent,
+    ) -> Result<HandlerFlow, Box<dyn Error + Send + Sync>>;
+}
+
+/// Handlers registered for all events, a single user, or a single device
+#[derive(Default)]
+pub struct HandlerRegistry {
+    global: Vec<Arc<dyn EventHandler>>,
+    per_user: HashMap<String, Vec<Arc<dyn EventHandler>>>,
+    per_device: HashMap<(String, String), Vec<Arc<dyn EventHandler>>>,
+}
+
+pub type EventHandlers = Arc<RwLock<HandlerRegistry>>;
+
+impl HandlerRegistry {
+    /// Run a handler for every event of every user
+    pub fn register(&mut self, handler: Arc<dyn EventHandler>) {
+        self.global.push(handler);
+    }
+
+    /// Run a handler for every event of one user
+    pub fn register_for_user(&mut self, user_id: &str, handler: Arc<dyn EventHandler>) {
+        self.per_user
+            .entry(user_id.to_string())
+            .or_default()
+            .push(handler);
+    }
+
+    /// Run a handler for the events of one of a user's devices
+    pub fn register_for_device(
+        &mut self,
+        user_id: &str,
+        device_id: &str,
+        handler: Arc<dyn EventHandler>,
+    ) {
+        self.per_device
+            .entry((user_id.to_string(), device_id.to_string()))
+            .or_default()
+            .push(handler);
+    }
+
+    /// Remove every handler registered for a user or their devices
+    pub fn unregister_user(&mut self, user_id: &str) {
+        self.per_user.remove(user_id);
+        self.per_device.retain(|(owner, _), _| owner != user_id);
+    }
+
+    /// Handlers for an event in dispatch order: global handlers first, so
+    /// filters apply to everything, then user handlers, then device handlers,
+    /// each in registration order
+    pub fn handlers_for(&self, user_id: &str, device_id: &str) -> Vec<Arc<dyn EventHandler>> {
+        let user_handlers = self.per_user.get(user_id).into_iter().flatten();
+        let device_handlers = self
+            .per_device
+            .get(&(user_id.to_string(), device_id.to_string()))
+            .into_iter()
+            .flatten();
+
+        self.global
+            .iter()
+            .chain(user_handlers)
+            .chain(device_handlers)
+            .cloned()
+            .collect()
+    }
+}
+
+/// Logs every camera event
+pub struct LoggingHandler;
+
+#[async_trait]
+impl EventHandler for LoggingHandler {
+    fn name(&self) -> &str {
+        "logging"
+    }
+
+    async fn handle(
+        &self,
+        user_id: &str,
+        event: &CameraEvent,
+    ) -> Result<HandlerFlow, Box<dyn Error + Send + Sync>> {
+        match event.event_type.as_str() {
+            "motion" => log::info!(
+                "Motion detected on camera {} for user {} at {}",
+                event.device_id, user_id, event.timestamp
+            ),
+            "person" => log::info!(
+                "Person detected on camera {} for user {} at {}",
+                event.device_id, user_id, event.timestamp
+            ),
+            _ => log::info!(
+                "{} event on camera {} for user {} at {}",
+                event.event_type, event.device_id, user_id, event.timestamp
+            ),
+        }
+
+        Ok(HandlerFlow::Continue)
+    }
+}
+
+/// Stops the chain for event types nothing should act on
+pub struct EventTypeFilter {
+    ignored: HashSet<String>,
+}
+
+impl EventTypeFilter {
+    pub fn new(ignored: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            ignored: ignored.into_iter().collect(),
+        }
+    }
+
+    /// Ignore the comma-separated event types in `IGNORED_EVENT_TYPES`
+    pub fn from_env() -> Self {
+        let ignored = std::env::var("IGNORED_EVENT_TYPES")
+            .unwrap_or_else(|_| "clip_preview".to_string());
+        Self::new(
+            ignored
+                .split(',')
+                .map(|event_type| event_type.trim().to_string())
+                .filter(|event_type| !event_type.is_empty()),
+        )
+    }
+}
+
+#[async_trait]
+impl EventHandler for EventTypeFilter {
+    fn name(&self) -> &str {
+        "event-type-filter"
+    }
+
+    async fn handle(
+        &self,
+        _user_id: &str,
+        event: &CameraEvent,
+    ) -> Result<HandlerFlow, Box<dyn Error + Send + Sync>> {
+        if self.ignored.contains(&event.event_type) {
+            return Ok(HandlerFlow::Stop);
+        }
+        Ok(HandlerFlow::Continue)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    // Records the order handlers ran in
+    pub(crate) struct Recorder {
+        pub(crate) name: &'static str,
+        pub(crate) flow: HandlerFlow,
+        pub(crate) seen: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    #[async_trait]
+    impl EventHandler for Recorder {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        async fn handle(
+            &self,
+            _user_id: &str,
+            _event: &CameraEvent,
+        ) -> Result<HandlerFlow, Box<dyn Error + Send + Sync>> {
+            self.seen.lock().unwrap().push(self.name);
+            Ok(self.flow)
+        }
+    }
+
+    pub(crate) fn recorder(
+        name: &'static str,
+        flow: HandlerFlow,
+        seen: &Arc<Mutex<Vec<&'static str>>>,
+    ) -> Arc<dyn EventHandler> {
+        Arc::new(Recorder {
+            name,
+            flow,
+            seen: Arc::clone(seen),
+        })
+    }
+
+    fn names(handlers: Vec<Arc<dyn EventHandler>>) -> Vec<String> {
+        handlers.iter().map(|handler| handler.name().to_string()).collect()
+    }
+
+    #[test]
+    fn global_handlers_run_before_user_and_device_handlers() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut registry = HandlerRegistry::default();
+        registry.register_for_device("alice", "camera-1", recorder("device", HandlerFlow::Continue, &seen));
+        registry.register_for_user("alice", recorder("user", HandlerFlow::Continue, &seen));
+        registry.register(recorder("global-1", HandlerFlow::Continue, &seen));
+        registry.register(recorder("global-2", HandlerFlow::Continue, &seen));
+
+        assert_eq!(
+            names(registry.handlers_for("alice", "camera-1")),
+            ["global-1", "global-2", "user", "device"]
+        );
+        // Device handlers only see their own device, user handlers their own user
+        assert_eq!(
+            names(registry.handlers_for("alice", "camera-2")),
+            ["global-1", "global-2", "user"]
+        );
+        assert_eq!(
+            names(registry.handlers_for("bob", "camera-1")),
+            ["global-1", "global-2"]
+        );
+    }
+
+    #[test]
+    fn unregistering_a_user_keeps_other_handlers() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut registry = HandlerRegistry::default();
+        registry.register(recorder("global", HandlerFlow::Continue, &seen));
+        registry.register_for_user("alice", recorder("alice", HandlerFlow::Continue, &seen));
+        registry.register_for_device("alice", "camera-1", recorder("alice-camera", HandlerFlow::Continue, &seen));
+        registry.register_for_device("bob", "camera-1", recorder("bob-camera", HandlerFlow::Continue, &seen));
+
+        registry.unregister_user("alice");
+
+        assert_eq!(names(registry.handlers_for("alice", "camera-1")), ["global"]);
+        assert_eq!(
+            names(registry.handlers_for("bob", "camera-1")),
+            ["global", "bob-camera"]
+        );
+    }
+
+    #[tokio::test]
+    async fn the_filter_stops_ignored_event_types() {
+        let filter = EventTypeFilter::new(["clip_preview".to_string()]);
+        let mut event = CameraEvent {
+            event_id: "e1".to_string(),
+            event_type: "clip_preview".to_string(),
+            timestamp: "2025-01-01T12:00:00Z".to_string(),
+            device_id: "camera-1".to_string(),
+            project_id: None,
+        };
+
+        assert_eq!(filter.handle("alice", &event).await.unwrap(), HandlerFlow::Stop);
+        event.event_type = "person".to_string();
+        assert_eq!(filter.handle("alice", &event).await.unwrap(), HandlerFlow::Continue);
+    }
+}
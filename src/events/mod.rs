@@ -0,0 +1,18 @@
+use serde::{Deserialize, Serialize};
+
+pub mod dedup;
+pub mod handlers;
+pub mod processor;
+pub mod pubsub;
+pub mod sdm;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CameraEvent {
+    pub event_id: String,
+    pub event_type: String,
+    pub timestamp: String,
+    pub device_id: String,
+    /// Device Access project the device belongs to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub project_id: Option<String>,
+}
@@ -0,0 +1,101 @@
+use crate::events::{
+    dedup::EventDeduplicator,
+    handlers::{EventHandlers, HandlerFlow},
+    CameraEvent,
+};
+
+/// Handles camera events from every source exactly once
+#[derive(Clone)]
+pub struct EventProcessor {
+    dedup: EventDeduplicator,
+    handlers: EventHandlers,
+}
+
+impl EventProcessor {
+    pub fn new(dedup: EventDeduplicator, handlers: EventHandlers) -> Self {
+        Self { dedup, handlers }
+    }
+
+    /// The handlers run for each new event
+    pub fn handlers(&self) -> &EventHandlers {
+        &self.handlers
+    }
+
+    /// Process an event for a user. Returns false if the event was a duplicate.
+    pub async fn process(&self, user_id: &str, event: &CameraEvent) -> bool {
+        // The claim is saved before acting on the event so that a crash
+        // can't cause it to be handled twice
+        if !self.dedup.claim(user_id, event).await {
+            log::debug!(
+                "Skipping already processed event {} for user {}",
+                event.event_id, user_id
+            );
+            return false;
+        }
+
+        // Don't hold the registry lock while handlers run
+        let handlers = {
+            let registry = self.handlers.read().await;
+            registry.handlers_for(user_id, &event.device_id)
+        };
+
+        for handler in handlers {
+            match handler.handle(user_id, event).await {
+                Ok(HandlerFlow::Continue) => {}
+                Ok(HandlerFlow::Stop) => break,
+                Err(e) => log::error!(
+                    "Handler {} failed on event {} for user {}: {}",
+                    handler.name(),
+                    event.event_id,
+                    user_id,
+                    e
+                ),
+            }
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::handlers::{tests::recorder, HandlerRegistry};
+    use crate::storage::{json::JsonRepository, UserRepository};
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn a_stopping_handler_ends_the_chain() {
+        let dir = std::env::temp_dir().join(format!("dishwashmon-processor-{}", uuid::Uuid::new_v4()));
+        let repository: Arc<dyn UserRepository> = Arc::new(JsonRepository::new(
+            dir.join("users.json").to_string_lossy().into_owned(),
+            dir.join("events.json").to_string_lossy().into_owned(),
+            0,
+            None,
+        ));
+        let dedup = EventDeduplicator::load(repository, 10).await;
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut registry = HandlerRegistry::default();
+        registry.register(recorder("global", HandlerFlow::Continue, &seen));
+        registry.register_for_user("alice", recorder("user", HandlerFlow::Stop, &seen));
+        registry.register_for_device("alice", "camera-1", recorder("device", HandlerFlow::Continue, &seen));
+        let processor = EventProcessor::new(dedup, Arc::new(tokio::sync::RwLock::new(registry)));
+
+        let event = CameraEvent {
+            event_id: "e1".to_string(),
+            event_type: "person".to_string(),
+            timestamp: "2025-01-01T12:00:00Z".to_string(),
+            device_id: "camera-1".to_string(),
+            project_id: None,
+        };
+        assert!(processor.process("alice", &event).await);
+        assert_eq!(*seen.lock().unwrap(), ["global", "user"]);
+
+        // Duplicates don't reach any handler
+        assert!(!processor.process("alice", &event).await);
+        assert_eq!(seen.lock().unwrap().len(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,284 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+
+use crate::events::{sdm, CameraEvent};
+use crate::sdm::{SdmClient, SdmError};
+
+#[derive(Debug, Clone)]
+pub struct PubSubConfig {
+    pub base_url: String,
+    /// Subscription used for users that have not configured their own
+    pub default_subscription: Option<String>,
+    pub max_messages: u32,
+    /// Undecodable messages are dropped after this many deliveries
+    pub max_delivery_attempts: u32,
+    /// The emulator does not accept OAuth credentials
+    pub use_auth: bool,
+}
+
+impl PubSubConfig {
+    pub fn from_env() -> Self {
+        let emulator_host = env::var("PUBSUB_EMULATOR_HOST").ok().filter(|v| !v.is_empty());
+        let base_url = match &emulator_host {
+            Some(host) => format!("http://{}", host),
+            None => env::var("PUBSUB_API_BASE_URL")
+                .unwrap_or_else(|_| "https://pubsub.googleapis.com".to_string()),
+        };
+
+        Self {
+            base_url,
+            default_subscription: env::var("PUBSUB_SUBSCRIPTION").ok().filter(|v| !v.is_empty()),
+            max_messages: env::var("PUBSUB_MAX_MESSAGES")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(100),
+            max_delivery_attempts: env::var("PUBSUB_MAX_DELIVERY_ATTEMPTS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(5),
+            use_auth: emulator_host.is_none(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PubSubMessage {
+    #[serde(default)]
+    pub data: String,
+    pub message_id: String,
+}
+
+impl PubSubMessage {
+    /// Decode the base64 payload into an SDM event envelope
+    pub fn sdm_envelope(&self) -> Result<sdm::SdmEventEnvelope, String> {
+        let data = BASE64
+            .decode(&self.data)
+            .map_err(|e| format!("invalid base64 payload: {}", e))?;
+        sdm::decode_sdm_envelope(&data).map_err(|e| format!("invalid SDM event: {}", e))
+    }
+
+    /// Decode the base64 payload into SDM camera events
+    pub fn camera_events(&self) -> Result<Vec<CameraEvent>, String> {
+        Ok(self.sdm_envelope()?.into_camera_events())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReceivedMessage {
+    pub ack_id: String,
+    pub message: PubSubMessage,
+    // Only populated when the subscription has a dead-letter policy
+    #[serde(default)]
+    pub delivery_attempt: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct PullResponse {
+    #[serde(default)]
+    received_messages: Vec<ReceivedMessage>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PullRequest {
+    max_messages: u32,
+    return_immediately: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AcknowledgeRequest<'a> {
+    ack_ids: &'a [String],
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ModifyAckDeadlineRequest<'a> {
+    ack_ids: &'a [String],
+    ack_deadline_seconds: u32,
+}
+
+/// Minimal client for the Pub/Sub REST API
+#[derive(Clone)]
+pub struct PubSubClient {
+    client: SdmClient,
+    config: PubSubConfig,
+}
+
+impl PubSubClient {
+    pub fn new(config: PubSubConfig, client: SdmClient) -> Self {
+        Self {
+            client,
+            config,
+        }
+    }
+
+    pub fn config(&self) -> &PubSubConfig {
+        &self.config
+    }
+
+    async fn post<T: Serialize>(
+        &self,
+        subscription: &str,
+        action: &str,
+        body: &T,
+        access_token: &str,
+    ) -> Result<reqwest::Response, SdmError> {
+        let url = format!("{}/v1/{}:{}", self.config.base_url, subscription, action);
+        let mut request = self.client.http().post(&url).json(body);
+        if self.config.use_auth {
+            request = request.bearer_auth(access_token);
+        }
+
+        self.client.execute(request).await
+    }
+
+    /// Pull waiting messages from a subscription
+    pub async fn pull(
+        &self,
+        subscription: &str,
+        access_token: &str,
+    ) -> Result<Vec<ReceivedMessage>, SdmError> {
+        // We poll on our own schedule, so don't hold the request open
+        let request = PullRequest {
+            max_messages: self.config.max_messages,
+            return_immediately: true,
+        };
+
+        let response = self.post(subscription, "pull", &request, access_token).await?;
+        let pulled: PullResponse = response.json().await?;
+        Ok(pulled.received_messages)
+    }
+
+    /// Acknowledge messages so they are not delivered again
+    pub async fn acknowledge(
+        &self,
+        subscription: &str,
+        ack_ids: &[String],
+        access_token: &str,
+    ) -> Result<(), SdmError> {
+        if ack_ids.is_empty() {
+            return Ok(());
+        }
+
+        let request = AcknowledgeRequest { ack_ids };
+        self.post(subscription, "acknowledge", &request, access_token)
+            .await?;
+        Ok(())
+    }
+
+    /// Return messages to the subscription for immediate redelivery
+    pub async fn nack(
+        &self,
+        subscription: &str,
+        ack_ids: &[String],
+        access_token: &str,
+    ) -> Result<(), SdmError> {
+        if ack_ids.is_empty() {
+            return Ok(());
+        }
+
+        let request = ModifyAckDeadlineRequest {
+            ack_ids,
+            ack_deadline_seconds: 0,
+        };
+        self.post(subscription, "modifyAckDeadline", &request, access_token)
+            .await?;
+        Ok(())
+    }
+}
+
+/// A decoded message waiting to be processed and acknowledged
+pub struct PulledMessage {
+    pub ack_id: String,
+    pub message_id: String,
+    pub events: Vec<CameraEvent>,
+}
+
+/// Pull consumer for one subscription that tracks redeliveries of
+/// messages that could not be decoded
+pub struct SubscriptionConsumer {
+    client: PubSubClient,
+    subscription: String,
+    // message_id -> number of times we have seen it fail to decode
+    failed_deliveries: HashMap<String, u32>,
+}
+
+impl SubscriptionConsumer {
+    pub fn new(client: PubSubClient, subscription: String) -> Self {
+        Self {
+            client,
+            subscription,
+            failed_deliveries: HashMap::new(),
+        }
+    }
+
+    pub fn subscription(&self) -> &str {
+        &self.subscription
+    }
+
+    /// Pull a batch of messages and decode them. Messages that fail to decode
+    /// are nacked for redelivery until `max_delivery_attempts` is reached and
+    /// then acknowledged and dropped so they cannot block the subscription.
+    pub async fn pull(&mut self, access_token: &str) -> Result<Vec<PulledMessage>, SdmError> {
+        let received = self.client.pull(&self.subscription, access_token).await?;
+
+        let mut decoded = Vec::new();
+        let mut nacks = Vec::new();
+        let mut dropped = Vec::new();
+
+        for received_message in received {
+            let message_id = received_message.message.message_id.clone();
+            if received_message.delivery_attempt.unwrap_or(1) > 1
+                || self.failed_deliveries.contains_key(&message_id)
+            {
+                log::debug!("Message {} on {} is a redelivery", message_id, self.subscription);
+            }
+
+            match received_message.message.camera_events() {
+                Ok(events) => {
+                    self.failed_deliveries.remove(&message_id);
+                    decoded.push(PulledMessage {
+                        ack_id: received_message.ack_id,
+                        message_id,
+                        events,
+                    });
+                }
+                Err(e) => {
+                    let attempts = self.failed_deliveries.entry(message_id.clone()).or_insert(0);
+                    *attempts = (*attempts).max(received_message.delivery_attempt.unwrap_or(0)) + 1;
+
+                    if *attempts >= self.client.config().max_delivery_attempts {
+                        log::error!(
+                            "Dropping message {} on {} after {} attempts: {}",
+                            message_id, self.subscription, attempts, e
+                        );
+                        self.failed_deliveries.remove(&message_id);
+                        dropped.push(received_message.ack_id);
+                    } else {
+                        log::warn!(
+                            "Failed to decode message {} on {} (attempt {}): {}",
+                            message_id, self.subscription, attempts, e
+                        );
+                        nacks.push(received_message.ack_id);
+                    }
+                }
+            }
+        }
+
+        self.client.acknowledge(&self.subscription, &dropped, access_token).await?;
+        self.client.nack(&self.subscription, &nacks, access_token).await?;
+
+        Ok(decoded)
+    }
+
+    /// Acknowledge messages once their events have been processed
+    pub async fn acknowledge(&self, ack_ids: &[String], access_token: &str) -> Result<(), SdmError> {
+        self.client.acknowledge(&self.subscription, ack_ids, access_token).await
+    }
+}
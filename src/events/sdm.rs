@@ -0,0 +1,179 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::events::CameraEvent;
+
+/// Event message published by the Smart Device Management API
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SdmEventEnvelope {
+    pub event_id: String,
+    pub timestamp: String,
+    #[serde(default)]
+    pub resource_update: Option<ResourceUpdate>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResourceUpdate {
+    // Format: "enterprises/project-id/devices/device-id"
+    pub name: String,
+    #[serde(default)]
+    pub events: HashMap<String, SdmEventPayload>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SdmEventPayload {
+    #[serde(default)]
+    pub event_id: Option<String>,
+}
+
+/// Map an SDM event name to the short event type used by the rest of the service
+pub fn event_type_for(sdm_event: &str) -> String {
+    match sdm_event {
+        "sdm.devices.events.CameraMotion.Motion" => "motion".to_string(),
+        "sdm.devices.events.CameraPerson.Person" => "person".to_string(),
+        "sdm.devices.events.CameraSound.Sound" => "sound".to_string(),
+        "sdm.devices.events.DoorbellChime.Chime" => "chime".to_string(),
+        "sdm.devices.events.CameraClipPreview.ClipPreview" => "clip_preview".to_string(),
+        // Fall back to the trait and event name, e.g. "CameraMotion.Motion"
+        other => other
+            .strip_prefix("sdm.devices.events.")
+            .unwrap_or(other)
+            .to_string(),
+    }
+}
+
+impl SdmEventEnvelope {
+    /// Device ID extracted from the resource name, if this is a device update
+    pub fn device_id(&self) -> Option<&str> {
+        self.resource_update
+            .as_ref()
+            .and_then(|update| update.name.split('/').next_back())
+    }
+
+    /// Device Access project ID extracted from the resource name
+    pub fn project_id(&self) -> Option<&str> {
+        self.resource_update
+            .as_ref()
+            .and_then(|update| update.name.split('/').nth(1))
+    }
+
+    /// Convert the envelope into one `CameraEvent` per contained event.
+    /// Trait-only updates carry no events and produce an empty list.
+    pub fn into_camera_events(self) -> Vec<CameraEvent> {
+        let device_id = match self.device_id() {
+            Some(id) => id.to_string(),
+            None => return Vec::new(),
+        };
+        let project_id = self.project_id().map(str::to_string);
+
+        let update = match self.resource_update {
+            Some(update) => update,
+            None => return Vec::new(),
+        };
+
+        let single_event = update.events.len() == 1;
+        update
+            .events
+            .into_iter()
+            .map(|(name, payload)| {
+                // Messages with several events share one envelope ID, so fall
+                // back to a per-event ID to keep them distinguishable
+                let event_id = match payload.event_id {
+                    Some(id) => id,
+                    None if single_event => self.event_id.clone(),
+                    None => format!("{}:{}", self.event_id, name),
+                };
+
+                CameraEvent {
+                    event_id,
+                    event_type: event_type_for(&name),
+                    timestamp: self.timestamp.clone(),
+                    device_id: device_id.clone(),
+                    project_id: project_id.clone(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Decode the JSON payload of a Pub/Sub message
+pub fn decode_sdm_envelope(data: &[u8]) -> Result<SdmEventEnvelope, serde_json::Error> {
+    serde_json::from_slice(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn envelope(events: serde_json::Value) -> SdmEventEnvelope {
+        serde_json::from_value(serde_json::json!({
+            "eventId": "envelope-1",
+            "timestamp": "2025-01-01T12:00:00Z",
+            "resourceUpdate": {
+                "name": "enterprises/project-1/devices/camera-1",
+                "events": events,
+            },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn events_keep_their_own_ids() {
+        let events = envelope(serde_json::json!({
+            "sdm.devices.events.CameraPerson.Person": {"eventId": "person-1"},
+        }))
+        .into_camera_events();
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event_id, "person-1");
+        assert_eq!(events[0].event_type, "person");
+        assert_eq!(events[0].device_id, "camera-1");
+        assert_eq!(events[0].project_id.as_deref(), Some("project-1"));
+        assert_eq!(events[0].timestamp, "2025-01-01T12:00:00Z");
+    }
+
+    #[test]
+    fn a_lone_event_without_an_id_uses_the_envelope_id() {
+        let events = envelope(serde_json::json!({
+            "sdm.devices.events.CameraMotion.Motion": {},
+        }))
+        .into_camera_events();
+
+        assert_eq!(events[0].event_id, "envelope-1");
+        assert_eq!(events[0].event_type, "motion");
+    }
+
+    #[test]
+    fn several_events_without_ids_get_distinct_ids() {
+        let mut events = envelope(serde_json::json!({
+            "sdm.devices.events.CameraMotion.Motion": {},
+            "sdm.devices.events.CameraSound.Sound": {},
+        }))
+        .into_camera_events();
+        events.sort_by(|a, b| a.event_type.cmp(&b.event_type));
+
+        assert_eq!(events[0].event_id, "envelope-1:sdm.devices.events.CameraMotion.Motion");
+        assert_eq!(events[1].event_id, "envelope-1:sdm.devices.events.CameraSound.Sound");
+    }
+
+    #[test]
+    fn trait_updates_carry_no_events() {
+        let update: SdmEventEnvelope = serde_json::from_value(serde_json::json!({
+            "eventId": "envelope-1",
+            "timestamp": "2025-01-01T12:00:00Z",
+            "resourceUpdate": {"name": "enterprises/project-1/devices/camera-1", "traits": {}},
+        }))
+        .unwrap();
+        assert_eq!(update.project_id(), Some("project-1"));
+        assert!(update.into_camera_events().is_empty());
+
+        assert!(envelope(serde_json::json!({})).into_camera_events().is_empty());
+    }
+
+    #[test]
+    fn unknown_event_names_keep_their_trait() {
+        assert_eq!(event_type_for("sdm.devices.events.Future.Thing"), "Future.Thing");
+    }
+}
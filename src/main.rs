@@ -1,143 +1,178 @@
 mod auth;
 mod api;
 mod devices;
+mod dishwasher;
+mod events;
+mod notifications;
+mod sdm;
 mod storage;
+mod supervisor;
 mod views;
 
-use auth::models::{NestToken, OAuthConfig, UserConfig, UserStore};
+use auth::{
+    models::{NestToken, OAuthConfig, UserConfig, UserStore},
+    oauth::AuthError,
+};
+use dishwasher::{state_machine::StateMachineConfig, tracker::DishwasherTracker};
 use dotenv::dotenv;
-use log::info;
-use reqwest::{header, Client};
-use serde::Deserialize;
-use std::{collections::HashMap, env, error::Error, sync::Arc};
-use tokio::{
-    sync::Mutex,
-    time::{sleep, Duration},
+use events::{
+    dedup::EventDeduplicator,
+    handlers::{EventTypeFilter, HandlerRegistry, LoggingHandler},
+    processor::EventProcessor,
+    pubsub::{PubSubClient, PubSubConfig, SubscriptionConsumer},
 };
+use notifications::{
+    email::{SmtpConfig, SmtpNotifier},
+    mqtt::{MqttConfig, MqttPublisher},
+    webhook::{WebhookNotifier, WebhookSettings},
+    Notification, NotificationDispatcher, Notifier,
+};
+use std::{env, error::Error, sync::Arc};
+use sdm::{SdmClient, SdmClientConfig, SdmError};
+use supervisor::{MonitorContext, ShutdownSignal, Supervisor};
+use tokio::time::Duration;
 
-#[derive(Debug, Deserialize)]
-struct CameraEvent {
-    event_id: String,
-    event_type: String,
-    timestamp: String,
-    device_id: String,
-    // Additional fields based on Google Nest API response
-}
-
-async fn poll_camera_events(
-    user_config: &UserConfig,
-) -> Result<Vec<CameraEvent>, Box<dyn Error + Send + Sync>> {
-    let client = Client::new();
-    let mut headers = header::HeaderMap::new();
-    headers.insert(
-        header::AUTHORIZATION,
-        header::HeaderValue::from_str(&format!("Bearer {}", user_config.token.access_token))?,
-    );
-
-    let mut all_events = Vec::new();
+// How often each user's subscription is polled
+const POLL_INTERVAL: Duration = Duration::from_secs(15);
 
-    // Poll each device for the user
-    for device_id in &user_config.device_ids {
-        let url = format!(
-            "https://smartdevicemanagement.googleapis.com/v1/enterprises/{}/devices/{}/events",
-            user_config.project_id, device_id
-        );
+// Wait before polling again after an error that won't clear up by itself,
+// such as a deleted subscription or revoked permission
+const ACCESS_ERROR_BACKOFF: Duration = Duration::from_secs(300);
 
-        match client.get(&url).headers(headers.clone()).send().await {
-            Ok(response) => {
-                if let Ok(events) = response.json::<Vec<CameraEvent>>().await {
-                    all_events.extend(events);
-                }
-            }
-            Err(e) => {
-                log::error!("Error polling device {}: {}", device_id, e);
-            }
-        }
-    }
-
-    Ok(all_events)
-}
-
-async fn process_event(event: &CameraEvent, user_id: &str) {
-    match event.event_type.as_str() {
-        "motion" => {
-            info!(
-                "Motion detected on camera {} for user {} at {}",
-                event.device_id, user_id, event.timestamp
-            );
-            // Your custom logic here - could be different per user
-        }
-        "person" => {
-            info!(
-                "Person detected on camera {} for user {} at {}",
-                event.device_id, user_id, event.timestamp
-            );
-            // Your custom logic here
-        }
-        // Add other event types
-        _ => info!("Unhandled event type: {}", event.event_type),
-    }
-}
-
-async fn monitor_user_cameras(
-    user_config: UserConfig,
-    users: UserStore,
-    oauth_config: OAuthConfig, 
-) {
-    let user_id = user_config.user_id.clone();
+async fn monitor_user_cameras(user_id: String, context: MonitorContext, mut shutdown: ShutdownSignal) {
+    let MonitorContext {
+        users,
+        oauth_config,
+        events,
+        pubsub,
+        sdm,
+        notifications,
+        ..
+    } = context;
+    let mut consumer: Option<SubscriptionConsumer> = None;
+    let mut warned_no_subscription = false;
 
     loop {
+        let mut next_poll = POLL_INTERVAL;
+
         // Get current user config
         let current_config = {
             let users_lock = users.lock().await;
             users_lock.get(&user_id).cloned()
         };
 
-        if let Some(mut config) = current_config {
+        if let Some(config) = current_config {
+            let mut token = match config.token.clone() {
+                Some(token) => token,
+                None => {
+                    log::info!("User {} has no Google account connected, stopping monitoring", user_id);
+                    break;
+                }
+            };
+            
             // Check if token needs refresh
-            if config.token.is_expired() {
-                log::info!("Token expired for user {}, refreshing", user_id);
-                match auth::oauth::refresh_token(&oauth_config, &config.token.refresh_token).await {
-                    Ok(new_token) => {
-                        let mut users_lock = users.lock().await;
-                        if let Some(user_config) = users_lock.get_mut(&user_id) {
-                            user_config.token = new_token;
-                            log::info!("Refreshed token for user {}", user_id);
-                            config = user_config.clone();
-                        }
+            // Refresh shortly before the stored expiry rather than waiting for a 401
+            if token.is_expired() {
+                log::info!(
+                    "Token for user {} expires at {}, refreshing",
+                    user_id,
+                    token.expires_at.to_rfc3339()
+                );
+                if !refresh_user_token(&user_id, &token, &users, &sdm, &oauth_config, &notifications).await {
+                    break;
+                }
+                if let Some(refreshed) = users.lock().await.get(&user_id).and_then(|c| c.token.clone()) {
+                    token = refreshed;
+                }
+            }
+
+            // Events are delivered through the user's Pub/Sub subscription
+            let subscription = config
+                .pubsub_subscription
+                .clone()
+                .or_else(|| pubsub.config().default_subscription.clone());
+            let subscription = match subscription {
+                Some(subscription) => subscription,
+                None => {
+                    // Events may still arrive through the push endpoint
+                    if !warned_no_subscription {
+                        log::warn!("No Pub/Sub subscription configured for user {}", user_id);
+                        warned_no_subscription = true;
                     }
-                    Err(e) => {
-                        log::error!("Failed to refresh token for user {}: {}", user_id, e);
+                    if !shutdown.sleep(POLL_INTERVAL).await {
+                        break;
                     }
+                    continue;
                 }
+            };
+            if consumer.as_ref().map(|c| c.subscription()) != Some(subscription.as_str()) {
+                log::info!("Consuming events for user {} from {}", user_id, subscription);
+                consumer = Some(SubscriptionConsumer::new(pubsub.clone(), subscription));
             }
+            let consumer = consumer.as_mut().expect("consumer was just created");
 
             // Poll for events
-            match poll_camera_events(&config).await {
-                Ok(events) => {
-                    for event in events {
-                        process_event(&event, &user_id).await;
-                    }
-                }
-                Err(e) => {
-                    log::error!("Error polling events for user {}: {}", user_id, e);
+            match consumer.pull(&token.access_token).await {
+                Ok(messages) => {
+                    // Several users can share a subscription and whichever of
+                    // their monitors pulls a message gets it, so events go to
+                    // everyone monitoring the device. Every message is then
+                    // handled, or belongs to nobody, and can be acknowledged.
+                    let targets = subscribers(
+                        &users,
+                        consumer.subscription(),
+                        pubsub.config().default_subscription.as_deref(),
+                    )
+                    .await;
+
+                    let mut ack_ids = Vec::new();
+                    for message in messages {
+                        log::debug!("Processing message {} pulled for user {}", message.message_id, user_id);
 
-                    // If unauthorized, try refreshing token
-                    if e.to_string().contains("401") {
-                        match auth::oauth::refresh_token(&oauth_config, &config.token.refresh_token).await {
-                            Ok(new_token) => {
-                                let mut users_lock = users.lock().await;
-                                if let Some(user_config) = users_lock.get_mut(&user_id) {
-                                    user_config.token = new_token;
-                                    log::info!("Refreshed token for user {}", user_id);
+                        for event in &message.events {
+                            for (target, project_id, device_ids) in &targets {
+                                if event.project_id.as_ref() == Some(project_id)
+                                    && device_ids.contains(&event.device_id)
+                                {
+                                    events.process(target, event).await;
                                 }
                             }
-                            Err(e) => {
-                                log::error!("Failed to refresh token for user {}: {}", user_id, e);
-                            }
                         }
+                        ack_ids.push(message.ack_id);
+                    }
+
+                    if let Err(e) = consumer.acknowledge(&ack_ids, &token.access_token).await {
+                        log::error!("Failed to acknowledge events for user {}: {}", user_id, e);
                     }
                 }
+                Err(SdmError::Unauthorized(message)) => {
+                    log::warn!("Access token for user {} was rejected: {}", user_id, message);
+
+                    if !refresh_user_token(&user_id, &token, &users, &sdm, &oauth_config, &notifications).await {
+                        break;
+                    }
+                }
+                Err(SdmError::RateLimited { retry_after }) => {
+                    next_poll = retry_after.unwrap_or(POLL_INTERVAL).max(POLL_INTERVAL);
+                    log::warn!("Rate limited polling events for user {}, waiting {:?}", user_id, next_poll);
+                }
+                Err(SdmError::NotFound(message)) => {
+                    log::error!(
+                        "Subscription {} for user {} does not exist: {}",
+                        consumer.subscription(), user_id, message
+                    );
+                    next_poll = ACCESS_ERROR_BACKOFF;
+                }
+                Err(SdmError::PermissionDenied(message)) => {
+                    log::error!(
+                        "User {} may not read from subscription {}: {}",
+                        user_id, consumer.subscription(), message
+                    );
+                    next_poll = ACCESS_ERROR_BACKOFF;
+                }
+                Err(e) => {
+                    log::error!("Error polling events for user {}: {}", user_id, e);
+                }
             }
         } else {
             // User was removed while we were running
@@ -145,7 +180,93 @@ async fn monitor_user_cameras(
             break;
         }
 
-        sleep(Duration::from_secs(15)).await;
+        if !shutdown.sleep(next_poll).await {
+            log::info!("Stopping monitoring for user {}", user_id);
+            break;
+        }
+    }
+}
+
+// Users whose events arrive on `subscription`, with their Device Access
+// project and the devices they monitor in it. Subscriptions can carry events
+// from several projects, so users without a project get none.
+async fn subscribers(
+    users: &UserStore,
+    subscription: &str,
+    default_subscription: Option<&str>,
+) -> Vec<(String, String, Vec<String>)> {
+    let users_lock = users.lock().await;
+    users_lock
+        .values()
+        .filter(|config| !config.needs_reauth)
+        .filter(|config| config.pubsub_subscription.as_deref().or(default_subscription) == Some(subscription))
+        .filter_map(|config| {
+            let project_id = config.project_id.clone()?;
+            Some((config.user_id.clone(), project_id, config.device_ids.clone()))
+        })
+        .collect()
+}
+
+// Users created before users were keyed by Google account can only be
+// reached from a session issued back then. Pause their monitoring until the
+// owner reconnects Google from such a session, which moves them to their
+// Google account.
+async fn pause_legacy_users(users: &UserStore) {
+    let mut users_lock = users.lock().await;
+    for config in users_lock
+        .values_mut()
+        .filter(|config| config.is_legacy() && !config.needs_reauth)
+    {
+        log::warn!(
+            "User {} predates Google sign-in, pausing monitoring until they reconnect",
+            config.user_id
+        );
+        config.needs_reauth = true;
+    }
+}
+
+// Refresh a user's access token and store it. Returns false if Google
+// rejected the refresh token, in which case the user is flagged as needing to
+// sign in again and told about it, and monitoring should stop.
+async fn refresh_user_token(
+    user_id: &str,
+    token: &NestToken,
+    users: &UserStore,
+    sdm: &SdmClient,
+    oauth_config: &OAuthConfig,
+    notifications: &NotificationDispatcher,
+) -> bool {
+    match auth::oauth::refresh_token(sdm, oauth_config, token).await {
+        Ok(new_token) => {
+            let mut users_lock = users.lock().await;
+            if let Some(user_config) = users_lock.get_mut(user_id) {
+                user_config.token = Some(new_token);
+                log::info!("Refreshed token for user {}", user_id);
+            }
+            true
+        }
+        Err(AuthError::InvalidGrant(reason)) => {
+            log::warn!(
+                "Refresh token for user {} was rejected ({}), stopping monitoring until they sign in again",
+                user_id, reason
+            );
+            {
+                let mut users_lock = users.lock().await;
+                if let Some(user_config) = users_lock.get_mut(user_id) {
+                    user_config.needs_reauth = true;
+                }
+            }
+            notifications
+                .dispatch(&Notification::ReauthRequired {
+                    user_id: user_id.to_string(),
+                })
+                .await;
+            false
+        }
+        Err(e) => {
+            log::error!("Failed to refresh token for user {}: {}", user_id, e);
+            true
+        }
     }
 }
 
@@ -154,16 +275,11 @@ pub async fn add_user(
     user_id: String,
     token: NestToken,
     device_ids: Vec<String>,
-    project_id: String,
+    project_id: Option<String>,
 ) {
     let mut users_lock = users.lock().await;
     
-    let user_config = UserConfig {
-        user_id: user_id.clone(),
-        device_ids,
-        token,
-        project_id,
-    };
+    let user_config = UserConfig::new(user_id.clone(), device_ids, token, project_id);
     
     users_lock.insert(user_id, user_config);
 }
@@ -176,23 +292,13 @@ fn setup_logging() {
 
 #[cfg(feature = "web-api")]
 async fn start_web_server(
-    users: UserStore,
-    oauth_config: OAuthConfig,
+    app_state: api::handlers::auth_handlers::AppState,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    use std::collections::HashMap;
     use std::net::SocketAddr;
-    use std::sync::Arc;
     use axum::Router;
     use tower_http::cors::{Any, CorsLayer};
     use tower_http::trace::TraceLayer;
     
-    // Create app state for the web server
-    let app_state = api::handlers::auth_handlers::AppState {
-        users: Arc::clone(&users),
-        oauth_config,
-        auth_states: Arc::new(tokio::sync::Mutex::new(HashMap::new())),
-    };
-    
     // Start the web server
     let server_port = env::var("SERVER_PORT")
         .unwrap_or_else(|_| "3000".to_string())
@@ -205,9 +311,11 @@ async fn start_web_server(
         .allow_methods(Any)
         .allow_headers(Any);
     
+    let mut shutdown = app_state.supervisor.shutdown_signal();
     let app = Router::new()
         .merge(api::auth_routes::auth_routes())
         .merge(api::device_routes::device_routes())
+        .merge(api::event_routes::event_routes())
         .merge(api::web_routes::web_routes())
         .with_state(app_state)
         .layer(cors)
@@ -216,9 +324,10 @@ async fn start_web_server(
     let addr = SocketAddr::from(([0, 0, 0, 0], server_port));
     log::info!("Server listening on {}", addr);
     
-    // Run the server
+    // Run the server until shutdown, letting in-flight requests finish
     axum::Server::bind(&addr)
         .serve(app.into_make_service())
+        .with_graceful_shutdown(async move { shutdown.wait().await })
         .await?;
     
     Ok(())
@@ -237,97 +346,313 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let client_secret = env::var("GOOGLE_CLIENT_SECRET")
         .map_err(|_| "GOOGLE_CLIENT_SECRET environment variable must be set")?;
         
-    let _project_id = env::var("GOOGLE_PROJECT_ID")
-        .map_err(|_| "GOOGLE_PROJECT_ID environment variable must be set")?;
+    // Device Access project given to new users, for deployments serving one
+    // household. Otherwise each user enters their own during onboarding.
+    let default_project_id = match env::var("GOOGLE_PROJECT_ID").ok().filter(|id| !id.is_empty()) {
+        Some(project_id) => Some(
+            devices::discovery::parse_project_id(&project_id)
+                .map_err(|_| "GOOGLE_PROJECT_ID is not a valid Device Access project ID")?,
+        ),
+        None => None,
+    };
     
     let host = env::var("HOST").unwrap_or_else(|_| "localhost".to_string());
     let port = env::var("SERVER_PORT").unwrap_or_else(|_| "3000".to_string());
     
-    let redirect_uri = env::var("REDIRECT_URI").unwrap_or_else(|_| {
-        if host == "localhost" {
-            format!("http://{}:{}/auth/callback", host, port)
-        } else {
-            format!("https://{}/auth/callback", host)
-        }
-    });
+    // Where the app is reached, for the OAuth redirect and links in emails
+    let public_url = env::var("PUBLIC_URL")
+        .ok()
+        .filter(|v| !v.is_empty())
+        .map(|url| url.trim_end_matches('/').to_string())
+        .unwrap_or_else(|| {
+            if host == "localhost" {
+                format!("http://{}:{}", host, port)
+            } else {
+                format!("https://{}", host)
+            }
+        });
+    
+    let redirect_uri = env::var("REDIRECT_URI")
+        .unwrap_or_else(|_| format!("{}/auth/callback", public_url));
     
     log::info!("Using redirect URI: {}", redirect_uri);
     
     // Create OAuth configuration
-    let oauth_config = OAuthConfig {
+    let mut oauth_config = OAuthConfig {
         client_id,
         client_secret,
         redirect_uri,
         ..Default::default()
     };
     
+    // Google's endpoints can be swapped for a local stand-in when testing
+    if let Some(uri) = env::var("GOOGLE_AUTH_URI").ok().filter(|v| !v.is_empty()) {
+        oauth_config.auth_uri = uri;
+    }
+    if let Some(uri) = env::var("GOOGLE_TOKEN_URI").ok().filter(|v| !v.is_empty()) {
+        oauth_config.token_uri = uri;
+    }
+    if let Some(uri) = env::var("GOOGLE_JWKS_URI").ok().filter(|v| !v.is_empty()) {
+        oauth_config.jwks_uri = uri;
+    }
+    
     log::info!("Starting dishwasher monitor service");
     
+    // Users live in Postgres when DATABASE_URL is set, otherwise in JSON files
+    let repository = storage::repository_from_env().await?;
+    log::info!("Using {} storage", repository.name());
+    
     // Load user store from persistent storage or create a new one
-    let data_file = env::var("DATA_FILE").unwrap_or_else(|_| "data/users.json".to_string());
-    let users = storage::load_user_data(&data_file).await.unwrap_or_else(|e| {
-        log::error!("Failed to load user data: {}", e);
-        Arc::new(Mutex::new(HashMap::new()))
-    });
+    // Refuse to start rather than overwrite unreadable data with an empty store
+    let users = match repository.load_users().await {
+        Ok(users) => UserStore::new(users),
+        Err(e) => {
+            log::error!("Failed to load user data: {}", e);
+            return Err(e.into());
+        }
+    };
+    pause_legacy_users(&users).await;
+    // Write once at startup so changes to the stored format (token encryption,
+    // key rotation, new fields) are applied straight away
+    users.mark_changed();
     
-    // Start periodic saves
-    let users_for_save = Arc::clone(&users);
-    storage::start_periodic_save(
-        users_for_save,
-        data_file,
-        std::time::Duration::from_secs(60), // Save every minute
+    // Save whenever users change
+    storage::start_change_driven_save(
+        users.clone(),
+        Arc::clone(&repository),
+        std::time::Duration::from_secs(2),
     ).await;
     
+    // Every call to Google shares one connection pool and retry policy
+    let sdm = SdmClient::new(SdmClientConfig::from_env());
+    
+    // Camera events arrive through Google Cloud Pub/Sub
+    let pubsub = PubSubClient::new(PubSubConfig::from_env(), sdm.clone());
+    
+    // Track dishwasher state for every monitored camera
+    let dishwashers = DishwasherTracker::new(StateMachineConfig::from_env());
+    dishwasher::tracker::start_timeout_checks(
+        dishwashers.clone(),
+        std::time::Duration::from_secs(30),
+    ).await;
+    
+    // Remember which events were already handled so they only run once
+    let dedup_capacity = env::var("DEDUP_CAPACITY")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(1000);
+    let dedup = EventDeduplicator::load(Arc::clone(&repository), dedup_capacity).await;
+    
+    // Send notifications through every configured channel
+    // The mailer also sends account emails from the web server
+    let mut notifiers: Vec<Arc<dyn Notifier>> = Vec::new();
+    let mailer = SmtpConfig::from_env().and_then(|smtp_config| match SmtpNotifier::new(&smtp_config) {
+        Ok(notifier) => Some(Arc::new(notifier)),
+        Err(e) => {
+            log::error!("Failed to set up email: {}", e);
+            None
+        }
+    });
+    if let Some(mailer) = &mailer {
+        notifiers.push(mailer.clone());
+    }
+    if let Some(mqtt_config) = MqttConfig::from_env() {
+        notifiers.push(Arc::new(MqttPublisher::connect(mqtt_config, users.clone())));
+    }
+    match WebhookNotifier::new(WebhookSettings::from_env()) {
+        Ok(notifier) => notifiers.push(Arc::new(notifier)),
+        Err(e) => log::error!("Failed to set up webhook notifications: {}", e),
+    }
+    let notifications = NotificationDispatcher::new(users.clone(), notifiers);
+    notifications::start_transition_notifications(
+        notifications.clone(),
+        dishwashers.subscribe(),
+    ).await;
+    
+    // Handlers run for each new event
+    let mut handlers = HandlerRegistry::default();
+    handlers.register(Arc::new(EventTypeFilter::from_env()));
+    handlers.register(Arc::new(LoggingHandler));
+    handlers.register(Arc::new(notifications.clone()));
+    let events = EventProcessor::new(dedup, Arc::new(tokio::sync::RwLock::new(handlers)));
+    
+    // The supervisor owns every monitor task
+    let supervisor = Supervisor::new(MonitorContext {
+        users: users.clone(),
+        oauth_config: oauth_config.clone(),
+        events: events.clone(),
+        pubsub: pubsub.clone(),
+        sdm: sdm.clone(),
+        notifications,
+        dishwashers: dishwashers.clone(),
+    });
+    
     // Handle web API if the feature is enabled
     #[cfg(feature = "web-api")]
-    {
+    let web_server = {
         log::info!("Starting web server for authentication");
-        let users_clone = Arc::clone(&users);
-        let oauth_config_clone = oauth_config.clone();
+        // Sessions only need the Secure flag when the app is served over HTTPS
+        let sessions = api::session::Sessions::from_env(oauth_config.redirect_uri.starts_with("https://"))
+            .map_err(|e| e.to_string())?;
+        
+        // Create app state for the web server
+        let app_state = api::handlers::auth_handlers::AppState {
+            users: users.clone(),
+            id_tokens: auth::jwks::JwksVerifier::new(&sdm, &oauth_config.jwks_uri),
+            oauth_config: oauth_config.clone(),
+            pending_logins: auth::pending::PendingLogins::new(auth::pending::PENDING_LOGIN_TTL),
+            dishwashers: dishwashers.clone(),
+            events: events.clone(),
+            push_auth: api::event_routes::PushAuth::from_env(&sdm),
+            sdm: sdm.clone(),
+            sessions,
+            mailer,
+            public_url,
+            default_project_id,
+            supervisor: supervisor.clone(),
+        };
         
         tokio::spawn(async move {
-            if let Err(e) = start_web_server(users_clone, oauth_config_clone).await {
+            if let Err(e) = start_web_server(app_state).await {
                 log::error!("Web server error: {}", e);
             }
-        });
+        })
+    };
+    
+    // Start monitoring tasks and register handlers for any existing users
+    let existing_users: Vec<String> = users.lock().await.keys().cloned().collect();
+    for user_id in existing_users {
+        supervisor.sync_monitor(&user_id).await;
     }
     
-    // In a real app, you'd load existing users from a database
-    // For now we'll just monitor the empty user list
+    log::info!("Monitoring service running. Press Ctrl+C to exit.");
+    supervisor::wait_for_shutdown_signal().await;
     
-    // Start monitoring tasks for any existing users
-    let mut handles = Vec::new();
+    log::info!("Shutting down");
+    supervisor.shutdown(Duration::from_secs(10)).await;
     
-    {
-        let users_lock = users.lock().await;
-        for (_, config) in users_lock.iter() {
-            let user_config = config.clone();
-            let users_clone = Arc::clone(&users);
-            let oauth_config_clone = oauth_config.clone();
-            
-            let handle = tokio::spawn(async move {
-                monitor_user_cameras(
-                    user_config,
-                    users_clone,
-                    oauth_config_clone,
-                )
-                .await;
-            });
-            
-            handles.push(handle);
-        }
+    #[cfg(feature = "web-api")]
+    if tokio::time::timeout(Duration::from_secs(10), web_server).await.is_err() {
+        log::warn!("Web server did not stop within 10 seconds");
     }
     
-    // Keep the main task running indefinitely
-    log::info!("Monitoring service running. Press Ctrl+C to exit.");
-    
-    // Wait for all tasks to complete (they won't in a real app)
-    // In a real app, you'd want to implement a proper shutdown mechanism
-    for handle in handles {
-        handle.await?;
+    // Don't lose changes made since the last save
+    log::info!("Saving user data");
+    if let Err(e) = users.save(repository.as_ref()).await {
+        log::error!("Failed to save user data: {}", e);
     }
     
-    // This point will never be reached in a real app
-    // as the program should run continuously
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn user(user_id: &str, subscription: Option<&str>, needs_reauth: bool) -> (String, UserConfig) {
+        let config = serde_json::from_value(serde_json::json!({
+            "user_id": user_id,
+            "device_ids": [format!("{}-camera", user_id)],
+            "project_id": "project-1",
+            "pubsub_subscription": subscription,
+            "needs_reauth": needs_reauth,
+        }))
+        .unwrap();
+        (user_id.to_string(), config)
+    }
+
+    #[tokio::test]
+    async fn shared_subscriptions_reach_every_monitored_user() {
+        let users = UserStore::new(HashMap::from([
+            user("alice", None, false),
+            user("bob", None, false),
+            user("carol", Some("projects/p/subscriptions/carol"), false),
+            user("dave", None, true),
+            ("erin".to_string(), UserConfig { project_id: None, ..user("erin", None, false).1 }),
+        ]));
+
+        // Dave needs to sign in again and Erin hasn't chosen a project
+        let default = Some("projects/p/subscriptions/shared");
+
+        let mut shared = subscribers(&users, "projects/p/subscriptions/shared", default).await;
+        shared.sort();
+        assert_eq!(
+            shared,
+            vec![
+                ("alice".to_string(), "project-1".to_string(), vec!["alice-camera".to_string()]),
+                ("bob".to_string(), "project-1".to_string(), vec!["bob-camera".to_string()]),
+            ]
+        );
+
+        let own = subscribers(&users, "projects/p/subscriptions/carol", default).await;
+        assert_eq!(own.len(), 1);
+        assert_eq!(own[0].0, "carol");
+    }
+
+    #[tokio::test]
+    async fn users_from_before_google_sign_in_are_paused() {
+        let legacy_id = uuid::Uuid::new_v4().to_string();
+        let local_id = uuid::Uuid::new_v4().to_string();
+        let (_, mut local) = user(&local_id, None, false);
+        local.account = serde_json::from_value(serde_json::json!({
+            "email": "alice@example.com",
+            "password_hash": "hash",
+        }))
+        .unwrap();
+        let users = UserStore::new(HashMap::from([
+            user(&legacy_id, None, false),
+            user("1234567890", None, false),
+            (local_id.clone(), local),
+        ]));
+
+        pause_legacy_users(&users).await;
+
+        let users_lock = users.lock().await;
+        assert!(users_lock[&legacy_id].needs_reauth);
+        assert!(!users_lock["1234567890"].needs_reauth);
+        assert!(!users_lock[&local_id].needs_reauth);
+    }
+
+    #[cfg(feature = "web-api")]
+    #[tokio::test]
+    async fn tokens_stored_without_an_expiry_are_refreshed() {
+        let dir = std::env::temp_dir().join(format!("dishwashmon-main-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("users.json");
+        std::fs::write(
+            &file,
+            serde_json::json!({
+                "users": {
+                    "alice": {
+                        "user_id": "alice",
+                        "device_ids": ["camera-1"],
+                        "token": {
+                            "access_token": "old-access",
+                            "expires_in": 3600,
+                            "token_type": "Bearer",
+                            "refresh_token": "stored-refresh",
+                        },
+                    },
+                },
+            })
+            .to_string(),
+        )
+        .unwrap();
+
+        let users = UserStore::new(storage::load_user_data(file.to_str().unwrap(), 0, None).await.unwrap());
+        let token = users.lock().await["alice"].token.clone().unwrap();
+        assert!(token.is_expired());
+
+        let oauth_config = auth::oauth::tests::token_endpoint().await;
+        let sdm = SdmClient::new(SdmClientConfig::default());
+        let notifications = NotificationDispatcher::new(users.clone(), Vec::new());
+        assert!(refresh_user_token("alice", &token, &users, &sdm, &oauth_config, &notifications).await);
+
+        let refreshed = users.lock().await["alice"].token.clone().unwrap();
+        assert_eq!(refreshed.access_token, "new-access");
+        assert!(!refreshed.is_expired());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,282 @@
+use async_trait::async_trait;
+use lettre::{
+    message::header::ContentType, transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use std::env;
+
+use crate::auth::models::UserConfig;
+use crate::dishwasher::state_machine::DishwasherState;
+use crate::notifications::{Notification, NotificationError, Notifier};
+
+/// How the connection to the SMTP server is secured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmtpTls {
+    /// Plain connection upgraded with STARTTLS
+    StartTls,
+    /// TLS from the start of the connection
+    Tls,
+    /// Unencrypted, only suitable for local mail sinks
+    None,
+}
+
+#[derive(Debug, Clone)]
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: u16,
+    pub tls: SmtpTls,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+}
+
+impl SmtpConfig {
+    /// Read SMTP settings from the environment. Returns None if `SMTP_HOST` is not set.
+    pub fn from_env() -> Option<Self> {
+        let host = env::var("SMTP_HOST").ok().filter(|v| !v.is_empty())?;
+
+        let tls = match env::var("SMTP_TLS").unwrap_or_default().to_lowercase().as_str() {
+            "tls" => SmtpTls::Tls,
+            "none" => SmtpTls::None,
+            _ => SmtpTls::StartTls,
+        };
+
+        let default_port = match tls {
+            SmtpTls::StartTls => 587,
+            SmtpTls::Tls => 465,
+            SmtpTls::None => 25,
+        };
+
+        Some(Self {
+            host,
+            port: env::var("SMTP_PORT")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default_port),
+            tls,
+            username: env::var("SMTP_USERNAME").ok().filter(|v| !v.is_empty()),
+            password: env::var("SMTP_PASSWORD").ok().filter(|v| !v.is_empty()),
+            from: env::var("SMTP_FROM")
+                .unwrap_or_else(|_| "Dishwasher Monitor <dishwashmon@localhost>".to_string()),
+        })
+    }
+}
+
+/// Sends notifications by email to each user's configured recipients
+pub struct SmtpNotifier {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: String,
+}
+
+impl SmtpNotifier {
+    pub fn new(config: &SmtpConfig) -> Result<Self, NotificationError> {
+        let builder = match config.tls {
+            SmtpTls::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host),
+            SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host),
+            SmtpTls::None => Ok(AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(
+                &config.host,
+            )),
+        }
+        .map_err(|e| NotificationError::Email(e.to_string()))?;
+
+        let mut builder = builder.port(config.port);
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        Ok(Self {
+            transport: builder.build(),
+            from: config.from.clone(),
+        })
+    }
+
+    /// Send a plain text email
+    pub async fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), NotificationError> {
+        let message = Message::builder()
+            .from(
+                self.from
+                    .parse()
+                    .map_err(|_| NotificationError::Address(self.from.clone()))?,
+            )
+            .to(to
+                .parse()
+                .map_err(|_| NotificationError::Address(to.to_string()))?)
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(body.to_string())
+            .map_err(|e| NotificationError::Email(e.to_string()))?;
+
+        self.transport
+            .send(message)
+            .await
+            .map_err(|e| NotificationError::Email(e.to_string()))?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Notifier for SmtpNotifier {
+    fn name(&self) -> &str {
+        "email"
+    }
+
+    async fn notify(
+        &self,
+        user: &UserConfig,
+        notification: &Notification,
+    ) -> Result<(), NotificationError> {
+        let settings = &user.notifications;
+        let wanted = match notification {
+            Notification::DishwasherStateChanged(transition) => {
+                settings.notify_on_finished && transition.to == DishwasherState::Finished
+            }
+            Notification::PersonDetected { .. } => settings.notify_on_person,
+            Notification::CameraEvent { .. } => false,
+            // Monitoring has stopped, so always let the user know
+            Notification::ReauthRequired { .. } => true,
+        };
+        if !wanted {
+            return Ok(());
+        }
+
+        let subject = notification.subject();
+        let body = notification.body();
+        for recipient in &settings.email_recipients {
+            self.send(recipient, &subject, &body).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dishwasher::tracker::Transition;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    // A message accepted by the test SMTP server
+    #[derive(Debug, Default, Clone)]
+    struct Received {
+        recipients: Vec<String>,
+        data: String,
+    }
+
+    // Accept mail on a local port, just enough SMTP for lettre to deliver
+    async fn smtp_sink() -> (u16, Arc<Mutex<Vec<Received>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let received = Arc::new(Mutex::new(Vec::new()));
+
+        let sink = Arc::clone(&received);
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let sink = Arc::clone(&sink);
+                tokio::spawn(async move {
+                    let (reader, mut writer) = stream.into_split();
+                    let mut lines = BufReader::new(reader).lines();
+                    let mut message = Received::default();
+                    writer.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        let command = line.to_uppercase();
+                        let reply: &[u8] = if command.starts_with("EHLO") || command.starts_with("HELO") {
+                            b"250 localhost\r\n"
+                        } else if command.starts_with("RCPT TO:") {
+                            message.recipients.push(line[8..].trim_matches(|c| c == '<' || c == '>').to_string());
+                            b"250 OK\r\n"
+                        } else if command == "DATA" {
+                            writer.write_all(b"354 Go ahead\r\n").await.unwrap();
+                            while let Ok(Some(line)) = lines.next_line().await {
+                                if line == "." {
+                                    break;
+                                }
+                                message.data.push_str(&line);
+                                message.data.push('\n');
+                            }
+                            sink.lock().unwrap().push(std::mem::take(&mut message));
+                            b"250 OK\r\n"
+                        } else if command == "QUIT" {
+                            writer.write_all(b"221 Bye\r\n").await.unwrap();
+                            break;
+                        } else {
+                            b"250 OK\r\n"
+                        };
+                        writer.write_all(reply).await.unwrap();
+                    }
+                });
+            }
+        });
+
+        (port, received)
+    }
+
+    fn user(notify_on_finished: bool, notify_on_person: bool) -> UserConfig {
+        serde_json::from_value(serde_json::json!({
+            "user_id": "alice",
+            "device_ids": ["camera-1"],
+            "notifications": {
+                "email_recipients": ["alice@example.com", "bob@example.com"],
+                "notify_on_finished": notify_on_finished,
+                "notify_on_person": notify_on_person,
+            },
+        }))
+        .unwrap()
+    }
+
+    fn changed_to(to: DishwasherState) -> Notification {
+        Notification::DishwasherStateChanged(Transition {
+            user_id: "alice".to_string(),
+            device_id: "camera-1".to_string(),
+            from: DishwasherState::Running,
+            to,
+            at: chrono::Utc::now(),
+            cause: "timeout".to_string(),
+        })
+    }
+
+    fn person() -> Notification {
+        Notification::PersonDetected {
+            user_id: "alice".to_string(),
+            device_id: "camera-1".to_string(),
+            timestamp: "2025-01-01T12:00:00Z".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn notifications_are_mailed_to_wanting_recipients() {
+        let (port, received) = smtp_sink().await;
+        let notifier = SmtpNotifier::new(&SmtpConfig {
+            host: "127.0.0.1".to_string(),
+            port,
+            tls: SmtpTls::None,
+            username: None,
+            password: None,
+            from: "Dishwasher Monitor <dishwashmon@localhost>".to_string(),
+        })
+        .unwrap();
+
+        notifier.notify(&user(true, false), &changed_to(DishwasherState::Finished)).await.unwrap();
+        {
+            let received = received.lock().unwrap();
+            assert_eq!(received.len(), 2);
+            assert_eq!(received[0].recipients, ["alice@example.com"]);
+            assert_eq!(received[1].recipients, ["bob@example.com"]);
+            assert!(received[0].data.contains("Subject: Your dishes are clean"));
+        }
+
+        // Only finished dishes and, if asked for, people are mailed
+        notifier.notify(&user(true, false), &changed_to(DishwasherState::Unloaded)).await.unwrap();
+        notifier.notify(&user(false, false), &changed_to(DishwasherState::Finished)).await.unwrap();
+        notifier.notify(&user(true, false), &person()).await.unwrap();
+        assert_eq!(received.lock().unwrap().len(), 2);
+
+        notifier.notify(&user(false, true), &person()).await.unwrap();
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 4);
+        assert!(received[2].data.contains("Subject: Someone is at the dishwasher"));
+    }
+}
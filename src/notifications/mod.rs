@@ -0,0 +1,232 @@
+use async_trait::async_trait;
+use serde::Serialize;
+use std::error::Error;
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::sync::broadcast;
+
+use crate::auth::models::{UserConfig, UserStore};
+use crate::dishwasher::{state_machine::DishwasherState, tracker::Transition};
+use crate::events::{
+    handlers::{EventHandler, HandlerFlow},
+    CameraEvent,
+};
+
+pub mod email;
+pub mod mqtt;
+pub mod webhook;
+
+#[derive(Error, Debug)]
+pub enum NotificationError {
+    #[error("Invalid address {0}")]
+    Address(String),
+
+    #[error("Email error: {0}")]
+    Email(String),
+
+    #[error("Webhook error: {0}")]
+    Webhook(String),
+
+    #[error("MQTT error: {0}")]
+    Mqtt(String),
+}
+
+/// Something a user may want to be told about
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Notification {
+    DishwasherStateChanged(Transition),
+    PersonDetected {
+        user_id: String,
+        device_id: String,
+        timestamp: String,
+    },
+    CameraEvent {
+        user_id: String,
+        event: CameraEvent,
+    },
+    /// Google revoked the user's authorization and monitoring has stopped
+    ReauthRequired {
+        user_id: String,
+    },
+}
+
+impl Notification {
+    pub fn user_id(&self) -> &str {
+        match self {
+            Notification::DishwasherStateChanged(transition) => &transition.user_id,
+            Notification::PersonDetected { user_id, .. } => user_id,
+            Notification::CameraEvent { user_id, .. } => user_id,
+            Notification::ReauthRequired { user_id } => user_id,
+        }
+    }
+
+    pub fn subject(&self) -> String {
+        match self {
+            Notification::DishwasherStateChanged(transition) => match transition.to {
+                DishwasherState::Finished => "Your dishes are clean".to_string(),
+                state => format!("Dishwasher is now {}", state),
+            },
+            Notification::PersonDetected { .. } => "Someone is at the dishwasher".to_string(),
+            Notification::CameraEvent { event, .. } => {
+                format!("Camera {} reported {}", event.device_id, event.event_type)
+            }
+            Notification::ReauthRequired { .. } => "Reconnect your Google account".to_string(),
+        }
+    }
+
+    pub fn body(&self) -> String {
+        match self {
+            Notification::DishwasherStateChanged(transition) => format!(
+                "The dishwasher watched by camera {} changed from {} to {} at {}.",
+                transition.device_id,
+                transition.from,
+                transition.to,
+                transition.at.to_rfc3339()
+            ),
+            Notification::PersonDetected {
+                device_id,
+                timestamp,
+                ..
+            } => format!(
+                "Camera {} saw a person near the dishwasher at {}.",
+                device_id, timestamp
+            ),
+            Notification::CameraEvent { event, .. } => format!(
+                "Camera {} reported a {} event at {}.",
+                event.device_id, event.event_type, event.timestamp
+            ),
+            Notification::ReauthRequired { .. } => "Google no longer accepts Dishwasher Monitor's access \
+                 to your cameras, so monitoring has stopped. Sign in again from your dashboard to resume."
+                .to_string(),
+        }
+    }
+}
+
+/// Delivers notifications over some channel
+#[async_trait]
+pub trait Notifier: Send + Sync {
+    /// Name used in logs
+    fn name(&self) -> &str;
+
+    async fn notify(
+        &self,
+        user: &UserConfig,
+        notification: &Notification,
+    ) -> Result<(), NotificationError>;
+}
+
+/// Sends each notification through every configured notifier
+#[derive(Clone)]
+pub struct NotificationDispatcher {
+    users: UserStore,
+    notifiers: Arc<Vec<Arc<dyn Notifier>>>,
+}
+
+impl NotificationDispatcher {
+    pub fn new(users: UserStore, notifiers: Vec<Arc<dyn Notifier>>) -> Self {
+        Self {
+            users,
+            notifiers: Arc::new(notifiers),
+        }
+    }
+
+    pub async fn dispatch(&self, notification: &Notification) {
+        let user = {
+            let users_lock = self.users.lock().await;
+            users_lock.get(notification.user_id()).cloned()
+        };
+
+        let user = match user {
+            Some(user) => user,
+            None => {
+                log::warn!(
+                    "Dropping notification for unknown user {}",
+                    notification.user_id()
+                );
+                return;
+            }
+        };
+
+        for notifier in self.notifiers.iter() {
+            if let Err(e) = notifier.notify(&user, notification).await {
+                log::error!(
+                    "{} notification for user {} failed: {}",
+                    notifier.name(),
+                    user.user_id,
+                    e
+                );
+            }
+        }
+    }
+}
+
+/// Notify users about every dishwasher state change
+pub async fn start_transition_notifications(
+    dispatcher: NotificationDispatcher,
+    mut transitions: broadcast::Receiver<Transition>,
+) {
+    tokio::spawn(async move {
+        loop {
+            match transitions.recv().await {
+                Ok(transition) => {
+                    dispatcher
+                        .dispatch(&Notification::DishwasherStateChanged(transition))
+                        .await;
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    log::warn!("Notifications fell behind, skipped {} transitions", skipped);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+#[async_trait]
+impl EventHandler for NotificationDispatcher {
+    fn name(&self) -> &str {
+        "notifications"
+    }
+
+    async fn handle(
+        &self,
+        user_id: &str,
+        event: &CameraEvent,
+    ) -> Result<HandlerFlow, Box<dyn Error + Send + Sync>> {
+        self.dispatch(&Notification::CameraEvent {
+            user_id: user_id.to_string(),
+            event: event.clone(),
+        })
+        .await;
+        Ok(HandlerFlow::Continue)
+    }
+}
+
+/// Tells a user when someone is seen by one of their cameras. Registered for
+/// each user who asked for person notifications.
+pub struct PersonAlerts(pub NotificationDispatcher);
+
+#[async_trait]
+impl EventHandler for PersonAlerts {
+    fn name(&self) -> &str {
+        "person-alerts"
+    }
+
+    async fn handle(
+        &self,
+        user_id: &str,
+        event: &CameraEvent,
+    ) -> Result<HandlerFlow, Box<dyn Error + Send + Sync>> {
+        if event.event_type == "person" {
+            self.0
+                .dispatch(&Notification::PersonDetected {
+                    user_id: user_id.to_string(),
+                    device_id: event.device_id.clone(),
+                    timestamp: event.timestamp.clone(),
+                })
+                .await;
+        }
+        Ok(HandlerFlow::Continue)
+    }
+}
@@ -0,0 +1,318 @@
+use async_trait::async_trait;
+use rumqttc::{AsyncClient, Event, LastWill, MqttOptions, Packet, QoS};
+use serde_json::json;
+use std::collections::HashSet;
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+use crate::auth::models::{UserConfig, UserStore};
+use crate::notifications::{Notification, NotificationError, Notifier};
+
+#[derive(Debug, Clone)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub client_id: String,
+    pub topic_prefix: String,
+    pub discovery_prefix: String,
+}
+
+impl MqttConfig {
+    /// Read MQTT settings from the environment. Returns None if `MQTT_HOST` is not set.
+    pub fn from_env() -> Option<Self> {
+        let host = env::var("MQTT_HOST").ok().filter(|v| !v.is_empty())?;
+
+        Some(Self {
+            host,
+            port: env::var("MQTT_PORT")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(1883),
+            username: env::var("MQTT_USERNAME").ok().filter(|v| !v.is_empty()),
+            password: env::var("MQTT_PASSWORD").ok().filter(|v| !v.is_empty()),
+            client_id: env::var("MQTT_CLIENT_ID")
+                .ok()
+                .filter(|v| !v.is_empty())
+                .unwrap_or_else(|| "dishwashmon".to_string()),
+            topic_prefix: env::var("MQTT_TOPIC_PREFIX")
+                .ok()
+                .filter(|v| !v.is_empty())
+                .unwrap_or_else(|| "dishwashmon".to_string()),
+            discovery_prefix: env::var("MQTT_DISCOVERY_PREFIX")
+                .ok()
+                .filter(|v| !v.is_empty())
+                .unwrap_or_else(|| "homeassistant".to_string()),
+        })
+    }
+}
+
+// Device IDs end up in topics and Home Assistant object IDs, which only
+// allow a limited set of characters
+fn object_id(device_id: &str) -> String {
+    device_id
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' })
+        .collect()
+}
+
+/// Publishes dishwasher state and camera events to an MQTT broker, along
+/// with Home Assistant discovery config for every monitored camera
+#[derive(Clone)]
+pub struct MqttPublisher {
+    client: AsyncClient,
+    config: MqttConfig,
+    // Devices whose discovery config was published on the current connection
+    announced: Arc<Mutex<HashSet<String>>>,
+}
+
+impl MqttPublisher {
+    fn new(client: AsyncClient, config: MqttConfig) -> Self {
+        Self {
+            client,
+            config,
+            announced: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    /// Connect to the broker. The connection is driven by a background task
+    /// that reconnects as needed and announces all users' cameras each time
+    /// it connects.
+    pub fn connect(config: MqttConfig, users: UserStore) -> Self {
+        let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            options.set_credentials(username, password);
+        }
+        options.set_last_will(LastWill::new(
+            availability_topic(&config),
+            "offline",
+            QoS::AtLeastOnce,
+            true,
+        ));
+
+        let (client, mut eventloop) = AsyncClient::new(options, 100);
+        let publisher = Self::new(client, config);
+
+        let connection = publisher.clone();
+        tokio::spawn(async move {
+            loop {
+                match eventloop.poll().await {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        log::info!("Connected to MQTT broker {}", connection.config.host);
+                        // The publish queue is drained by this loop, so announce
+                        // from a separate task
+                        let connection = connection.clone();
+                        let users = users.clone();
+                        tokio::spawn(async move {
+                            connection.on_connected(&users).await;
+                        });
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        log::warn!("MQTT connection error: {}, reconnecting", e);
+                        tokio::time::sleep(Duration::from_secs(5)).await;
+                    }
+                }
+            }
+        });
+
+        publisher
+    }
+
+    async fn on_connected(&self, users: &UserStore) {
+        // The broker may have lost retained discovery config, so send it again
+        self.announced.lock().await.clear();
+
+        if let Err(e) = self.publish(&availability_topic(&self.config), "online", true).await {
+            log::error!("Failed to publish MQTT availability: {}", e);
+        }
+
+        let device_ids: Vec<String> = {
+            let users_lock = users.lock().await;
+            users_lock
+                .values()
+                .flat_map(|user| user.device_ids.iter().cloned())
+                .collect()
+        };
+        for device_id in device_ids {
+            if let Err(e) = self.announce(&device_id).await {
+                log::error!("Failed to publish MQTT discovery for {}: {}", device_id, e);
+            }
+        }
+    }
+
+    fn device_topic(&self, device_id: &str, name: &str) -> String {
+        format!("{}/{}/{}", self.config.topic_prefix, object_id(device_id), name)
+    }
+
+    async fn publish(&self, topic: &str, payload: &str, retain: bool) -> Result<(), NotificationError> {
+        self.client
+            .publish(topic, QoS::AtLeastOnce, retain, payload.as_bytes().to_vec())
+            .await
+            .map_err(|e| NotificationError::Mqtt(e.to_string()))
+    }
+
+    /// Publish Home Assistant discovery config for a camera's entities,
+    /// unless it was already published on this connection
+    async fn announce(&self, device_id: &str) -> Result<(), NotificationError> {
+        if self.announced.lock().await.contains(device_id) {
+            return Ok(());
+        }
+
+        let object_id = object_id(device_id);
+        let device = json!({
+            "identifiers": [format!("dishwashmon_{}", object_id)],
+            "name": format!("Dishwasher ({})", &object_id[object_id.len().saturating_sub(6)..]),
+            "manufacturer": "dishwashmon",
+            "model": "Nest camera",
+        });
+        let availability = availability_topic(&self.config);
+
+        let entities = [
+            (
+                "sensor",
+                "state",
+                json!({
+                    "name": "Dishwasher state",
+                    "state_topic": self.device_topic(device_id, "state"),
+                    "icon": "mdi:dishwasher",
+                }),
+            ),
+            (
+                "sensor",
+                "last_event",
+                json!({
+                    "name": "Last camera event",
+                    "state_topic": self.device_topic(device_id, "last_event"),
+                    "value_template": "{{ value_json.event_type }}",
+                    "json_attributes_topic": self.device_topic(device_id, "last_event"),
+                    "icon": "mdi:cctv",
+                }),
+            ),
+            (
+                "binary_sensor",
+                "person",
+                json!({
+                    "name": "Person at dishwasher",
+                    "state_topic": self.device_topic(device_id, "person"),
+                    "device_class": "occupancy",
+                    "off_delay": 60,
+                }),
+            ),
+        ];
+
+        for (component, entity, mut config) in entities {
+            config["unique_id"] = json!(format!("dishwashmon_{}_{}", object_id, entity));
+            config["object_id"] = json!(format!("dishwasher_{}_{}", object_id, entity));
+            config["availability_topic"] = json!(availability);
+            config["device"] = device.clone();
+
+            let topic = format!(
+                "{}/{}/dishwashmon_{}/{}/config",
+                self.config.discovery_prefix, component, object_id, entity
+            );
+            self.publish(&topic, &config.to_string(), true).await?;
+        }
+
+        // Only once it was all sent, so a failed announcement is tried again
+        self.announced.lock().await.insert(device_id.to_string());
+        Ok(())
+    }
+}
+
+fn availability_topic(config: &MqttConfig) -> String {
+    format!("{}/status", config.topic_prefix)
+}
+
+#[async_trait]
+impl Notifier for MqttPublisher {
+    fn name(&self) -> &str {
+        "mqtt"
+    }
+
+    async fn notify(
+        &self,
+        _user: &UserConfig,
+        notification: &Notification,
+    ) -> Result<(), NotificationError> {
+        match notification {
+            Notification::DishwasherStateChanged(transition) => {
+                self.announce(&transition.device_id).await?;
+                self.publish(
+                    &self.device_topic(&transition.device_id, "state"),
+                    transition.to.as_str(),
+                    true,
+                )
+                .await
+            }
+            Notification::CameraEvent { event, .. } => {
+                self.announce(&event.device_id).await?;
+                let payload = serde_json::to_string(event)
+                    .map_err(|e| NotificationError::Mqtt(e.to_string()))?;
+                self.publish(&self.device_topic(&event.device_id, "last_event"), &payload, true)
+                    .await?;
+
+                if event.event_type == "person" {
+                    // Home Assistant switches the sensor back off after `off_delay`
+                    self.publish(&self.device_topic(&event.device_id, "person"), "ON", false)
+                        .await?;
+                }
+                Ok(())
+            }
+            // Covered by the camera event
+            Notification::PersonDetected { .. } => Ok(()),
+            // Only device state is published
+            Notification::ReauthRequired { .. } => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Discovery config is published for this many entities per camera
+    const ENTITIES: usize = 3;
+
+    fn config() -> MqttConfig {
+        MqttConfig {
+            host: "localhost".to_string(),
+            port: 1883,
+            username: None,
+            password: None,
+            client_id: "dishwashmon-test".to_string(),
+            topic_prefix: "dishwashmon".to_string(),
+            discovery_prefix: "homeassistant".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn cameras_are_announced_once_per_connection() {
+        // Nothing drains the queue, so publishing more than one camera's
+        // config would wait forever
+        let (client, _eventloop) = AsyncClient::new(MqttOptions::new("test", "localhost", 1883), ENTITIES);
+        let publisher = MqttPublisher::new(client, config());
+
+        publisher.announce("camera/1").await.unwrap();
+        assert!(publisher.announced.lock().await.contains("camera/1"));
+
+        let again = tokio::time::timeout(Duration::from_secs(1), publisher.announce("camera/1"));
+        assert!(again.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn failed_announcements_are_tried_again() {
+        // Without its event loop the client can't queue anything
+        let (client, eventloop) = AsyncClient::new(MqttOptions::new("test", "localhost", 1883), ENTITIES);
+        drop(eventloop);
+        let publisher = MqttPublisher::new(client, config());
+
+        assert!(publisher.announce("camera-1").await.is_err());
+        assert!(publisher.announce("camera-1").await.is_err());
+        assert!(publisher.announced.lock().await.is_empty());
+    }
+}
@@ -0,0 +1,320 @@
+use async_trait::async_trait;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use reqwest::{Client, StatusCode};
+use serde::Serialize;
+use sha2::Sha256;
+use std::env;
+use std::time::Duration;
+
+use crate::auth::models::{UserConfig, WebhookConfig};
+use crate::notifications::{Notification, NotificationError, Notifier};
+use crate::storage;
+
+pub const SIGNATURE_HEADER: &str = "X-Dishwashmon-Signature";
+pub const TIMESTAMP_HEADER: &str = "X-Dishwashmon-Timestamp";
+pub const DELIVERY_HEADER: &str = "X-Dishwashmon-Delivery";
+
+#[derive(Debug, Clone)]
+pub struct WebhookSettings {
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub timeout: Duration,
+    pub dead_letter_file: String,
+}
+
+impl WebhookSettings {
+    pub fn from_env() -> Self {
+        Self {
+            max_attempts: env::var("WEBHOOK_MAX_ATTEMPTS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(5),
+            initial_backoff: Duration::from_secs(1),
+            timeout: Duration::from_secs(10),
+            dead_letter_file: env::var("WEBHOOK_DEAD_LETTER_FILE")
+                .unwrap_or_else(|_| "data/webhook_dead_letters.jsonl".to_string()),
+        }
+    }
+}
+
+/// A delivery that could not be completed, kept for inspection or replay
+#[derive(Debug, Serialize)]
+struct DeadLetter<'a> {
+    failed_at: String,
+    delivery_id: &'a str,
+    user_id: &'a str,
+    webhook_id: &'a str,
+    url: &'a str,
+    attempts: u32,
+    error: String,
+    payload: serde_json::Value,
+}
+
+/// Sign `timestamp.body` with the webhook's secret, so receivers can reject
+/// replayed deliveries as well as forged ones
+pub fn sign_payload(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Posts notifications as signed JSON to each of a user's webhooks
+#[derive(Clone)]
+pub struct WebhookNotifier {
+    client: Client,
+    settings: WebhookSettings,
+}
+
+impl WebhookNotifier {
+    pub fn new(settings: WebhookSettings) -> Result<Self, NotificationError> {
+        let client = Client::builder()
+            .timeout(settings.timeout)
+            .build()
+            .map_err(|e| NotificationError::Webhook(e.to_string()))?;
+        Ok(Self { client, settings })
+    }
+
+    // Send once. Err carries whether the failure is worth retrying.
+    async fn send_once(
+        &self,
+        webhook: &WebhookConfig,
+        delivery_id: &str,
+        body: &str,
+    ) -> Result<(), (bool, String)> {
+        let timestamp = Utc::now().timestamp();
+        let response = self
+            .client
+            .post(&webhook.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(DELIVERY_HEADER, delivery_id)
+            .header(TIMESTAMP_HEADER, timestamp.to_string())
+            .header(SIGNATURE_HEADER, sign_payload(&webhook.secret, timestamp, body))
+            .body(body.to_string())
+            .send()
+            .await
+            .map_err(|e| (true, e.to_string()))?;
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+
+        let retryable = status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS;
+        Err((retryable, format!("HTTP {}", status)))
+    }
+
+    /// Deliver a payload, retrying with exponential backoff and recording
+    /// it in the dead-letter file if every attempt fails
+    pub async fn deliver(&self, user_id: &str, webhook: &WebhookConfig, payload: &serde_json::Value) {
+        let delivery_id = uuid::Uuid::new_v4().to_string();
+        let body = payload.to_string();
+        let mut backoff = self.settings.initial_backoff;
+        let mut attempts = 0;
+
+        let error = loop {
+            attempts += 1;
+            match self.send_once(webhook, &delivery_id, &body).await {
+                Ok(()) => return,
+                Err((retryable, error)) => {
+                    if !retryable || attempts >= self.settings.max_attempts {
+                        break error;
+                    }
+                    log::warn!(
+                        "Webhook {} delivery {} failed (attempt {}): {}, retrying in {:?}",
+                        webhook.id, delivery_id, attempts, error, backoff
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+            }
+        };
+
+        log::error!(
+            "Giving up on webhook {} delivery {} to {} after {} attempts: {}",
+            webhook.id, delivery_id, webhook.url, attempts, error
+        );
+
+        let dead_letter = DeadLetter {
+            failed_at: Utc::now().to_rfc3339(),
+            delivery_id: &delivery_id,
+            user_id,
+            webhook_id: &webhook.id,
+            url: &webhook.url,
+            attempts,
+            error,
+            payload: payload.clone(),
+        };
+        if let Err(e) = storage::append_dead_letter(&self.settings.dead_letter_file, &dead_letter).await {
+            log::error!("Failed to record dead letter {}: {}", delivery_id, e);
+        }
+    }
+}
+
+#[async_trait]
+impl Notifier for WebhookNotifier {
+    fn name(&self) -> &str {
+        "webhook"
+    }
+
+    async fn notify(
+        &self,
+        user: &UserConfig,
+        notification: &Notification,
+    ) -> Result<(), NotificationError> {
+        // Webhooks receive the raw camera event instead
+        if let Notification::PersonDetected { .. } = notification {
+            return Ok(());
+        }
+        if user.webhooks.is_empty() {
+            return Ok(());
+        }
+
+        let payload = serde_json::to_value(notification)
+            .map_err(|e| NotificationError::Webhook(e.to_string()))?;
+
+        // Retries can take a while, so don't hold up other notifiers
+        for webhook in user.webhooks.clone() {
+            let notifier = self.clone();
+            let user_id = user.user_id.clone();
+            let payload = payload.clone();
+            tokio::spawn(async move {
+                notifier.deliver(&user_id, &webhook, &payload).await;
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "web-api"))]
+mod tests {
+    use super::*;
+    use axum::{http::HeaderMap, routing::post, Router};
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+
+    // A delivery as the receiver saw it
+    struct Received {
+        headers: HeaderMap,
+        body: String,
+    }
+
+    // Receive deliveries locally, answering with `statuses` in turn and
+    // 200 once they run out
+    async fn receiver(statuses: Vec<u16>) -> (String, Arc<Mutex<Vec<Received>>>) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&received);
+        let statuses = Arc::new(Mutex::new(statuses.into_iter()));
+        let app = Router::new().route(
+            "/hook",
+            post(move |headers: HeaderMap, body: String| async move {
+                log.lock().unwrap().push(Received { headers, body });
+                let status = statuses.lock().unwrap().next().unwrap_or(200);
+                axum::http::StatusCode::from_u16(status).unwrap()
+            }),
+        );
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(app.into_make_service());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        (format!("http://{}/hook", addr), received)
+    }
+
+    fn notifier(max_attempts: u32, dead_letter_file: &std::path::Path) -> WebhookNotifier {
+        WebhookNotifier::new(WebhookSettings {
+            max_attempts,
+            initial_backoff: Duration::from_millis(10),
+            timeout: Duration::from_secs(5),
+            dead_letter_file: dead_letter_file.to_string_lossy().into_owned(),
+        })
+        .unwrap()
+    }
+
+    fn webhook(url: String) -> WebhookConfig {
+        WebhookConfig {
+            id: "webhook-1".to_string(),
+            url,
+            secret: "webhook-secret".to_string(),
+        }
+    }
+
+    fn dead_letter_file() -> std::path::PathBuf {
+        std::env::temp_dir()
+            .join(format!("dishwashmon-webhook-{}", uuid::Uuid::new_v4()))
+            .join("dead_letters.jsonl")
+    }
+
+    #[tokio::test]
+    async fn deliveries_are_signed() {
+        let (url, received) = receiver(vec![]).await;
+        let payload = serde_json::json!({"state": "clean"});
+
+        notifier(3, &dead_letter_file()).deliver("alice", &webhook(url), &payload).await;
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        let headers = &received[0].headers;
+        let timestamp: i64 = headers[TIMESTAMP_HEADER].to_str().unwrap().parse().unwrap();
+        assert_eq!(received[0].body, payload.to_string());
+        assert_eq!(
+            headers[SIGNATURE_HEADER].to_str().unwrap(),
+            sign_payload("webhook-secret", timestamp, &received[0].body)
+        );
+        assert!(!headers[DELIVERY_HEADER].is_empty());
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried_as_the_same_delivery() {
+        let (url, received) = receiver(vec![500]).await;
+        let dead_letters = dead_letter_file();
+
+        notifier(3, &dead_letters).deliver("alice", &webhook(url), &serde_json::json!({})).await;
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0].headers[DELIVERY_HEADER], received[1].headers[DELIVERY_HEADER]);
+        assert!(!dead_letters.exists());
+    }
+
+    #[tokio::test]
+    async fn deliveries_are_dead_lettered_once_retries_run_out() {
+        let (url, received) = receiver(vec![500, 503, 502, 500]).await;
+        let dead_letters = dead_letter_file();
+        let payload = serde_json::json!({"state": "clean"});
+
+        notifier(3, &dead_letters).deliver("alice", &webhook(url.clone()), &payload).await;
+        assert_eq!(received.lock().unwrap().len(), 3);
+
+        let contents = std::fs::read_to_string(&dead_letters).unwrap();
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["user_id"], "alice");
+        assert_eq!(lines[0]["webhook_id"], "webhook-1");
+        assert_eq!(lines[0]["url"], url);
+        assert_eq!(lines[0]["attempts"], 3);
+        assert_eq!(lines[0]["error"], "HTTP 502 Bad Gateway");
+        assert_eq!(lines[0]["payload"], payload);
+
+        std::fs::remove_dir_all(dead_letters.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_not_retried() {
+        let (url, received) = receiver(vec![404]).await;
+        let dead_letters = dead_letter_file();
+
+        notifier(3, &dead_letters).deliver("alice", &webhook(url), &serde_json::json!({})).await;
+
+        assert_eq!(received.lock().unwrap().len(), 1);
+        assert!(std::fs::read_to_string(&dead_letters).unwrap().contains("HTTP 404 Not Found"));
+
+        std::fs::remove_dir_all(dead_letters.parent().unwrap()).unwrap();
+    }
+}
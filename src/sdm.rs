@@ -0,0 +1,429 @@
+use rand::Rng;
+use reqwest::{header, Client, RequestBuilder, Response, StatusCode};
+use serde::Deserialize;
+use std::env;
+use std::time::Duration;
+use thiserror::Error;
+
+// Longest `Retry-After` we wait out; beyond this the response is returned to
+// the caller, who will try again on its next poll anyway
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// Failure of a call to the SDM or Pub/Sub API, classified so callers can
+/// react to each case
+#[derive(Error, Debug)]
+pub enum SdmError {
+    /// The access token is missing, invalid or expired
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+
+    #[error("Rate limited by the API")]
+    RateLimited { retry_after: Option<Duration> },
+
+    #[error("Not found: {0}")]
+    NotFound(String),
+
+    /// The user hasn't granted access, or the project isn't allowed to use the API
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+
+    #[error("API error ({status}): {message}")]
+    Api { status: u16, message: String },
+
+    #[error("Network error: {0}")]
+    Transport(reqwest::Error),
+
+    #[error("Failed to decode response: {0}")]
+    Decode(String),
+}
+
+impl From<reqwest::Error> for SdmError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_decode() {
+            SdmError::Decode(e.to_string())
+        } else {
+            SdmError::Transport(e)
+        }
+    }
+}
+
+// Google APIs report errors as {"error": {"code": 404, "message": "...", "status": "NOT_FOUND"}}
+#[derive(Debug, Deserialize)]
+struct ErrorResponse {
+    error: ErrorDetails,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorDetails {
+    #[serde(default)]
+    message: String,
+    #[serde(default)]
+    status: String,
+}
+
+impl SdmError {
+    /// Classify an unsuccessful response
+    pub async fn from_response(response: Response) -> Self {
+        let status = response.status();
+        let retry_after = retry_after(&response);
+        let body = match response.text().await {
+            Ok(body) => body,
+            Err(e) => return e.into(),
+        };
+
+        let (message, google_status) = match serde_json::from_str::<ErrorResponse>(&body) {
+            Ok(ErrorResponse { error }) => (error.message, error.status),
+            Err(_) => (body, String::new()),
+        };
+
+        match (status, google_status.as_str()) {
+            (StatusCode::UNAUTHORIZED, _) | (_, "UNAUTHENTICATED") => SdmError::Unauthorized(message),
+            (StatusCode::TOO_MANY_REQUESTS, _) | (_, "RESOURCE_EXHAUSTED") => {
+                SdmError::RateLimited { retry_after }
+            }
+            (StatusCode::NOT_FOUND, _) | (_, "NOT_FOUND") => SdmError::NotFound(message),
+            (StatusCode::FORBIDDEN, _) | (_, "PERMISSION_DENIED") => SdmError::PermissionDenied(message),
+            _ => SdmError::Api {
+                status: status.as_u16(),
+                message,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SdmClientConfig {
+    /// Smart Device Management API root, e.g. `https://smartdevicemanagement.googleapis.com/v1`
+    pub base_url: String,
+    /// Limit on each attempt, including reading the response body
+    pub timeout: Duration,
+    pub connect_timeout: Duration,
+    /// Retries after the first attempt for rate-limited, failing or timed out requests
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for SdmClientConfig {
+    fn default() -> Self {
+        Self {
+            base_url: "https://smartdevicemanagement.googleapis.com/v1".to_string(),
+            timeout: Duration::from_secs(10),
+            connect_timeout: Duration::from_secs(5),
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl SdmClientConfig {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+
+        Self {
+            base_url: env::var("SDM_API_BASE_URL")
+                .ok()
+                .filter(|v| !v.is_empty())
+                .unwrap_or(defaults.base_url),
+            timeout: env::var("SDM_TIMEOUT_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.timeout),
+            max_retries: env::var("SDM_MAX_RETRIES")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(defaults.max_retries),
+            ..defaults
+        }
+    }
+}
+
+/// HTTP client shared by every call to Google: the SDM API, the OAuth token
+/// endpoint, Google's signing keys and Pub/Sub. Connections are pooled, each
+/// attempt is time-limited and transient failures are retried with backoff.
+#[derive(Clone)]
+pub struct SdmClient {
+    // Clones share the connection pool
+    client: Client,
+    config: SdmClientConfig,
+}
+
+impl SdmClient {
+    pub fn new(config: SdmClientConfig) -> Self {
+        let client = Client::builder()
+            .timeout(config.timeout)
+            .connect_timeout(config.connect_timeout)
+            .build()
+            .expect("Failed to build HTTP client");
+
+        Self { client, config }
+    }
+
+    /// The underlying client, for building requests to pass to `send`
+    pub fn http(&self) -> &Client {
+        &self.client
+    }
+
+    /// URL of an SDM API resource, e.g. `enterprises/{project}/devices`
+    pub fn url(&self, path: &str) -> String {
+        format!(
+            "{}/{}",
+            self.config.base_url.trim_end_matches('/'),
+            path.trim_start_matches('/')
+        )
+    }
+
+    /// Send a request, retrying on 429, 5xx, timeouts and connection
+    /// failures. The last response is returned as-is once retries run out, so
+    /// callers still need to check the status.
+    pub async fn send(&self, request: RequestBuilder) -> Result<Response, reqwest::Error> {
+        let mut attempt = 0;
+
+        loop {
+            // Streaming bodies can't be replayed, so send those only once
+            let result = match request.try_clone() {
+                Some(request) => request.send().await,
+                None => return request.send().await,
+            };
+
+            let delay = match &result {
+                Ok(response) if is_retryable(response.status()) => {
+                    match retry_after(response) {
+                        Some(delay) if delay > MAX_RETRY_AFTER => return result,
+                        Some(delay) => delay,
+                        None => self.backoff(attempt),
+                    }
+                }
+                Err(e) if e.is_timeout() || e.is_connect() => self.backoff(attempt),
+                _ => return result,
+            };
+
+            if attempt >= self.config.max_retries {
+                return result;
+            }
+            attempt += 1;
+
+            match &result {
+                Ok(response) => log::warn!(
+                    "Request to {} failed with {}, retrying in {:?}",
+                    response.url(),
+                    response.status(),
+                    delay
+                ),
+                Err(e) => log::warn!("Request failed: {}, retrying in {:?}", e, delay),
+            }
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Send a request with `send`, turning an unsuccessful response into an `SdmError`
+    pub async fn execute(&self, request: RequestBuilder) -> Result<Response, SdmError> {
+        let response = self.send(request).await?;
+        if !response.status().is_success() {
+            return Err(SdmError::from_response(response).await);
+        }
+        Ok(response)
+    }
+
+    // Exponential backoff with jitter, so clients that failed together don't
+    // retry together
+    fn backoff(&self, attempt: u32) -> Duration {
+        let max = self
+            .config
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.config.max_backoff);
+        max.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+fn is_retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(header::RETRY_AFTER)?.to_str().ok()?;
+    parse_retry_after(value, chrono::Utc::now())
+}
+
+// `Retry-After` is either a number of seconds or an HTTP date
+fn parse_retry_after(value: &str, now: chrono::DateTime<chrono::Utc>) -> Option<Duration> {
+    let value = value.trim();
+
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (date.with_timezone(&chrono::Utc) - now)
+            .to_std()
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    #[cfg(feature = "web-api")]
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn retry_after_accepts_seconds_or_a_date() {
+        let now = chrono::Utc.with_ymd_and_hms(2025, 1, 1, 12, 0, 0).unwrap();
+
+        assert_eq!(parse_retry_after("120", now), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after(" 0 ", now), Some(Duration::ZERO));
+        assert_eq!(
+            parse_retry_after("Wed, 01 Jan 2025 12:00:30 GMT", now),
+            Some(Duration::from_secs(30))
+        );
+        // A date in the past means retry now
+        assert_eq!(
+            parse_retry_after("Wed, 01 Jan 2025 11:59:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("-5", now), None);
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    // Serve `responses` in turn, then 200s, counting the requests made
+    #[cfg(feature = "web-api")]
+    pub(crate) async fn scripted_server(
+        responses: Vec<axum::response::Response>,
+    ) -> (String, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+        use axum::{response::IntoResponse, routing::any, Router};
+        use std::net::SocketAddr;
+        use std::sync::{atomic::AtomicUsize, Arc, Mutex};
+
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&requests);
+        let responses = Arc::new(Mutex::new(responses.into_iter()));
+        let app = Router::new().fallback(any(move || async move {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let next = responses.lock().unwrap().next();
+            next.unwrap_or_else(|| "{}".into_response())
+        }));
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(app.into_make_service());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        (format!("http://{}/v1", addr), requests)
+    }
+
+    #[cfg(feature = "web-api")]
+    fn client(base_url: String, max_retries: u32) -> SdmClient {
+        SdmClient::new(SdmClientConfig {
+            base_url,
+            max_retries,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(10),
+            ..SdmClientConfig::default()
+        })
+    }
+
+    #[cfg(feature = "web-api")]
+    fn status(code: u16) -> axum::response::Response {
+        use axum::response::IntoResponse;
+
+        axum::http::StatusCode::from_u16(code).unwrap().into_response()
+    }
+
+    #[cfg(feature = "web-api")]
+    #[tokio::test]
+    async fn unavailable_responses_are_retried() {
+        let (base_url, requests) = scripted_server(vec![status(503)]).await;
+        let sdm = client(base_url, 3);
+
+        let response = sdm.send(sdm.http().get(sdm.url("enterprises/p/devices"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[cfg(feature = "web-api")]
+    #[tokio::test]
+    async fn rate_limited_requests_wait_for_retry_after() {
+        use axum::response::IntoResponse;
+
+        let rate_limited = (StatusCode::TOO_MANY_REQUESTS, [(header::RETRY_AFTER, "1")]).into_response();
+        let (base_url, requests) = scripted_server(vec![rate_limited]).await;
+        let sdm = client(base_url, 3);
+
+        let started = std::time::Instant::now();
+        let response = sdm.send(sdm.http().get(sdm.url("enterprises/p/devices"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[cfg(feature = "web-api")]
+    #[tokio::test]
+    async fn retries_stop_at_max_retries() {
+        let (base_url, requests) = scripted_server((0..10).map(|_| status(500)).collect()).await;
+        let sdm = client(base_url, 2);
+
+        let response = sdm.send(sdm.http().get(sdm.url("enterprises/p/devices"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    // Classify a response with the given status, headers and body
+    #[cfg(feature = "web-api")]
+    async fn classify(code: u16, headers: &[(header::HeaderName, &'static str)], body: &str) -> SdmError {
+        use axum::response::IntoResponse;
+
+        let mut response = (axum::http::StatusCode::from_u16(code).unwrap(), body.to_string()).into_response();
+        for (name, value) in headers {
+            response.headers_mut().insert(name.clone(), header::HeaderValue::from_static(value));
+        }
+        let (base_url, _) = scripted_server(vec![response]).await;
+        let sdm = client(base_url, 0);
+
+        let response = sdm.send(sdm.http().get(sdm.url("enterprises/p/devices"))).await.unwrap();
+        SdmError::from_response(response).await
+    }
+
+    #[cfg(feature = "web-api")]
+    fn google_error(code: u16, status: &str, message: &str) -> String {
+        serde_json::json!({ "error": { "code": code, "status": status, "message": message } }).to_string()
+    }
+
+    #[cfg(feature = "web-api")]
+    #[tokio::test]
+    async fn errors_are_classified_by_status_code_or_google_status() {
+        let error = classify(401, &[], &google_error(401, "UNAUTHENTICATED", "Token expired")).await;
+        assert!(matches!(error, SdmError::Unauthorized(message) if message == "Token expired"));
+        let error = classify(400, &[], &google_error(400, "UNAUTHENTICATED", "Bad token")).await;
+        assert!(matches!(error, SdmError::Unauthorized(_)));
+
+        let error = classify(429, &[(header::RETRY_AFTER, "30")], "").await;
+        assert!(matches!(error, SdmError::RateLimited { retry_after: Some(delay) } if delay == Duration::from_secs(30)));
+        let error = classify(503, &[], &google_error(503, "RESOURCE_EXHAUSTED", "Quota exceeded")).await;
+        assert!(matches!(error, SdmError::RateLimited { retry_after: None }));
+
+        let error = classify(404, &[], &google_error(404, "NOT_FOUND", "Device not found")).await;
+        assert!(matches!(error, SdmError::NotFound(message) if message == "Device not found"));
+
+        let error = classify(403, &[], &google_error(403, "PERMISSION_DENIED", "Not allowed")).await;
+        assert!(matches!(error, SdmError::PermissionDenied(message) if message == "Not allowed"));
+        let error = classify(400, &[], &google_error(400, "PERMISSION_DENIED", "Not allowed")).await;
+        assert!(matches!(error, SdmError::PermissionDenied(_)));
+    }
+
+    #[cfg(feature = "web-api")]
+    #[tokio::test]
+    async fn other_errors_keep_their_status_and_body() {
+        let error = classify(502, &[], "<html>Bad Gateway</html>").await;
+        assert!(matches!(error, SdmError::Api { status: 502, message } if message == "<html>Bad Gateway</html>"));
+
+        let error = classify(400, &[], &google_error(400, "INVALID_ARGUMENT", "Bad filter")).await;
+        assert!(matches!(error, SdmError::Api { status: 400, message } if message == "Bad filter"));
+
+        // Unrecognized errors are not mistaken for one of the classified cases
+        let error = classify(500, &[], "not found").await;
+        assert!(matches!(error, SdmError::Api { status: 500, .. }));
+    }
+}
@@ -0,0 +1,264 @@
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Key, Nonce,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use thiserror::Error;
+
+// Marks a value encrypted by `TokenCipher`, followed by the key ID, the
+// wrapped data key and the ciphertext
+const PREFIX: &str = "enc:v1:";
+
+// AES-GCM nonces are 96 bits
+const NONCE_LEN: usize = 12;
+
+#[derive(Error, Debug)]
+pub enum CryptoError {
+    #[error("Invalid encryption key: {0}")]
+    InvalidKey(String),
+
+    #[error("Unknown encryption key {0}")]
+    UnknownKey(String),
+
+    #[error("Encrypted tokens found but no encryption key is configured")]
+    MissingKey,
+
+    #[error("Malformed encrypted value")]
+    Malformed,
+
+    #[error("Encryption failed")]
+    Encrypt,
+
+    #[error("Decryption failed")]
+    Decrypt,
+
+    #[error("Failed to read key file: {0}")]
+    KeyFile(#[from] std::io::Error),
+}
+
+/// Encrypts secrets with a per-value data key that is itself encrypted
+/// ("wrapped") with a long-lived key. New values always use the primary key;
+/// the others are kept so values written before a rotation can be read.
+pub struct TokenCipher {
+    primary: String,
+    keys: HashMap<String, Aes256Gcm>,
+}
+
+impl TokenCipher {
+    /// Parse keys given as `<key id>:<base64 32-byte key>` entries. The first
+    /// entry is the primary key.
+    pub fn from_entries<'a>(entries: impl IntoIterator<Item = &'a str>) -> Result<Self, CryptoError> {
+        let mut primary = None;
+        let mut keys = HashMap::new();
+
+        for entry in entries {
+            let (key_id, key) = entry
+                .split_once(':')
+                .ok_or_else(|| CryptoError::InvalidKey("expected <key id>:<base64 key>".to_string()))?;
+            let key_id = key_id.trim();
+            if key_id.is_empty() {
+                return Err(CryptoError::InvalidKey("empty key id".to_string()));
+            }
+
+            let key = STANDARD
+                .decode(key.trim())
+                .map_err(|e| CryptoError::InvalidKey(format!("{}: {}", key_id, e)))?;
+            if key.len() != 32 {
+                return Err(CryptoError::InvalidKey(format!(
+                    "{}: expected 32 bytes, got {}",
+                    key_id,
+                    key.len()
+                )));
+            }
+
+            primary.get_or_insert_with(|| key_id.to_string());
+            keys.insert(
+                key_id.to_string(),
+                Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)),
+            );
+        }
+
+        let primary = primary.ok_or_else(|| CryptoError::InvalidKey("no keys given".to_string()))?;
+        Ok(Self { primary, keys })
+    }
+
+    /// Read keys from `TOKEN_ENCRYPTION_KEYS` (comma-separated) or from the
+    /// file named by `TOKEN_ENCRYPTION_KEY_FILE` (one per line). Returns None
+    /// if neither is set.
+    pub fn from_env() -> Result<Option<Self>, CryptoError> {
+        if let Some(keys) = env::var("TOKEN_ENCRYPTION_KEYS").ok().filter(|v| !v.is_empty()) {
+            return Self::from_entries(keys.split(',').filter(|entry| !entry.trim().is_empty()))
+                .map(Some);
+        }
+
+        if let Some(path) = env::var("TOKEN_ENCRYPTION_KEY_FILE").ok().filter(|v| !v.is_empty()) {
+            let contents = fs::read_to_string(path)?;
+            let entries = contents
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'));
+            return Self::from_entries(entries).map(Some);
+        }
+
+        Ok(None)
+    }
+
+    pub fn is_encrypted(value: &str) -> bool {
+        value.starts_with(PREFIX)
+    }
+
+    /// Whether a value is encrypted with the primary key
+    pub fn is_current(&self, value: &str) -> bool {
+        value
+            .strip_prefix(PREFIX)
+            .and_then(|rest| rest.split_once(':'))
+            .is_some_and(|(key_id, _)| key_id == self.primary)
+    }
+
+    /// Encrypt a value with the primary key. `context` is authenticated along
+    /// with it, so an encrypted value can't be moved to another field or user.
+    pub fn encrypt(&self, plaintext: &str, context: &str) -> Result<String, CryptoError> {
+        let kek = &self.keys[&self.primary];
+
+        let data_key = Aes256Gcm::generate_key(OsRng);
+        let wrapped = seal(kek, &data_key, self.primary.as_bytes())?;
+        let ciphertext = seal(
+            &Aes256Gcm::new(&data_key),
+            plaintext.as_bytes(),
+            context.as_bytes(),
+        )?;
+
+        Ok(format!(
+            "{}{}:{}:{}",
+            PREFIX,
+            self.primary,
+            STANDARD.encode(wrapped),
+            STANDARD.encode(ciphertext)
+        ))
+    }
+
+    /// Decrypt a value produced by `encrypt` with any known key
+    pub fn decrypt(&self, value: &str, context: &str) -> Result<String, CryptoError> {
+        let rest = value.strip_prefix(PREFIX).ok_or(CryptoError::Malformed)?;
+        let mut parts = rest.splitn(3, ':');
+        let (key_id, wrapped, ciphertext) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(key_id), Some(wrapped), Some(ciphertext)) => (key_id, wrapped, ciphertext),
+            _ => return Err(CryptoError::Malformed),
+        };
+
+        let kek = self
+            .keys
+            .get(key_id)
+            .ok_or_else(|| CryptoError::UnknownKey(key_id.to_string()))?;
+        let wrapped = STANDARD.decode(wrapped).map_err(|_| CryptoError::Malformed)?;
+        let ciphertext = STANDARD.decode(ciphertext).map_err(|_| CryptoError::Malformed)?;
+
+        let data_key = open(kek, &wrapped, key_id.as_bytes())?;
+        if data_key.len() != 32 {
+            return Err(CryptoError::Malformed);
+        }
+        let plaintext = open(
+            &Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&data_key)),
+            &ciphertext,
+            context.as_bytes(),
+        )?;
+
+        String::from_utf8(plaintext).map_err(|_| CryptoError::Malformed)
+    }
+}
+
+// Encrypt under a fresh nonce, returning the nonce followed by the ciphertext
+fn seal(cipher: &Aes256Gcm, msg: &[u8], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, Payload { msg, aad })
+        .map_err(|_| CryptoError::Encrypt)?;
+
+    let mut sealed = nonce.to_vec();
+    sealed.extend(ciphertext);
+    Ok(sealed)
+}
+
+fn open(cipher: &Aes256Gcm, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    if sealed.len() < NONCE_LEN {
+        return Err(CryptoError::Malformed);
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    cipher
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+        .map_err(|_| CryptoError::Decrypt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(key_id: &str, byte: u8) -> String {
+        format!("{}:{}", key_id, STANDARD.encode([byte; 32]))
+    }
+
+    fn cipher(entries: &[String]) -> TokenCipher {
+        TokenCipher::from_entries(entries.iter().map(String::as_str)).unwrap()
+    }
+
+    #[test]
+    fn values_round_trip_within_their_context() {
+        let cipher = cipher(&[entry("k1", 1)]);
+
+        let encrypted = cipher.encrypt("refresh-token", "alice:refresh_token").unwrap();
+        assert!(TokenCipher::is_encrypted(&encrypted));
+        assert!(!encrypted.contains("refresh-token"));
+        assert_eq!(cipher.decrypt(&encrypted, "alice:refresh_token").unwrap(), "refresh-token");
+
+        // Each encryption uses a fresh data key and nonce
+        assert_ne!(cipher.encrypt("refresh-token", "alice:refresh_token").unwrap(), encrypted);
+
+        assert!(matches!(
+            cipher.decrypt(&encrypted, "bob:refresh_token"),
+            Err(CryptoError::Decrypt)
+        ));
+        assert!(matches!(cipher.decrypt("refresh-token", "alice:refresh_token"), Err(CryptoError::Malformed)));
+    }
+
+    #[test]
+    fn rotated_keys_still_decrypt_old_values() {
+        let old = cipher(&[entry("k1", 1)]);
+        let encrypted = old.encrypt("secret", "context").unwrap();
+
+        let rotated = cipher(&[entry("k2", 2), entry("k1", 1)]);
+        assert_eq!(rotated.decrypt(&encrypted, "context").unwrap(), "secret");
+        let reencrypted = rotated.encrypt("secret", "context").unwrap();
+        assert!(reencrypted.starts_with("enc:v1:k2:"));
+
+        // Once the old key is dropped its values can't be read
+        let retired = cipher(&[entry("k2", 2)]);
+        assert_eq!(retired.decrypt(&reencrypted, "context").unwrap(), "secret");
+        assert!(matches!(
+            retired.decrypt(&encrypted, "context"),
+            Err(CryptoError::UnknownKey(key_id)) if key_id == "k1"
+        ));
+
+        // A different key under the same ID doesn't unwrap the data key
+        let replaced = cipher(&[entry("k1", 3)]);
+        assert!(matches!(replaced.decrypt(&encrypted, "context"), Err(CryptoError::Decrypt)));
+    }
+
+    #[test]
+    fn malformed_keys_are_rejected() {
+        for entries in [
+            vec!["no-separator".to_string()],
+            vec![format!(":{}", STANDARD.encode([1u8; 32]))],
+            vec![format!("k1:{}", STANDARD.encode([1u8; 16]))],
+            vec!["k1:not base64!".to_string()],
+            vec![],
+        ] {
+            assert!(matches!(
+                TokenCipher::from_entries(entries.iter().map(String::as_str)),
+                Err(CryptoError::InvalidKey(_))
+            ));
+        }
+    }
+}
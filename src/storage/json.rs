@@ -0,0 +1,198 @@
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::auth::models::UserConfig;
+use crate::events::dedup::ProcessedEvent;
+use crate::storage::{self, crypto::TokenCipher, StorageError, UserRepository};
+
+// The events log is compacted once it has at least this many lines and most
+// of them are for evicted events
+const MIN_COMPACTION_LINES: usize = 1000;
+
+// Size of the processed events log
+#[derive(Default)]
+struct EventLog {
+    lines: usize,
+    // Lines for events that are still cached
+    live: usize,
+}
+
+/// Stores users and processed events in local JSON files
+pub struct JsonRepository {
+    users_file: String,
+    events_file: String,
+    // Previous versions of the users file to keep
+    backups: usize,
+    // Encrypts tokens in the users file when configured
+    cipher: Option<Arc<TokenCipher>>,
+    // Whether the backups were checked against the cipher since starting
+    backups_encrypted: AtomicBool,
+    // Also serializes writes to the events file
+    events_log: Mutex<EventLog>,
+}
+
+impl JsonRepository {
+    pub fn new(
+        users_file: String,
+        events_file: String,
+        backups: usize,
+        cipher: Option<Arc<TokenCipher>>,
+    ) -> Self {
+        Self {
+            users_file,
+            events_file,
+            backups,
+            cipher,
+            backups_encrypted: AtomicBool::new(false),
+            events_log: Mutex::new(EventLog::default()),
+        }
+    }
+
+    // Rewrite the events log with only the cached events
+    async fn compact_events(&self, log: &mut EventLog) -> Result<HashMap<String, Vec<ProcessedEvent>>, StorageError> {
+        let events = storage::load_processed_events(&self.events_file).await?;
+        storage::save_processed_events(&events, &self.events_file).await?;
+
+        let count = events.values().map(Vec::len).sum();
+        *log = EventLog {
+            lines: count,
+            live: count,
+        };
+        Ok(events)
+    }
+}
+
+#[async_trait]
+impl UserRepository for JsonRepository {
+    fn name(&self) -> &str {
+        "json"
+    }
+
+    async fn load_users(&self) -> Result<HashMap<String, UserConfig>, StorageError> {
+        Ok(storage::load_user_data(&self.users_file, self.backups, self.cipher.as_deref()).await?)
+    }
+
+    async fn save_users(&self, users: &HashMap<String, UserConfig>) -> Result<(), StorageError> {
+        storage::save_user_data(users, &self.users_file, self.backups, self.cipher.as_deref()).await?;
+
+        // Backups are only written encrypted from now on, so older ones need
+        // checking once
+        if let Some(cipher) = &self.cipher {
+            if !self.backups_encrypted.load(Ordering::Relaxed) {
+                storage::encrypt_backups(&self.users_file, self.backups, cipher).await?;
+                self.backups_encrypted.store(true, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
+
+    async fn load_processed_events(
+        &self,
+    ) -> Result<HashMap<String, Vec<ProcessedEvent>>, StorageError> {
+        // Start from a compact log, which also converts files written before
+        // events were logged
+        let mut log = self.events_log.lock().await;
+        self.compact_events(&mut log).await
+    }
+
+    async fn save_processed_event(
+        &self,
+        user_id: &str,
+        event: &ProcessedEvent,
+        evicted: &[ProcessedEvent],
+    ) -> Result<(), StorageError> {
+        let mut log = self.events_log.lock().await;
+        storage::append_processed_event(user_id, event, evicted, &self.events_file).await?;
+
+        log.lines += 1;
+        log.live = (log.live + 1).saturating_sub(evicted.len());
+        if log.lines >= MIN_COMPACTION_LINES && log.lines > 2 * log.live {
+            self.compact_events(&mut log).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn processed(event_id: usize) -> ProcessedEvent {
+        ProcessedEvent {
+            event_id: format!("event-{}", event_id),
+            device_id: "camera-1".to_string(),
+            timestamp: "2025-01-01T12:00:00Z".to_string(),
+        }
+    }
+
+    fn repository(dir: &std::path::Path) -> JsonRepository {
+        JsonRepository::new(
+            dir.join("users.json").to_string_lossy().into_owned(),
+            dir.join("events.json").to_string_lossy().into_owned(),
+            0,
+            None,
+        )
+    }
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dishwashmon-json-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn line_count(dir: &std::path::Path) -> usize {
+        std::fs::read_to_string(dir.join("events.json")).unwrap().lines().count()
+    }
+
+    #[tokio::test]
+    async fn the_events_log_is_compacted_once_mostly_evicted() {
+        let dir = temp_dir();
+        let repository = repository(&dir);
+        repository.load_processed_events().await.unwrap();
+
+        // With room for one event, every claim evicts the previous one
+        repository.save_processed_event("alice", &processed(0), &[]).await.unwrap();
+        for n in 1..MIN_COMPACTION_LINES {
+            repository
+                .save_processed_event("alice", &processed(n), &[processed(n - 1)])
+                .await
+                .unwrap();
+        }
+        assert_eq!(line_count(&dir), 1);
+
+        let events = repository.load_processed_events().await.unwrap();
+        assert_eq!(events["alice"].len(), 1);
+        assert_eq!(events["alice"][0].event_id, format!("event-{}", MIN_COMPACTION_LINES - 1));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn caches_saved_whole_are_converted_to_a_log() {
+        let dir = temp_dir();
+        let old: HashMap<String, Vec<ProcessedEvent>> =
+            HashMap::from([("alice".to_string(), vec![processed(1), processed(2)])]);
+        std::fs::write(dir.join("events.json"), serde_json::to_string(&old).unwrap()).unwrap();
+
+        let repository = repository(&dir);
+        let events = repository.load_processed_events().await.unwrap();
+        assert_eq!(events["alice"].len(), 2);
+        assert_eq!(line_count(&dir), 2);
+
+        // A torn final line is skipped
+        repository.save_processed_event("alice", &processed(3), &[processed(1)]).await.unwrap();
+        let mut file = std::fs::OpenOptions::new().append(true).open(dir.join("events.json")).unwrap();
+        std::io::Write::write_all(&mut file, b"{\"user_id\": \"ali").unwrap();
+
+        let events = repository.load_processed_events().await.unwrap();
+        let ids: Vec<&str> = events["alice"].iter().map(|event| event.event_id.as_str()).collect();
+        assert_eq!(ids, vec!["event-2", "event-3"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
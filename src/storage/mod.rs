@@ -1,64 +1,760 @@
 use crate::auth::models::UserConfig;
+use crate::events::dedup::ProcessedEvent;
+use crate::storage::crypto::{CryptoError, TokenCipher};
+use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::fs::{self, File};
-use std::io::{self, Read, Write};
-use std::path::Path;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use thiserror::Error;
+use tokio::sync::{Mutex, MutexGuard, Notify};
+
+pub mod crypto;
+pub mod json;
+#[cfg(feature = "mongo")]
+pub mod mongo;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+
+#[derive(Error, Debug)]
+pub enum StorageError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+
+    #[cfg(feature = "postgres")]
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+
+    #[cfg(feature = "postgres")]
+    #[error("Migration error: {0}")]
+    Migration(#[from] sqlx::migrate::MigrateError),
+
+    #[cfg(feature = "mongo")]
+    #[error("MongoDB error: {0}")]
+    Mongo(#[from] mongodb::error::Error),
+
+    #[error("Another user already has the email address {0}")]
+    EmailTaken(String),
+
+    #[error("Encryption error: {0}")]
+    Crypto(#[from] CryptoError),
+
+    #[error("Configuration error: {0}")]
+    Config(String),
+}
+
+/// Persistent storage for users and the processed event cache
+#[async_trait]
+pub trait UserRepository: Send + Sync {
+    /// Name used in logs
+    fn name(&self) -> &str;
+
+    async fn load_users(&self) -> Result<HashMap<String, UserConfig>, StorageError>;
+
+    /// Replace the stored users with `users`
+    async fn save_users(&self, users: &HashMap<String, UserConfig>) -> Result<(), StorageError>;
+
+    /// Recently processed events per user, oldest first
+    async fn load_processed_events(
+        &self,
+    ) -> Result<HashMap<String, Vec<ProcessedEvent>>, StorageError>;
+
+    /// Record that `user_id` processed `event`, and forget `evicted`, the
+    /// user's older events that no longer fit in the cache
+    async fn save_processed_event(
+        &self,
+        user_id: &str,
+        event: &ProcessedEvent,
+        evicted: &[ProcessedEvent],
+    ) -> Result<(), StorageError>;
+}
+
+/// Connect to Postgres or MongoDB if `DATABASE_URL` is set, otherwise store
+/// data in the JSON files named by `DATA_FILE` and `EVENTS_FILE`
+pub async fn repository_from_env() -> Result<Arc<dyn UserRepository>, StorageError> {
+    if let Some(database_url) = env::var("DATABASE_URL").ok().filter(|v| !v.is_empty()) {
+        if database_url.starts_with("postgres://") || database_url.starts_with("postgresql://") {
+            #[cfg(feature = "postgres")]
+            {
+                let repository = postgres::PostgresRepository::connect(&database_url).await?;
+                return Ok(Arc::new(repository));
+            }
+
+            #[cfg(not(feature = "postgres"))]
+            return Err(StorageError::Config(
+                "DATABASE_URL points at Postgres but dishwashmon was built without the postgres feature"
+                    .to_string(),
+            ));
+        }
+
+        if database_url.starts_with("mongodb://") || database_url.starts_with("mongodb+srv://") {
+            #[cfg(feature = "mongo")]
+            {
+                let repository = mongo::MongoRepository::connect(&database_url).await?;
+                return Ok(Arc::new(repository));
+            }
+
+            #[cfg(not(feature = "mongo"))]
+            return Err(StorageError::Config(
+                "DATABASE_URL points at MongoDB but dishwashmon was built without the mongo feature"
+                    .to_string(),
+            ));
+        }
+
+        return Err(StorageError::Config(
+            "DATABASE_URL must be a postgres:// or mongodb:// URL".to_string(),
+        ));
+    }
+
+    let users_file = env::var("DATA_FILE").unwrap_or_else(|_| "data/users.json".to_string());
+    let events_file = env::var("EVENTS_FILE").unwrap_or_else(|_| "data/events.json".to_string());
+    let backups = env::var("DATA_BACKUPS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(3);
+
+    let cipher = TokenCipher::from_env()?;
+    if cipher.is_none() {
+        log::warn!(
+            "Neither TOKEN_ENCRYPTION_KEYS nor TOKEN_ENCRYPTION_KEY_FILE is set, OAuth tokens will be stored in plaintext"
+        );
+    }
+
+    Ok(Arc::new(json::JsonRepository::new(
+        users_file,
+        events_file,
+        backups,
+        cipher.map(Arc::new),
+    )))
+}
 
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct StoredUserData {
     pub users: HashMap<String, UserConfig>,
 }
 
-pub type UserStore = Arc<Mutex<HashMap<String, UserConfig>>>;
+/// Users shared between tasks. Mutable access through `lock` marks the store
+/// as changed so a save can be scheduled.
+#[derive(Clone, Default)]
+pub struct UserStore {
+    users: Arc<Mutex<HashMap<String, UserConfig>>>,
+    dirty: Arc<AtomicBool>,
+    changed: Arc<Notify>,
+    // Serializes saves, which would otherwise race on the same files
+    save_lock: Arc<Mutex<()>>,
+}
+
+impl UserStore {
+    pub fn new(users: HashMap<String, UserConfig>) -> Self {
+        Self {
+            users: Arc::new(Mutex::new(users)),
+            ..Default::default()
+        }
+    }
+
+    pub async fn lock(&self) -> UserStoreGuard<'_> {
+        UserStoreGuard {
+            users: self.users.lock().await,
+            store: self,
+            modified: false,
+        }
+    }
+
+    /// Schedule a save even though nothing was modified through `lock`
+    pub fn mark_changed(&self) {
+        self.dirty.store(true, Ordering::SeqCst);
+        self.changed.notify_one();
+    }
+
+    /// Write the users to the repository if they changed since the last save
+    pub async fn save(&self, repository: &dyn UserRepository) -> Result<(), StorageError> {
+        let _guard = self.save_lock.lock().await;
+        if !self.dirty.swap(false, Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        // Don't hold the lock while writing
+        let snapshot = self.users.lock().await.clone();
+        if let Err(e) = repository.save_users(&snapshot).await {
+            self.dirty.store(true, Ordering::SeqCst);
+            return Err(e);
+        }
+
+        Ok(())
+    }
+}
+
+pub struct UserStoreGuard<'a> {
+    users: MutexGuard<'a, HashMap<String, UserConfig>>,
+    store: &'a UserStore,
+    modified: bool,
+}
+
+impl Deref for UserStoreGuard<'_> {
+    type Target = HashMap<String, UserConfig>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.users
+    }
+}
+
+impl DerefMut for UserStoreGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.modified = true;
+        &mut self.users
+    }
+}
+
+impl Drop for UserStoreGuard<'_> {
+    fn drop(&mut self) {
+        if self.modified {
+            self.store.mark_changed();
+        }
+    }
+}
+
+// Path of the nth most recent backup of a file
+fn backup_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+// Replaces a file without ever leaving it partially written: the contents go
+// to a temporary file that is synced and then renamed over the original,
+// which is first rotated into the `backups` most recent backups
+fn write_atomic(path: &Path, contents: &[u8], backups: usize) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut tmp_name = path.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_name);
+
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    drop(file);
+
+    if backups > 0 && path.exists() {
+        for n in (1..backups).rev() {
+            let older = backup_path(path, n);
+            if older.exists() {
+                fs::rename(&older, backup_path(path, n + 1))?;
+            }
+        }
+        fs::rename(path, backup_path(path, 1))?;
+    }
+    fs::rename(&tmp_path, path)?;
+
+    // Make the renames themselves durable
+    #[cfg(unix)]
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        File::open(parent)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+// Token fields that are encrypted at rest
+const SECRET_TOKEN_FIELDS: [&str; 3] = ["access_token", "refresh_token", "id_token"];
+
+// Applies `transform(value, context)` to each user's token secrets in
+// serialized user data
+fn transform_token_secrets(
+    data: &mut serde_json::Value,
+    mut transform: impl FnMut(&str, &str) -> Result<String, CryptoError>,
+) -> Result<(), CryptoError> {
+    let users = match data.get_mut("users").and_then(|users| users.as_object_mut()) {
+        Some(users) => users,
+        None => return Ok(()),
+    };
 
-// Saves user data to a JSON file
-pub async fn save_user_data(users: &UserStore, file_path: &str) -> io::Result<()> {
-    let users_lock = users.lock().await;
+    for (user_id, user) in users.iter_mut() {
+        let token = match user.get_mut("token").and_then(|token| token.as_object_mut()) {
+            Some(token) => token,
+            None => continue,
+        };
+        for field in SECRET_TOKEN_FIELDS {
+            if let Some(serde_json::Value::String(value)) = token.get_mut(field) {
+                let context = format!("{}/{}", user_id, field);
+                *value = transform(value, &context)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Saves user data to a JSON file, keeping `backups` previous versions.
+// Tokens are encrypted if a cipher is given.
+pub async fn save_user_data(
+    users: &HashMap<String, UserConfig>,
+    file_path: &str,
+    backups: usize,
+    cipher: Option<&TokenCipher>,
+) -> Result<(), StorageError> {
     let data = StoredUserData {
-        users: users_lock.clone(),
+        users: users.clone(),
     };
-    
-    // Create directory if it doesn't exist
+
+    let mut value = serde_json::to_value(&data)?;
+    if let Some(cipher) = cipher {
+        // Always encrypting with the primary key re-encrypts values written
+        // with older keys, and encrypts plaintext left from before encryption
+        // was enabled
+        transform_token_secrets(&mut value, |secret, context| cipher.encrypt(secret, context))?;
+    }
+
+    let serialized = serde_json::to_string_pretty(&value)?;
+    Ok(write_atomic(Path::new(file_path), serialized.as_bytes(), backups)?)
+}
+
+// Brings the backups of the user data in line with the cipher, so that no
+// plaintext tokens, or tokens only a retired key can read, are left on disk
+// once encryption is enabled or the key rotated. Backups that can't be
+// re-encrypted are deleted.
+pub async fn encrypt_backups(file_path: &str, backups: usize, cipher: &TokenCipher) -> Result<(), StorageError> {
+    let path = Path::new(file_path);
+
+    for backup in (1..=backups).map(|n| backup_path(path, n)) {
+        if !backup.exists() {
+            continue;
+        }
+
+        let reencrypted = fs::read_to_string(&backup)
+            .map_err(StorageError::from)
+            .and_then(|contents| Ok(serde_json::from_str::<serde_json::Value>(&contents)?))
+            .and_then(|mut value| {
+                let mut changed = false;
+                transform_token_secrets(&mut value, |secret, context| {
+                    if cipher.is_current(secret) {
+                        return Ok(secret.to_string());
+                    }
+                    changed = true;
+                    let plaintext = if TokenCipher::is_encrypted(secret) {
+                        cipher.decrypt(secret, context)?
+                    } else {
+                        secret.to_string()
+                    };
+                    cipher.encrypt(&plaintext, context)
+                })?;
+                Ok(changed.then_some(value))
+            });
+
+        match reencrypted {
+            Ok(None) => {}
+            Ok(Some(value)) => {
+                let serialized = serde_json::to_string_pretty(&value)?;
+                write_atomic(&backup, serialized.as_bytes(), 0)?;
+                log::info!("Re-encrypted tokens in {}", backup.display());
+            }
+            Err(e) => {
+                log::warn!("Deleting {}, which can't be re-encrypted: {}", backup.display(), e);
+                fs::remove_file(&backup)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Reads one copy of the user data, decrypting tokens. Plaintext tokens are
+// accepted so existing files keep working once encryption is enabled.
+fn read_user_data(path: &Path, cipher: Option<&TokenCipher>) -> Result<StoredUserData, StorageError> {
+    let contents = fs::read_to_string(path)?;
+    let mut value: serde_json::Value = serde_json::from_str(&contents)?;
+
+    transform_token_secrets(&mut value, |secret, context| {
+        if !TokenCipher::is_encrypted(secret) {
+            return Ok(secret.to_string());
+        }
+        cipher
+            .ok_or(CryptoError::MissingKey)?
+            .decrypt(secret, context)
+    })?;
+
+    Ok(serde_json::from_value(value)?)
+}
+
+// Loads user data from a JSON file, falling back to the newest readable
+// backup if the file is missing or corrupt
+pub async fn load_user_data(
+    file_path: &str,
+    backups: usize,
+    cipher: Option<&TokenCipher>,
+) -> Result<HashMap<String, UserConfig>, StorageError> {
+    let path = Path::new(file_path);
+    let candidates = std::iter::once(path.to_path_buf()).chain((1..=backups).map(|n| backup_path(path, n)));
+
+    let mut last_error = None;
+    for candidate in candidates {
+        if !candidate.exists() {
+            continue;
+        }
+
+        match read_user_data(&candidate, cipher) {
+            Ok(data) => {
+                if candidate != path {
+                    log::warn!("Loaded user data from backup {}", candidate.display());
+                }
+                return Ok(data.users);
+            }
+            // A missing key is a configuration problem, and falling back
+            // could load an older, unencrypted copy
+            Err(e @ StorageError::Crypto(CryptoError::MissingKey | CryptoError::UnknownKey(_))) => {
+                return Err(e);
+            }
+            Err(e) => {
+                log::error!("Failed to read user data from {}: {}", candidate.display(), e);
+                last_error = Some(e);
+            }
+        }
+    }
+
+    match last_error {
+        // Every copy on disk is unreadable
+        Some(e) => Err(e),
+        None => Ok(HashMap::new()),
+    }
+}
+
+// One line of the processed event log: an event a user processed, and the
+// IDs of that user's events it evicted from the cache
+#[derive(Serialize, Deserialize)]
+struct ProcessedEventRecord {
+    user_id: String,
+    event: ProcessedEvent,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    evicted: Vec<String>,
+}
+
+// Rewrites the processed event log with just `events`
+pub async fn save_processed_events(
+    events: &HashMap<String, Vec<ProcessedEvent>>,
+    file_path: &str,
+) -> io::Result<()> {
+    let mut contents = String::new();
+    for (user_id, user_events) in events {
+        for event in user_events {
+            let record = ProcessedEventRecord {
+                user_id: user_id.clone(),
+                event: event.clone(),
+                evicted: Vec::new(),
+            };
+            contents.push_str(&serde_json::to_string(&record)?);
+            contents.push('\n');
+        }
+    }
+    write_atomic(Path::new(file_path), contents.as_bytes(), 0)
+}
+
+// Appends a processed event to the log, synced so the claim survives a crash
+pub async fn append_processed_event(
+    user_id: &str,
+    event: &ProcessedEvent,
+    evicted: &[ProcessedEvent],
+    file_path: &str,
+) -> io::Result<()> {
     if let Some(parent) = Path::new(file_path).parent() {
         fs::create_dir_all(parent)?;
     }
-    
-    let serialized = serde_json::to_string_pretty(&data)?;
-    let mut file = File::create(file_path)?;
-    file.write_all(serialized.as_bytes())?;
-    
-    Ok(())
+
+    let record = ProcessedEventRecord {
+        user_id: user_id.to_string(),
+        event: event.clone(),
+        evicted: evicted.iter().map(|event| event.event_id.clone()).collect(),
+    };
+    let mut line = serde_json::to_string(&record)?;
+    line.push('\n');
+
+    let mut file = OpenOptions::new().create(true).append(true).open(file_path)?;
+    file.write_all(line.as_bytes())?;
+    file.sync_data()
 }
 
-// Loads user data from a JSON file
-pub async fn load_user_data(file_path: &str) -> io::Result<UserStore> {
+// Replays the processed event log. Files from before the log hold the whole
+// cache as one JSON object and are read as is.
+pub async fn load_processed_events(
+    file_path: &str,
+) -> io::Result<HashMap<String, Vec<ProcessedEvent>>> {
     let path = Path::new(file_path);
     if !path.exists() {
-        return Ok(Arc::new(Mutex::new(HashMap::new())));
+        return Ok(HashMap::new());
     }
-    
-    let mut file = File::open(path)?;
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)?;
-    
-    let data: StoredUserData = serde_json::from_str(&contents)?;
-    Ok(Arc::new(Mutex::new(data.users)))
+
+    let contents = fs::read_to_string(path)?;
+    if let Ok(events) = serde_json::from_str(&contents) {
+        return Ok(events);
+    }
+
+    let mut events: HashMap<String, Vec<ProcessedEvent>> = HashMap::new();
+    for (number, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        // A crash can leave the last line partially written
+        let record: ProcessedEventRecord = match serde_json::from_str(line) {
+            Ok(record) => record,
+            Err(e) => {
+                log::warn!("Skipping unreadable line {} of {}: {}", number + 1, file_path, e);
+                continue;
+            }
+        };
+
+        let user_events = events.entry(record.user_id).or_default();
+        if !record.evicted.is_empty() {
+            user_events.retain(|event| !record.evicted.contains(&event.event_id));
+        }
+        if !user_events.iter().any(|event| event.event_id == record.event.event_id) {
+            user_events.push(record.event);
+        }
+    }
+    events.retain(|_, user_events| !user_events.is_empty());
+
+    Ok(events)
 }
 
-// Saves user data periodically
-pub async fn start_periodic_save(users: UserStore, file_path: String, interval: std::time::Duration) {
+// Appends a record to a JSON lines file
+pub async fn append_dead_letter<T: Serialize>(file_path: &str, record: &T) -> io::Result<()> {
+    if let Some(parent) = Path::new(file_path).parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut line = serde_json::to_string(record)?;
+    line.push('\n');
+    let mut file = OpenOptions::new().create(true).append(true).open(file_path)?;
+    file.write_all(line.as_bytes())?;
+
+    Ok(())
+}
+
+// Saves user data shortly after it changes. Changes made within `debounce`
+// of each other are written together.
+pub async fn start_change_driven_save(
+    users: UserStore,
+    repository: Arc<dyn UserRepository>,
+    debounce: std::time::Duration,
+) {
     tokio::spawn(async move {
-        let mut interval = tokio::time::interval(interval);
         loop {
-            interval.tick().await;
-            if let Err(e) = save_user_data(&users, &file_path).await {
-                log::error!("Failed to save user data: {}", e);
-            } else {
-                log::info!("User data saved successfully");
+            users.changed.notified().await;
+            tokio::time::sleep(debounce).await;
+
+            match users.save(repository.as_ref()).await {
+                Ok(()) => log::debug!("User data saved"),
+                Err(e) => {
+                    log::error!("Failed to save user data: {}", e);
+                    // Try again after another debounce interval
+                    users.changed.notify_one();
+                }
             }
         }
     });
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dishwashmon-storage-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn users(version: &str) -> HashMap<String, UserConfig> {
+        let config = serde_json::from_value(serde_json::json!({
+            "user_id": "alice",
+            "device_ids": [version],
+            "token": {
+                "access_token": "access-secret",
+                "refresh_token": "refresh-secret",
+                "token_type": "Bearer",
+                "expires_in": 3600,
+            },
+        }))
+        .unwrap();
+        HashMap::from([("alice".to_string(), config)])
+    }
+
+    fn version(users: &HashMap<String, UserConfig>) -> &str {
+        &users["alice"].device_ids[0]
+    }
+
+    // Keys filled with a single byte
+    fn cipher(keys: &[(&str, u8)]) -> TokenCipher {
+        use base64::{engine::general_purpose::STANDARD, Engine};
+
+        let entries: Vec<String> = keys
+            .iter()
+            .map(|(key_id, byte)| format!("{}:{}", key_id, STANDARD.encode([*byte; 32])))
+            .collect();
+        TokenCipher::from_entries(entries.iter().map(String::as_str)).unwrap()
+    }
+
+    #[test]
+    fn writes_keep_the_most_recent_backups_in_order() {
+        let dir = temp_dir();
+        let path = dir.join("users.json");
+
+        for contents in ["1", "2", "3", "4"] {
+            write_atomic(&path, contents.as_bytes(), 3).unwrap();
+        }
+        let read = |path: PathBuf| fs::read_to_string(path).unwrap();
+        assert_eq!(read(path.clone()), "4");
+        assert_eq!(read(backup_path(&path, 1)), "3");
+        assert_eq!(read(backup_path(&path, 2)), "2");
+        assert_eq!(read(backup_path(&path, 3)), "1");
+        assert!(!backup_path(&path, 4).exists());
+        assert!(!dir.join("users.json.tmp").exists());
+
+        write_atomic(&path, b"5", 3).unwrap();
+        assert_eq!(read(backup_path(&path, 3)), "2");
+        assert!(!backup_path(&path, 4).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn loads_fall_back_to_the_newest_readable_backup() {
+        let dir = temp_dir();
+        let path = dir.join("users.json");
+        let file = path.to_str().unwrap();
+        for saved in ["v1", "v2", "v3"] {
+            save_user_data(&users(saved), file, 2, None).await.unwrap();
+        }
+        assert_eq!(version(&load_user_data(file, 2, None).await.unwrap()), "v3");
+
+        fs::write(&path, "{ not json").unwrap();
+        assert_eq!(version(&load_user_data(file, 2, None).await.unwrap()), "v2");
+
+        fs::remove_file(&path).unwrap();
+        fs::write(backup_path(&path, 1), "").unwrap();
+        assert_eq!(version(&load_user_data(file, 2, None).await.unwrap()), "v1");
+
+        // With nothing readable left, loading fails rather than starting empty
+        fs::write(backup_path(&path, 2), "[]").unwrap();
+        assert!(load_user_data(file, 2, None).await.is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn enabling_encryption_leaves_no_plaintext_backups() {
+        let dir = temp_dir();
+        let path = dir.join("users.json");
+        let file = path.to_str().unwrap();
+
+        // A plaintext copy from before encryption, one encrypted with a key
+        // that is being retired and an unreadable one
+        save_user_data(&users("v1"), file, 3, None).await.unwrap();
+        save_user_data(&users("v2"), file, 3, Some(&cipher(&[("k1", 1)]))).await.unwrap();
+        fs::write(backup_path(&path, 3), "{ not json").unwrap();
+
+        let repository = json::JsonRepository::new(
+            file.to_string(),
+            dir.join("events.json").to_string_lossy().into_owned(),
+            3,
+            Some(Arc::new(cipher(&[("k2", 2), ("k1", 1)]))),
+        );
+        repository.save_users(&users("v3")).await.unwrap();
+
+        let current = cipher(&[("k2", 2)]);
+        for (copy, expected) in [(path.clone(), "v3"), (backup_path(&path, 1), "v2"), (backup_path(&path, 2), "v1")] {
+            let contents = fs::read_to_string(&copy).unwrap();
+            assert!(!contents.contains("-secret"), "{} has plaintext tokens", copy.display());
+            assert!(!contents.contains("enc:v1:k1:"), "{} still needs k1", copy.display());
+            let data = read_user_data(&copy, Some(&current)).unwrap();
+            assert_eq!(version(&data.users), expected);
+            assert_eq!(data.users["alice"].token.as_ref().unwrap().refresh_token, "refresh-secret");
+        }
+        assert!(!backup_path(&path, 3).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Remembers every save of the users, and nothing else
+    #[derive(Default)]
+    pub(crate) struct RecordingRepository {
+        saves: std::sync::Mutex<Vec<HashMap<String, UserConfig>>>,
+    }
+
+    #[async_trait]
+    impl UserRepository for RecordingRepository {
+        fn name(&self) -> &str {
+            "recording"
+        }
+
+        async fn load_users(&self) -> Result<HashMap<String, UserConfig>, StorageError> {
+            Ok(HashMap::new())
+        }
+
+        async fn save_users(&self, users: &HashMap<String, UserConfig>) -> Result<(), StorageError> {
+            self.saves.lock().unwrap().push(users.clone());
+            Ok(())
+        }
+
+        async fn load_processed_events(
+            &self,
+        ) -> Result<HashMap<String, Vec<ProcessedEvent>>, StorageError> {
+            Ok(HashMap::new())
+        }
+
+        async fn save_processed_event(
+            &self,
+            _user_id: &str,
+            _event: &ProcessedEvent,
+            _evicted: &[ProcessedEvent],
+        ) -> Result<(), StorageError> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn changes_are_saved_once_after_the_debounce() {
+        let store = UserStore::new(users("v1"));
+        let repository = Arc::new(RecordingRepository::default());
+        start_change_driven_save(store.clone(), repository.clone(), std::time::Duration::from_millis(100)).await;
+
+        // Reading doesn't count as a change
+        assert_eq!(version(&*store.lock().await), "v1");
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        assert!(repository.saves.lock().unwrap().is_empty());
+
+        store.lock().await.extend(users("v2"));
+        store.lock().await.get_mut("alice").unwrap().needs_reauth = true;
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+
+        let saves = repository.saves.lock().unwrap();
+        assert_eq!(saves.len(), 1);
+        assert_eq!(version(&saves[0]), "v2");
+        assert!(saves[0]["alice"].needs_reauth);
+    }
+
+    #[tokio::test]
+    async fn shutdown_saves_changes_still_waiting_for_the_debounce() {
+        let store = UserStore::new(users("v1"));
+        let repository = Arc::new(RecordingRepository::default());
+        start_change_driven_save(store.clone(), repository.clone(), std::time::Duration::from_secs(3600)).await;
+
+        store.lock().await.extend(users("v2"));
+
+        // As main does on shutdown
+        store.save(repository.as_ref()).await.unwrap();
+        store.save(repository.as_ref()).await.unwrap();
+
+        let saves = repository.saves.lock().unwrap();
+        assert_eq!(saves.len(), 1);
+        assert_eq!(version(&saves[0]), "v2");
+    }
+}
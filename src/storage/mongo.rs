@@ -0,0 +1,285 @@
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use mongodb::{
+    bson::doc,
+    error::{ErrorKind, WriteFailure},
+    options::{FindOptions, IndexOptions, ReplaceOptions, UpdateOptions},
+    Client, Collection, Database, IndexModel,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::auth::models::{LocalAccount, NestToken, NotificationSettings, UserConfig, WebhookConfig};
+use crate::events::dedup::ProcessedEvent;
+use crate::storage::{StorageError, UserRepository};
+
+// A user without their devices, which are stored separately
+#[derive(Debug, Serialize, Deserialize)]
+struct UserDocument {
+    user_id: String,
+    #[serde(default, deserialize_with = "crate::auth::models::deserialize_project_id")]
+    project_id: Option<String>,
+    pubsub_subscription: Option<String>,
+    notifications: NotificationSettings,
+    webhooks: Vec<WebhookConfig>,
+    #[serde(default)]
+    token: Option<NestToken>,
+    #[serde(default)]
+    needs_reauth: bool,
+    #[serde(default)]
+    account: Option<LocalAccount>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DeviceDocument {
+    user_id: String,
+    device_id: String,
+    // Order of the device in the user's device list
+    position: i32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct EventDocument {
+    user_id: String,
+    event_id: String,
+    device_id: String,
+    timestamp: String,
+}
+
+// Server error code for a write that breaks a unique index
+const DUPLICATE_KEY: i32 = 11000;
+
+/// Stores users, devices and processed events in MongoDB. Writes aren't
+/// wrapped in transactions, which need a replica set, so a failed save can
+/// leave some users written; the next save writes them all again.
+pub struct MongoRepository {
+    users: Collection<UserDocument>,
+    devices: Collection<DeviceDocument>,
+    events: Collection<EventDocument>,
+}
+
+impl MongoRepository {
+    /// Connect to the database named in the URL (or "dishwashmon") and
+    /// create the indexes used for lookups
+    pub async fn connect(database_url: &str) -> Result<Self, StorageError> {
+        let client = Client::with_uri_str(database_url).await?;
+        let db = client
+            .default_database()
+            .unwrap_or_else(|| client.database("dishwashmon"));
+
+        let repository = Self::new(&db);
+        repository.create_indexes().await?;
+        Ok(repository)
+    }
+
+    fn new(db: &Database) -> Self {
+        Self {
+            users: db.collection("users"),
+            devices: db.collection("devices"),
+            events: db.collection("events"),
+        }
+    }
+
+    async fn create_indexes(&self) -> Result<(), StorageError> {
+        let unique = || Some(IndexOptions::builder().unique(true).build());
+
+        self.users
+            .create_indexes(
+                [
+                    IndexModel::builder()
+                        .keys(doc! { "user_id": 1 })
+                        .options(unique())
+                        .build(),
+                    // Only users with a password have an email address
+                    IndexModel::builder()
+                        .keys(doc! { "account.email": 1 })
+                        .options(
+                            IndexOptions::builder()
+                                .unique(true)
+                                .partial_filter_expression(doc! { "account.email": { "$type": "string" } })
+                                .build(),
+                        )
+                        .build(),
+                ],
+                None,
+            )
+            .await?;
+
+        self.devices
+            .create_indexes(
+                [
+                    IndexModel::builder()
+                        .keys(doc! { "user_id": 1, "device_id": 1 })
+                        .options(unique())
+                        .build(),
+                    IndexModel::builder().keys(doc! { "device_id": 1 }).build(),
+                ],
+                None,
+            )
+            .await?;
+
+        self.events
+            .create_indexes(
+                [
+                    IndexModel::builder()
+                        .keys(doc! { "user_id": 1, "event_id": 1 })
+                        .options(unique())
+                        .build(),
+                    IndexModel::builder().keys(doc! { "device_id": 1 }).build(),
+                ],
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl UserRepository for MongoRepository {
+    fn name(&self) -> &str {
+        "mongo"
+    }
+
+    async fn load_users(&self) -> Result<HashMap<String, UserConfig>, StorageError> {
+        let documents: Vec<UserDocument> = self.users.find(None, None).await?.try_collect().await?;
+
+        let mut users: HashMap<String, UserConfig> = documents
+            .into_iter()
+            .map(|user| {
+                (
+                    user.user_id.clone(),
+                    UserConfig {
+                        user_id: user.user_id,
+                        device_ids: Vec::new(),
+                        token: user.token,
+                        project_id: user.project_id,
+                        pubsub_subscription: user.pubsub_subscription,
+                        notifications: user.notifications,
+                        webhooks: user.webhooks,
+                        needs_reauth: user.needs_reauth,
+                        account: user.account,
+                    },
+                )
+            })
+            .collect();
+
+        let options = FindOptions::builder()
+            .sort(doc! { "user_id": 1, "position": 1 })
+            .build();
+        let devices: Vec<DeviceDocument> =
+            self.devices.find(None, options).await?.try_collect().await?;
+        for device in devices {
+            if let Some(config) = users.get_mut(&device.user_id) {
+                config.device_ids.push(device.device_id);
+            }
+        }
+
+        Ok(users)
+    }
+
+    async fn save_users(&self, users: &HashMap<String, UserConfig>) -> Result<(), StorageError> {
+        let upsert = ReplaceOptions::builder().upsert(true).build();
+
+        // Removed first, so a user moved to a new ID can keep their unique
+        // email address
+        let user_ids: Vec<&str> = users.keys().map(String::as_str).collect();
+        let removed = doc! { "user_id": { "$nin": &user_ids } };
+        self.users.delete_many(removed.clone(), None).await?;
+        self.devices.delete_many(removed, None).await?;
+
+        for config in users.values() {
+            let user = UserDocument {
+                user_id: config.user_id.clone(),
+                project_id: config.project_id.clone(),
+                pubsub_subscription: config.pubsub_subscription.clone(),
+                notifications: config.notifications.clone(),
+                webhooks: config.webhooks.clone(),
+                token: config.token.clone(),
+                needs_reauth: config.needs_reauth,
+                account: config.account.clone(),
+            };
+            self.users
+                .replace_one(doc! { "user_id": &config.user_id }, &user, upsert.clone())
+                .await
+                .map_err(|e| match (&*e.kind, &config.account) {
+                    (ErrorKind::Write(WriteFailure::WriteError(error)), Some(account))
+                        if error.code == DUPLICATE_KEY =>
+                    {
+                        StorageError::EmailTaken(account.email.clone())
+                    }
+                    _ => e.into(),
+                })?;
+
+            self.devices
+                .delete_many(doc! { "user_id": &config.user_id }, None)
+                .await?;
+            let devices: Vec<DeviceDocument> = config
+                .device_ids
+                .iter()
+                .enumerate()
+                .map(|(position, device_id)| DeviceDocument {
+                    user_id: config.user_id.clone(),
+                    device_id: device_id.clone(),
+                    position: position as i32,
+                })
+                .collect();
+            if !devices.is_empty() {
+                self.devices.insert_many(devices, None).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn load_processed_events(
+        &self,
+    ) -> Result<HashMap<String, Vec<ProcessedEvent>>, StorageError> {
+        // ObjectIds increase with insertion order
+        let options = FindOptions::builder().sort(doc! { "_id": 1 }).build();
+        let documents: Vec<EventDocument> =
+            self.events.find(None, options).await?.try_collect().await?;
+
+        let mut events: HashMap<String, Vec<ProcessedEvent>> = HashMap::new();
+        for event in documents {
+            events.entry(event.user_id).or_default().push(ProcessedEvent {
+                event_id: event.event_id,
+                device_id: event.device_id,
+                timestamp: event.timestamp,
+            });
+        }
+
+        Ok(events)
+    }
+
+    async fn save_processed_event(
+        &self,
+        user_id: &str,
+        event: &ProcessedEvent,
+        evicted: &[ProcessedEvent],
+    ) -> Result<(), StorageError> {
+        // Upsert so that a claim saved before a crash isn't an error when the
+        // event is claimed again
+        self.events
+            .update_one(
+                doc! { "user_id": user_id, "event_id": &event.event_id },
+                doc! { "$setOnInsert": {
+                    "user_id": user_id,
+                    "event_id": &event.event_id,
+                    "device_id": &event.device_id,
+                    "timestamp": &event.timestamp,
+                } },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await?;
+
+        if !evicted.is_empty() {
+            let evicted_ids: Vec<&str> = evicted.iter().map(|event| event.event_id.as_str()).collect();
+            self.events
+                .delete_many(doc! { "user_id": user_id, "event_id": { "$in": &evicted_ids } }, None)
+                .await?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,263 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::postgres::{PgPool, PgPoolOptions};
+use sqlx::types::Json;
+use sqlx::Row;
+use std::collections::HashMap;
+
+use crate::auth::models::{LocalAccount, NestToken, NotificationSettings, UserConfig, WebhookConfig};
+use crate::events::dedup::ProcessedEvent;
+use crate::storage::{StorageError, UserRepository};
+
+/// Stores users and processed events in PostgreSQL
+pub struct PostgresRepository {
+    pool: PgPool,
+}
+
+impl PostgresRepository {
+    /// Connect to the database and apply any pending migrations
+    pub async fn connect(database_url: &str) -> Result<Self, StorageError> {
+        let pool = PgPoolOptions::new()
+            .max_connections(5)
+            .connect(database_url)
+            .await?;
+
+        sqlx::migrate!("./migrations").run(&pool).await?;
+
+        Ok(Self { pool })
+    }
+}
+
+#[async_trait]
+impl UserRepository for PostgresRepository {
+    fn name(&self) -> &str {
+        "postgres"
+    }
+
+    async fn load_users(&self) -> Result<HashMap<String, UserConfig>, StorageError> {
+        let rows = sqlx::query(
+            "SELECT u.user_id, u.project_id, u.pubsub_subscription, u.notifications, u.webhooks, u.needs_reauth,
+                    u.email, u.password_hash, u.email_verified,
+                    t.access_token, t.refresh_token, t.token_type, t.expires_in, t.expires_at,
+                    t.scope, t.id_token
+             FROM users u
+             LEFT JOIN tokens t ON t.user_id = u.user_id",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut users = HashMap::new();
+        for row in rows {
+            let user_id: String = row.try_get("user_id")?;
+            let notifications: Json<NotificationSettings> = row.try_get("notifications")?;
+            let webhooks: Json<Vec<WebhookConfig>> = row.try_get("webhooks")?;
+
+            // Local accounts have no token until they connect Google
+            let access_token: Option<String> = row.try_get("access_token")?;
+            let token = match access_token {
+                Some(access_token) => {
+                    let expires_in: i64 = row.try_get("expires_in")?;
+                    // Rows written before expires_at was stored count as expired
+                    let expires_at: Option<DateTime<Utc>> = row.try_get("expires_at")?;
+                    Some(NestToken {
+                        access_token,
+                        expires_in: expires_in as u64,
+                        token_type: row.try_get("token_type")?,
+                        refresh_token: row.try_get("refresh_token")?,
+                        scope: row.try_get("scope")?,
+                        id_token: row.try_get("id_token")?,
+                        expires_at: expires_at.unwrap_or(DateTime::UNIX_EPOCH),
+                    })
+                }
+                None => None,
+            };
+
+            let email: Option<String> = row.try_get("email")?;
+            let password_hash: Option<String> = row.try_get("password_hash")?;
+            let account = match (email, password_hash) {
+                (Some(email), Some(password_hash)) => Some(LocalAccount {
+                    email,
+                    password_hash,
+                    email_verified: row.try_get("email_verified")?,
+                }),
+                _ => None,
+            };
+
+            let config = UserConfig {
+                user_id: user_id.clone(),
+                device_ids: Vec::new(),
+                token,
+                project_id: row.try_get("project_id")?,
+                pubsub_subscription: row.try_get("pubsub_subscription")?,
+                notifications: notifications.0,
+                webhooks: webhooks.0,
+                needs_reauth: row.try_get("needs_reauth")?,
+                account,
+            };
+            users.insert(user_id, config);
+        }
+
+        let devices = sqlx::query("SELECT user_id, device_id FROM devices ORDER BY user_id, position")
+            .fetch_all(&self.pool)
+            .await?;
+        for row in devices {
+            let user_id: String = row.try_get("user_id")?;
+            if let Some(config) = users.get_mut(&user_id) {
+                config.device_ids.push(row.try_get("device_id")?);
+            }
+        }
+
+        Ok(users)
+    }
+
+    async fn save_users(&self, users: &HashMap<String, UserConfig>) -> Result<(), StorageError> {
+        let mut tx = self.pool.begin().await?;
+
+        // Tokens and devices of removed users go with them. Removed first, so
+        // a user moved to a new ID can keep their unique email address.
+        let user_ids: Vec<&str> = users.keys().map(String::as_str).collect();
+        sqlx::query("DELETE FROM users WHERE user_id <> ALL($1)")
+            .bind(&user_ids)
+            .execute(&mut *tx)
+            .await?;
+
+        for config in users.values() {
+            let account = config.account.as_ref();
+            sqlx::query(
+                "INSERT INTO users (user_id, project_id, pubsub_subscription, notifications, webhooks, needs_reauth,
+                                    email, password_hash, email_verified)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+                 ON CONFLICT (user_id) DO UPDATE SET
+                     project_id = EXCLUDED.project_id,
+                     pubsub_subscription = EXCLUDED.pubsub_subscription,
+                     notifications = EXCLUDED.notifications,
+                     webhooks = EXCLUDED.webhooks,
+                     needs_reauth = EXCLUDED.needs_reauth,
+                     email = EXCLUDED.email,
+                     password_hash = EXCLUDED.password_hash,
+                     email_verified = EXCLUDED.email_verified,
+                     updated_at = now()",
+            )
+            .bind(&config.user_id)
+            .bind(&config.project_id)
+            .bind(&config.pubsub_subscription)
+            .bind(Json(&config.notifications))
+            .bind(Json(&config.webhooks))
+            .bind(config.needs_reauth)
+            .bind(account.map(|account| &account.email))
+            .bind(account.map(|account| &account.password_hash))
+            .bind(account.is_some_and(|account| account.email_verified))
+            .execute(&mut *tx)
+            .await?;
+
+            match &config.token {
+                Some(token) => {
+                    sqlx::query(
+                        "INSERT INTO tokens (user_id, access_token, refresh_token, token_type, expires_in, expires_at, scope, id_token)
+                         VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+                         ON CONFLICT (user_id) DO UPDATE SET
+                             access_token = EXCLUDED.access_token,
+                             refresh_token = EXCLUDED.refresh_token,
+                             token_type = EXCLUDED.token_type,
+                             expires_in = EXCLUDED.expires_in,
+                             expires_at = EXCLUDED.expires_at,
+                             scope = EXCLUDED.scope,
+                             id_token = EXCLUDED.id_token,
+                             updated_at = now()",
+                    )
+                    .bind(&config.user_id)
+                    .bind(&token.access_token)
+                    .bind(&token.refresh_token)
+                    .bind(&token.token_type)
+                    .bind(token.expires_in as i64)
+                    .bind(token.expires_at)
+                    .bind(&token.scope)
+                    .bind(&token.id_token)
+                    .execute(&mut *tx)
+                    .await?;
+                }
+                None => {
+                    sqlx::query("DELETE FROM tokens WHERE user_id = $1")
+                        .bind(&config.user_id)
+                        .execute(&mut *tx)
+                        .await?;
+                }
+            }
+
+            sqlx::query("DELETE FROM devices WHERE user_id = $1")
+                .bind(&config.user_id)
+                .execute(&mut *tx)
+                .await?;
+
+            sqlx::query(
+                "INSERT INTO devices (user_id, device_id, position)
+                 SELECT $1, device_id, position - 1
+                 FROM UNNEST($2::text[]) WITH ORDINALITY AS d(device_id, position)
+                 ON CONFLICT DO NOTHING",
+            )
+            .bind(&config.user_id)
+            .bind(&config.device_ids)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn load_processed_events(
+        &self,
+    ) -> Result<HashMap<String, Vec<ProcessedEvent>>, StorageError> {
+        let rows = sqlx::query("SELECT user_id, event_id, device_id, timestamp FROM events ORDER BY id")
+            .fetch_all(&self.pool)
+            .await?;
+
+        let mut events: HashMap<String, Vec<ProcessedEvent>> = HashMap::new();
+        for row in rows {
+            events
+                .entry(row.try_get("user_id")?)
+                .or_default()
+                .push(ProcessedEvent {
+                    event_id: row.try_get("event_id")?,
+                    device_id: row.try_get("device_id")?,
+                    timestamp: row.try_get("timestamp")?,
+                });
+        }
+
+        Ok(events)
+    }
+
+    async fn save_processed_event(
+        &self,
+        user_id: &str,
+        event: &ProcessedEvent,
+        evicted: &[ProcessedEvent],
+    ) -> Result<(), StorageError> {
+        let evicted_ids: Vec<&str> = evicted.iter().map(|event| event.event_id.as_str()).collect();
+
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query(
+            "INSERT INTO events (user_id, event_id, device_id, timestamp)
+             VALUES ($1, $2, $3, $4)
+             ON CONFLICT (user_id, event_id) DO NOTHING",
+        )
+        .bind(user_id)
+        .bind(&event.event_id)
+        .bind(&event.device_id)
+        .bind(&event.timestamp)
+        .execute(&mut *tx)
+        .await?;
+
+        if !evicted_ids.is_empty() {
+            sqlx::query("DELETE FROM events WHERE user_id = $1 AND event_id = ANY($2)")
+                .bind(user_id)
+                .bind(&evicted_ids)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+}
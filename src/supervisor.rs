@@ -0,0 +1,391 @@
+use futures::future::{join_all, BoxFuture};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{watch, Mutex};
+use tokio::task::{JoinHandle, JoinSet};
+
+use crate::auth::models::{OAuthConfig, UserStore};
+use crate::dishwasher::tracker::DishwasherTracker;
+use crate::events::{processor::EventProcessor, pubsub::PubSubClient};
+use crate::notifications::{NotificationDispatcher, PersonAlerts};
+use crate::sdm::SdmClient;
+
+// Delay before restarting a monitor that panicked, doubled for each
+// consecutive panic up to MAX_RESTART_DELAY
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(300);
+
+// A monitor that ran this long before panicking is restarted without delay
+// escalation
+const HEALTHY_RUN: Duration = Duration::from_secs(600);
+
+/// Tells a task that it should stop
+#[derive(Clone)]
+pub struct ShutdownSignal(watch::Receiver<bool>);
+
+impl ShutdownSignal {
+    pub fn is_shutting_down(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Wait until the task is told to stop
+    pub async fn wait(&mut self) {
+        while !*self.0.borrow_and_update() {
+            if self.0.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Sleep for `duration`. Returns false if the task was told to stop first.
+    pub async fn sleep(&mut self, duration: Duration) -> bool {
+        tokio::select! {
+            _ = tokio::time::sleep(duration) => !self.is_shutting_down(),
+            _ = self.wait() => false,
+        }
+    }
+}
+
+// A running monitor and the means to stop it
+struct Monitor {
+    task: JoinHandle<()>,
+    stop: watch::Sender<bool>,
+}
+
+// Runs one user's monitor until it is told to stop
+type MonitorFn = Arc<dyn Fn(String, MonitorContext, ShutdownSignal) -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// Services shared by every monitor task
+#[derive(Clone)]
+pub struct MonitorContext {
+    pub users: UserStore,
+    pub oauth_config: OAuthConfig,
+    pub events: EventProcessor,
+    pub pubsub: PubSubClient,
+    pub sdm: SdmClient,
+    pub notifications: NotificationDispatcher,
+    pub dishwashers: DishwasherTracker,
+}
+
+/// Owns the per-user monitor tasks, keeping exactly one running for each
+/// user with registered cameras, restarting them if they panic and stopping
+/// them on shutdown
+#[derive(Clone)]
+pub struct Supervisor {
+    context: MonitorContext,
+    monitor: MonitorFn,
+    restart_delay: Duration,
+    shutdown: Arc<watch::Sender<bool>>,
+    monitors: Arc<Mutex<HashMap<String, Monitor>>>,
+}
+
+impl Supervisor {
+    pub fn new(context: MonitorContext) -> Self {
+        Self::with_monitor(context, RESTART_DELAY, |user_id, context, signal| {
+            Box::pin(crate::monitor_user_cameras(user_id, context, signal))
+        })
+    }
+
+    fn with_monitor(
+        context: MonitorContext,
+        restart_delay: Duration,
+        monitor: impl Fn(String, MonitorContext, ShutdownSignal) -> BoxFuture<'static, ()> + Send + Sync + 'static,
+    ) -> Self {
+        let (shutdown, _) = watch::channel(false);
+        Self {
+            context,
+            monitor: Arc::new(monitor),
+            restart_delay,
+            shutdown: Arc::new(shutdown),
+            monitors: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Signal that fires when the whole service shuts down
+    pub fn shutdown_signal(&self) -> ShutdownSignal {
+        ShutdownSignal(self.shutdown.subscribe())
+    }
+
+    /// Start or stop a user's monitor and register their event handlers to
+    /// match their registered cameras and settings. Call after any change to
+    /// a user's devices, authorization or notification settings; a running
+    /// monitor picks up changes to the device list on its next poll.
+    pub async fn sync_monitor(&self, user_id: &str) {
+        let (wanted, device_ids, notify_on_person) = {
+            let users_lock = self.context.users.lock().await;
+            match users_lock.get(user_id) {
+                Some(config) => (
+                    !config.device_ids.is_empty() && config.token.is_some() && !config.needs_reauth,
+                    config.device_ids.clone(),
+                    config.notifications.notify_on_person,
+                ),
+                None => (false, Vec::new(), false),
+            }
+        };
+
+        // Each camera drives its own dishwasher, and person alerts only go to
+        // users who asked for them
+        {
+            let mut handlers = self.context.events.handlers().write().await;
+            handlers.unregister_user(user_id);
+            for device_id in &device_ids {
+                handlers.register_for_device(user_id, device_id, Arc::new(self.context.dishwashers.clone()));
+            }
+            if notify_on_person {
+                handlers.register_for_user(user_id, Arc::new(PersonAlerts(self.context.notifications.clone())));
+            }
+        }
+        self.context.dishwashers.retain_devices(user_id, &device_ids).await;
+
+        let wanted = wanted && !*self.shutdown.borrow();
+
+        let mut monitors = self.monitors.lock().await;
+        let running = monitors
+            .get(user_id)
+            .is_some_and(|monitor| !monitor.task.is_finished());
+
+        match (wanted, running) {
+            (true, false) => {
+                log::info!("Starting monitor for user {}", user_id);
+                monitors.insert(user_id.to_string(), self.spawn_monitor(user_id.to_string()));
+            }
+            (false, true) => {
+                log::info!("Stopping monitor for user {}", user_id);
+                if let Some(monitor) = monitors.remove(user_id) {
+                    monitor.stop.send_replace(true);
+                }
+            }
+            (false, false) => {
+                monitors.remove(user_id);
+            }
+            (true, true) => {}
+        }
+    }
+
+    fn spawn_monitor(&self, user_id: String) -> Monitor {
+        let (stop, _) = watch::channel(false);
+        let signal = ShutdownSignal(stop.subscribe());
+        let supervisor = self.clone();
+
+        let task = tokio::spawn(async move {
+            let mut delay = supervisor.restart_delay;
+            // Aborts the running monitor if this task is aborted
+            let mut running = JoinSet::new();
+
+            loop {
+                let started = Instant::now();
+                running.spawn((supervisor.monitor)(
+                    user_id.clone(),
+                    supervisor.context.clone(),
+                    signal.clone(),
+                ));
+
+                match running.join_next().await {
+                    Some(Err(e)) if e.is_panic() => {
+                        if started.elapsed() >= HEALTHY_RUN {
+                            delay = supervisor.restart_delay;
+                        }
+                        log::error!(
+                            "Monitor for user {} panicked, restarting in {:?}",
+                            user_id, delay
+                        );
+                        if !signal.clone().sleep(delay).await {
+                            break;
+                        }
+                        delay = (delay * 2).min(MAX_RESTART_DELAY);
+                    }
+                    // Stopped because the user was removed or it was told to stop
+                    _ => break,
+                }
+            }
+        });
+
+        Monitor { task, stop }
+    }
+
+    /// Signal every task to stop and wait up to `timeout` for monitors to finish
+    pub async fn shutdown(&self, timeout: Duration) {
+        self.shutdown.send_replace(true);
+
+        let monitors: Vec<Monitor> = self.monitors.lock().await.drain().map(|(_, monitor)| monitor).collect();
+        let mut tasks = Vec::new();
+        for monitor in monitors {
+            monitor.stop.send_replace(true);
+            tasks.push(monitor.task);
+        }
+        let abort_handles: Vec<_> = tasks.iter().map(|task| task.abort_handle()).collect();
+
+        if tokio::time::timeout(timeout, join_all(tasks)).await.is_err() {
+            log::warn!("Monitors did not stop within {:?}, aborting them", timeout);
+            for handle in abort_handles {
+                handle.abort();
+            }
+        }
+    }
+}
+
+/// Wait for SIGINT (Ctrl+C) or, on Unix, SIGTERM
+pub async fn wait_for_shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => log::info!("Received SIGINT"),
+                    _ = terminate.recv() => log::info!("Received SIGTERM"),
+                }
+                return;
+            }
+            Err(e) => log::error!("Failed to listen for SIGTERM: {}", e),
+        }
+    }
+
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        log::error!("Failed to listen for Ctrl+C: {}", e);
+        // Without a signal handler there's no way to shut down cleanly
+        std::future::pending::<()>().await;
+    }
+    log::info!("Received SIGINT");
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::dishwasher::state_machine::StateMachineConfig;
+    use crate::events::{dedup::EventDeduplicator, handlers::HandlerRegistry, pubsub::PubSubConfig};
+    use crate::sdm::SdmClientConfig;
+    use crate::storage::tests::RecordingRepository;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    // Services for monitors that never reach Google, with processed events
+    // kept in memory
+    pub(crate) async fn context(users: UserStore) -> MonitorContext {
+        let repository = Arc::new(RecordingRepository::default());
+        let dedup = EventDeduplicator::load(repository, 100).await;
+        let sdm = SdmClient::new(SdmClientConfig::default());
+
+        MonitorContext {
+            users: users.clone(),
+            oauth_config: OAuthConfig::default(),
+            events: EventProcessor::new(dedup, Arc::new(tokio::sync::RwLock::new(HandlerRegistry::default()))),
+            pubsub: PubSubClient::new(
+                PubSubConfig {
+                    base_url: "http://127.0.0.1:9".to_string(),
+                    default_subscription: None,
+                    max_messages: 10,
+                    max_delivery_attempts: 5,
+                    use_auth: false,
+                },
+                sdm.clone(),
+            ),
+            sdm,
+            notifications: NotificationDispatcher::new(users, Vec::new()),
+            dishwashers: DishwasherTracker::new(StateMachineConfig::default()),
+        }
+    }
+
+    fn users() -> UserStore {
+        let alice = serde_json::from_value(serde_json::json!({
+            "user_id": "alice",
+            "device_ids": ["camera-1"],
+            "token": {
+                "access_token": "access",
+                "refresh_token": "refresh",
+                "token_type": "Bearer",
+                "expires_in": 3600,
+            },
+        }))
+        .unwrap();
+        UserStore::new(HashMap::from([("alice".to_string(), alice)]))
+    }
+
+    // Sets its flag once dropped, showing the task holding it was stopped
+    struct Dropped(Arc<AtomicBool>);
+
+    impl Drop for Dropped {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn monitors_are_restarted_after_a_panic() {
+        let starts = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&starts);
+        let supervisor = Supervisor::with_monitor(
+            context(users()).await,
+            Duration::from_millis(10),
+            move |_, _, mut signal| {
+                let start = counter.fetch_add(1, Ordering::SeqCst);
+                Box::pin(async move {
+                    if start == 0 {
+                        panic!("monitor failed");
+                    }
+                    signal.wait().await;
+                })
+            },
+        );
+
+        supervisor.sync_monitor("alice").await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(starts.load(Ordering::SeqCst), 2);
+
+        tokio::time::timeout(Duration::from_secs(1), supervisor.shutdown(Duration::from_secs(5)))
+            .await
+            .unwrap();
+        assert!(supervisor.monitors.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn shutdown_stops_monitors_that_ignore_the_signal() {
+        let dropped = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&dropped);
+        let supervisor = Supervisor::with_monitor(context(users()).await, RESTART_DELAY, move |_, _, _| {
+            let guard = Dropped(Arc::clone(&flag));
+            Box::pin(async move {
+                let _guard = guard;
+                std::future::pending::<()>().await;
+            })
+        });
+
+        supervisor.sync_monitor("alice").await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!dropped.load(Ordering::SeqCst));
+
+        supervisor.shutdown(Duration::from_millis(100)).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(dropped.load(Ordering::SeqCst));
+
+        // Nothing starts once shutting down
+        supervisor.sync_monitor("alice").await;
+        assert!(supervisor.monitors.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn syncing_again_keeps_one_monitor_and_one_set_of_handlers() {
+        let users = users();
+        users.lock().await.get_mut("alice").unwrap().notifications.notify_on_person = true;
+        let starts = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&starts);
+        let supervisor = Supervisor::with_monitor(context(users).await, RESTART_DELAY, move |_, _, mut signal| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move { signal.wait().await })
+        });
+
+        for _ in 0..2 {
+            supervisor.sync_monitor("alice").await;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(starts.load(Ordering::SeqCst), 1);
+        assert_eq!(supervisor.monitors.lock().await.len(), 1);
+        // The camera's dishwasher and the person alerts
+        let handlers = supervisor.context.events.handlers().read().await.handlers_for("alice", "camera-1");
+        assert_eq!(handlers.len(), 2);
+
+        supervisor.shutdown(Duration::from_secs(1)).await;
+    }
+}
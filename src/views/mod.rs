@@ -1,4 +1,8 @@
+use crate::auth::accounts::MIN_PASSWORD_LEN;
+use crate::auth::models::{NotificationSettings, WebhookConfig};
 use crate::devices::discovery::Device;
+use crate::dishwasher::state_machine::DishwasherState;
+use std::collections::HashMap;
 
 // Base HTML template with CSS styling
 pub fn base_template(title: &str, content: &str) -> String {
@@ -76,7 +80,7 @@ pub fn base_template(title: &str, content: &str) -> String {
     </footer>
 </body>
 </html>"#,
-        title, content
+        escape_html(title), content
     )
 }
 
@@ -90,7 +94,10 @@ pub fn home_page() -> String {
         <div class="card">
             <h3>Get Started</h3>
             <p>Sign in with your Google account to connect your Nest cameras.</p>
-            <a href="/auth/authorize" class="button">Sign in with Google</a>
+            <div class="actions">
+                <a href="/auth/authorize" class="button">Sign in with Google</a>
+                <a href="/auth/register" class="button secondary">Create an Account</a>
+            </div>
         </div>
         
         <div class="card">
@@ -108,7 +115,7 @@ pub fn home_page() -> String {
 }
 
 // Camera selection page
-pub fn camera_selection_page(user_id: &str, cameras: &[Device]) -> String {
+pub fn camera_selection_page(cameras: &[Device]) -> String {
     let mut camera_list = String::new();
     
     // Generate camera cards
@@ -123,14 +130,16 @@ pub fn camera_selection_page(user_id: &str, cameras: &[Device]) -> String {
                 <p><strong>ID:</strong> {}</p>
                 <div class="actions">
                     <form action="/cameras/register" method="post">
-                        <input type="hidden" name="user_id" value="{}">
                         <input type="hidden" name="device_id" value="{}">
                         <button type="submit" class="button">Add to Monitoring</button>
                     </form>
                 </div>
             </div>
             "#,
-            camera.display_name, location, camera.device_id, user_id, camera.device_id
+            escape_html(&camera.display_name),
+            escape_html(location),
+            escape_html(&camera.device_id),
+            escape_html(&camera.device_id)
         ));
     }
     
@@ -165,29 +174,46 @@ pub fn camera_selection_page(user_id: &str, cameras: &[Device]) -> String {
 }
 
 // Dashboard page for managing cameras
-pub fn dashboard_page(user_id: &str, registered_cameras: &[Device]) -> String {
+pub fn dashboard_page(
+    registered_cameras: &[Device],
+    states: &HashMap<String, DishwasherState>,
+    notifications: &NotificationSettings,
+    webhooks: &[WebhookConfig],
+    needs_reauth: bool,
+    google_connected: bool,
+    project_id: Option<&str>,
+) -> String {
     let mut camera_list = String::new();
+    let status = if needs_reauth { "Paused" } else { "Monitoring" };
     
     // Generate camera cards for registered cameras
     for camera in registered_cameras {
         let location = camera.room_name.as_deref().unwrap_or("Unknown location");
+        let state = states
+            .get(&camera.device_id)
+            .copied()
+            .unwrap_or(DishwasherState::Idle);
         
         camera_list.push_str(&format!(
             r#"
             <div class="camera-card">
                 <h3>{}</h3>
                 <p><strong>Location:</strong> {}</p>
-                <p><strong>Status:</strong> Monitoring</p>
+                <p><strong>Status:</strong> {}</p>
+                <p><strong>Dishwasher:</strong> {}</p>
                 <div class="actions">
                     <form action="/cameras/unregister" method="post">
-                        <input type="hidden" name="user_id" value="{}">
                         <input type="hidden" name="device_id" value="{}">
                         <button type="submit" class="button danger">Remove</button>
                     </form>
                 </div>
             </div>
             "#,
-            camera.display_name, location, user_id, camera.device_id
+            escape_html(&camera.display_name),
+            escape_html(location),
+            status,
+            state,
+            escape_html(&camera.device_id)
         ));
     }
     
@@ -206,24 +232,359 @@ pub fn dashboard_page(user_id: &str, registered_cameras: &[Device]) -> String {
             <h2>Your Dashboard</h2>
             <p>Manage your monitored cameras and view status.</p>
             
-            <div style="margin-bottom: 1rem;">
+            {}
+            
+            {}
+            
+            <div class="actions" style="margin-bottom: 1rem;">
                 <a href="/cameras/select" class="button">Add More Cameras</a>
+                <form action="/auth/logout" method="post">
+                    <button type="submit" class="button secondary">Sign Out</button>
+                </form>
             </div>
             
             <h3>Currently Monitored Cameras</h3>
             <div class="camera-list">
                 {}
             </div>
+            
+            {}
+            
+            {}
         </div>
         "#,
-        camera_list
+        if !google_connected {
+            connect_banner()
+        } else if needs_reauth {
+            reauth_banner()
+        } else if project_id.is_none() {
+            project_banner()
+        } else {
+            String::new()
+        },
+        project_id
+            .map(|project_id| format!(
+                r#"<p>Device Access project: <strong>{}</strong> (<a href="/project">change</a>)</p>"#,
+                escape_html(project_id)
+            ))
+            .unwrap_or_default(),
+        camera_list,
+        notification_settings_form(notifications),
+        webhook_list(webhooks)
     );
     
     base_template("Dashboard", &content)
 }
 
-// Authorization success page
-pub fn auth_success_page(user_id: &str) -> String {
+// Shown while monitoring is paused because Google revoked the user's authorization
+fn reauth_banner() -> String {
+    r#"
+            <div class="card" style="border-left: 4px solid #cc3300;">
+                <h3 style="margin-top: 0;">Reconnect your Google account</h3>
+                <p>Google no longer accepts Dishwasher Monitor's access to your cameras, so monitoring is paused.</p>
+                <a href="/auth/authorize" class="button">Reconnect</a>
+            </div>
+        "#
+    .to_string()
+}
+
+// Shown to users with a local account until they connect Google
+fn connect_banner() -> String {
+    r#"
+            <div class="card" style="border-left: 4px solid #0066cc;">
+                <h3 style="margin-top: 0;">Connect your Google account</h3>
+                <p>Give Dishwasher Monitor access to your Nest cameras to start monitoring.</p>
+                <a href="/auth/authorize" class="button">Connect Google</a>
+            </div>
+        "#
+    .to_string()
+}
+
+// Shown to connected users until they choose their Device Access project
+fn project_banner() -> String {
+    r#"
+            <div class="card" style="border-left: 4px solid #0066cc;">
+                <h3 style="margin-top: 0;">Choose your Device Access project</h3>
+                <p>Tell Dishwasher Monitor which Device Access project your cameras are in.</p>
+                <a href="/project" class="button">Choose Project</a>
+            </div>
+        "#
+    .to_string()
+}
+
+// Escape text for use in HTML content and attribute values
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Form for choosing how a user is notified
+fn notification_settings_form(settings: &NotificationSettings) -> String {
+    let checked = |enabled: bool| if enabled { "checked" } else { "" };
+    
+    format!(
+        r#"
+        <h3>Notifications</h3>
+        <div class="card">
+            <form action="/notifications/settings" method="post">
+                <div class="form-group">
+                    <label for="email_recipients">Email recipients (comma separated)</label>
+                    <input type="text" id="email_recipients" name="email_recipients" value="{}">
+                </div>
+                <div class="form-group">
+                    <label><input type="checkbox" name="notify_on_finished" value="on" style="width: auto;" {}> When the dishes are clean</label>
+                    <label><input type="checkbox" name="notify_on_person" value="on" style="width: auto;" {}> When someone is at the dishwasher</label>
+                </div>
+                <button type="submit" class="button">Save</button>
+            </form>
+        </div>
+        "#,
+        escape_html(&settings.email_recipients.join(", ")),
+        checked(settings.notify_on_finished),
+        checked(settings.notify_on_person)
+    )
+}
+
+// Registered webhooks with their signing secrets
+fn webhook_list(webhooks: &[WebhookConfig]) -> String {
+    let mut rows = String::new();
+    
+    for webhook in webhooks {
+        rows.push_str(&format!(
+            r#"
+            <div class="card">
+                <p><strong>URL:</strong> {}</p>
+                <p><strong>Signing secret:</strong> <code>{}</code></p>
+                <form action="/webhooks/unregister" method="post">
+                        <input type="hidden" name="webhook_id" value="{}">
+                    <button type="submit" class="button danger">Remove</button>
+                </form>
+            </div>
+            "#,
+            escape_html(&webhook.url), escape_html(&webhook.secret), escape_html(&webhook.id)
+        ));
+    }
+    
+    format!(
+        r#"
+        <h3>Webhooks</h3>
+        <p>Dishwasher state changes and camera events are posted as JSON to each webhook, signed with an HMAC-SHA256 <code>X-Dishwashmon-Signature</code> header.</p>
+        {}
+        <div class="card">
+            <form action="/webhooks/register" method="post">
+                <div class="form-group">
+                    <label for="webhook_url">Webhook URL</label>
+                    <input type="url" id="webhook_url" name="url" placeholder="https://example.com/hooks/dishwasher" required>
+                </div>
+                <button type="submit" class="button">Add Webhook</button>
+            </form>
+        </div>
+        "#,
+        rows
+    )
+}
+
+// Shown above account forms when something went wrong
+fn form_error(error: Option<&str>) -> String {
+    match error {
+        Some(error) => format!(
+            r#"<p style="color: #cc3300;"><strong>{}</strong></p>"#,
+            escape_html(error)
+        ),
+        None => String::new(),
+    }
+}
+
+// Sign-in page offering Google or an email and password
+pub fn login_page(error: Option<&str>) -> String {
+    let content = format!(
+        r#"
+        <div class="container">
+            <h2>Sign In</h2>
+            <div class="card">
+                <p>Sign in with your Google account to monitor your Nest cameras.</p>
+                <a href="/auth/authorize" class="button">Sign in with Google</a>
+            </div>
+            
+            <div class="card">
+                <h3>Sign in with email</h3>
+                {}
+                <form action="/auth/login" method="post">
+                    <div class="form-group">
+                        <label for="email">Email</label>
+                        <input type="email" id="email" name="email" autocomplete="username" required>
+                    </div>
+                    <div class="form-group">
+                        <label for="password">Password</label>
+                        <input type="password" id="password" name="password" autocomplete="current-password" required>
+                    </div>
+                    <div class="actions">
+                        <button type="submit" class="button">Sign In</button>
+                        <a href="/auth/register" class="button secondary">Create an Account</a>
+                    </div>
+                </form>
+                <p><a href="/auth/forgot">Forgot your password?</a></p>
+            </div>
+        </div>
+        "#,
+        form_error(error)
+    );
+    
+    base_template("Sign In", &content)
+}
+
+// Registration form for an email and password account
+pub fn register_page(error: Option<&str>) -> String {
+    let content = format!(
+        r#"
+        <div class="container">
+            <h2>Create an Account</h2>
+            <div class="card">
+                <p>Create a login for your household. You'll connect your Google account afterwards to choose cameras.</p>
+                {}
+                <form action="/auth/register" method="post">
+                    <div class="form-group">
+                        <label for="email">Email</label>
+                        <input type="email" id="email" name="email" autocomplete="username" required>
+                    </div>
+                    <div class="form-group">
+                        <label for="password">Password (at least {} characters)</label>
+                        <input type="password" id="password" name="password" autocomplete="new-password" minlength="{}" required>
+                    </div>
+                    <button type="submit" class="button">Create Account</button>
+                </form>
+                <p>Already registered? <a href="/auth/login">Sign in</a></p>
+            </div>
+        </div>
+        "#,
+        form_error(error),
+        MIN_PASSWORD_LEN,
+        MIN_PASSWORD_LEN
+    );
+    
+    base_template("Create an Account", &content)
+}
+
+// Form requesting a password reset link
+pub fn forgot_password_page() -> String {
+    let content = r#"
+        <div class="container">
+            <h2>Reset Your Password</h2>
+            <div class="card">
+                <p>Enter the email address you registered with and we'll send you a link to choose a new password.</p>
+                <form action="/auth/forgot" method="post">
+                    <div class="form-group">
+                        <label for="email">Email</label>
+                        <input type="email" id="email" name="email" autocomplete="username" required>
+                    </div>
+                    <button type="submit" class="button">Send Reset Link</button>
+                </form>
+            </div>
+        </div>
+        "#;
+    
+    base_template("Reset Your Password", content)
+}
+
+// Form choosing a new password, reached from a reset link
+pub fn reset_password_page(token: &str, error: Option<&str>) -> String {
+    let content = format!(
+        r#"
+        <div class="container">
+            <h2>Choose a New Password</h2>
+            <div class="card">
+                {}
+                <form action="/auth/reset" method="post">
+                    <input type="hidden" name="token" value="{}">
+                    <div class="form-group">
+                        <label for="password">New password (at least {} characters)</label>
+                        <input type="password" id="password" name="password" autocomplete="new-password" minlength="{}" required>
+                    </div>
+                    <button type="submit" class="button">Save Password</button>
+                </form>
+            </div>
+        </div>
+        "#,
+        form_error(error),
+        escape_html(token),
+        MIN_PASSWORD_LEN,
+        MIN_PASSWORD_LEN
+    );
+    
+    base_template("Choose a New Password", &content)
+}
+
+// Shown after an email with a link has been sent
+pub fn check_email_page(message: &str) -> String {
+    let content = format!(
+        r#"
+        <div class="container">
+            <h2>Check Your Email</h2>
+            <div class="card">
+                <p>{}</p>
+                <a href="/auth/login" class="button secondary">Back to Sign In</a>
+            </div>
+        </div>
+        "#,
+        escape_html(message)
+    );
+    
+    base_template("Check Your Email", &content)
+}
+
+// Onboarding step choosing the Device Access project a user's cameras are in
+pub fn project_page(project_id: Option<&str>, subscription: Option<&str>, error: Option<&str>) -> String {
+    let content = format!(
+        r#"
+        <div class="container">
+            <h2>Choose Your Device Access Project</h2>
+            <div class="card">
+                <p>Dishwasher Monitor reaches your cameras through your own Device Access project. Copy its project ID from the <a href="https://console.nest.google.com/device-access/">Device Access Console</a>.</p>
+                {}
+                <form action="/project" method="post">
+                    <div class="form-group">
+                        <label for="project_id">Project ID</label>
+                        <input type="text" id="project_id" name="project_id" value="{}" required>
+                    </div>
+                    <div class="form-group">
+                        <label for="pubsub_subscription">Pub/Sub subscription (optional)</label>
+                        <input type="text" id="pubsub_subscription" name="pubsub_subscription" value="{}" placeholder="projects/my-project/subscriptions/sdm-events">
+                        <p>The full name of the pull subscription for the topic shown in the Device Access Console. Leave it empty to use this server's subscription.</p>
+                    </div>
+                    <button type="submit" class="button">Continue</button>
+                </form>
+                <p>Changing the project removes the cameras you chose from the old one.</p>
+            </div>
+        </div>
+        "#,
+        form_error(error),
+        escape_html(project_id.unwrap_or_default()),
+        escape_html(subscription.unwrap_or_default())
+    );
+    
+    base_template("Choose Your Device Access Project", &content)
+}
+
+// Authorization success page, leading to the next onboarding step
+pub fn auth_success_page(needs_project: bool) -> String {
+    let next_step = if needs_project {
+        r#"<p>Next, tell us which Device Access project your cameras are in.</p>
+                <a href="/project" class="button">Choose Project</a>"#
+    } else {
+        r#"<p>You can now select which cameras you want to use for monitoring your dishwasher.</p>
+                <a href="/cameras/select" class="button">Select Cameras</a>"#
+    };
+    
     let content = format!(
         r#"
         <div class="container">
@@ -232,12 +593,11 @@ pub fn auth_success_page(user_id: &str) -> String {
             
             <div class="card">
                 <h3>Next Steps</h3>
-                <p>You can now select which cameras you want to use for monitoring your dishwasher.</p>
-                <a href="/cameras/select?user_id={}" class="button">Select Cameras</a>
+                {}
             </div>
         </div>
         "#,
-        user_id
+        next_step
     );
     
     base_template("Authorization Successful", &content)
@@ -255,8 +615,69 @@ pub fn error_page(title: &str, message: &str) -> String {
             </div>
         </div>
         "#,
-        title, message
+        escape_html(title), escape_html(message)
     );
     
     base_template(&format!("Error: {}", title), &content)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stored_recipients_are_escaped() {
+        let settings = NotificationSettings {
+            email_recipients: vec![r#""><script>alert(1)</script>"#.to_string()],
+            ..NotificationSettings::default()
+        };
+
+        let form = notification_settings_form(&settings);
+        assert!(!form.contains("<script>"));
+        assert!(form.contains(r#"value="&quot;&gt;&lt;script&gt;alert(1)&lt;/script&gt;""#));
+    }
+
+    #[test]
+    fn messages_and_request_values_are_escaped() {
+        let injected = r#""><script>alert(1)</script>"#;
+
+        let pages = [
+            error_page(injected, injected),
+            login_page(Some(injected)),
+            register_page(Some(injected)),
+            reset_password_page(injected, Some(injected)),
+            check_email_page(injected),
+            project_page(Some(injected), Some(injected), Some(injected)),
+        ];
+        for page in pages {
+            assert!(!page.contains("<script>"), "{}", page);
+            assert!(page.contains("&lt;script&gt;"));
+        }
+    }
+
+    #[test]
+    fn camera_names_and_ids_are_escaped() {
+        let injected = r#""><script>alert(1)</script>"#;
+        let camera = Device {
+            room_name: Some(injected.to_string()),
+            ..Device::placeholder(injected)
+        };
+        let escaped = "&quot;&gt;&lt;script&gt;alert(1)&lt;/script&gt;";
+
+        let pages = [
+            camera_selection_page(std::slice::from_ref(&camera)),
+            dashboard_page(
+                std::slice::from_ref(&camera),
+                &HashMap::new(),
+                &NotificationSettings::default(),
+                &[],
+                false,
+                true,
+                Some(injected),
+            ),
+        ];
+        for page in pages {
+            assert!(!page.contains("<script>"), "{}", page);
+            assert!(page.contains(&format!(r#"name="device_id" value="{}""#, escaped)));
+        }
+    }
+}